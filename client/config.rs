@@ -0,0 +1,171 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use dotspb::dec_exec::dec_exec_client::DecExecClient;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+use crate::Error;
+
+const CONFIG_VAR: &str = "SIGNING_CLIENT_CONFIG";
+const DEFAULT_CONFIG: &str = "signing_client.json";
+
+const DEFAULT_NODES: [&str; 3] = ["http://localhost:50050", "http://localhost:50051", "http://localhost:50052"];
+
+/// Client configuration, read from `signing_client.json` in the working directory or from the file
+/// named by `SIGNING_CLIENT_CONFIG`. Every setting can be overridden by an environment variable.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// Node endpoints in party order, overridden by the comma-separated `SIGNING_NODES`
+    pub nodes: Vec<String>,
+    /// PEM file of the CA that signed the node certificates, overridden by `SIGNING_CA_CERT`
+    pub ca_cert: Option<String>,
+    /// PEM file of the client certificate for mutual TLS, overridden by `SIGNING_CLIENT_CERT`
+    pub client_cert: Option<String>,
+    /// PEM file of the client certificate's private key, overridden by `SIGNING_CLIENT_KEY`
+    pub client_key: Option<String>,
+    /// Name to verify the node certificates against instead of the endpoint host, overridden by `SIGNING_TLS_DOMAIN`
+    pub tls_domain: Option<String>,
+    /// Seconds to wait for a connection to a node, overridden by `SIGNING_CONNECT_TIMEOUT`
+    pub connect_timeout_secs: u64,
+    /// Default deadline in seconds of every call, overridden by `SIGNING_DEADLINE`
+    pub deadline_secs: Option<u64>,
+    /// Deadlines in seconds of individual functions such as "keygen" or "signing"
+    pub deadlines: HashMap<String, u64>,
+    /// Identifies this client to the nodes, which log it with the parameters of each request.
+    /// Overridden by `SIGNING_CLIENT_ID` and defaulting to `HOSTNAME`.
+    pub client_id: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            nodes: DEFAULT_NODES.iter().map(|node| node.to_string()).collect(),
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            tls_domain: None,
+            connect_timeout_secs: 10,
+            deadline_secs: None,
+            deadlines: HashMap::new(),
+            client_id: None,
+        }
+    }
+}
+
+fn env_override(name: &str, setting: &mut Option<String>) {
+    if let Ok(value) = env::var(name) {
+        *setting = Some(value);
+    }
+}
+
+fn env_override_secs(name: &str) -> Result<Option<u64>, Error> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::Config(format!("{} must be a number of seconds", name))),
+        Err(_) => Ok(None),
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| Error::Config(format!("cannot read {}: {}", path, e)))
+}
+
+impl ClientConfig {
+    /// Loads the configuration file, if any, and applies the environment overrides
+    pub fn load() -> Result<Self, Error> {
+        let path = env::var(CONFIG_VAR).unwrap_or_else(|_| DEFAULT_CONFIG.to_owned());
+        let mut config: ClientConfig = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| Error::Config(format!("{}: {}", path, e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ClientConfig::default(),
+            Err(e) => return Err(Error::Config(format!("cannot read {}: {}", path, e))),
+        };
+
+        if let Ok(nodes) = env::var("SIGNING_NODES") {
+            config.nodes = nodes.split(',').map(|node| node.trim().to_owned()).collect();
+        }
+        env_override("SIGNING_CA_CERT", &mut config.ca_cert);
+        env_override("SIGNING_CLIENT_CERT", &mut config.client_cert);
+        env_override("SIGNING_CLIENT_KEY", &mut config.client_key);
+        env_override("SIGNING_TLS_DOMAIN", &mut config.tls_domain);
+        env_override("SIGNING_CLIENT_ID", &mut config.client_id);
+        if let Some(secs) = env_override_secs("SIGNING_CONNECT_TIMEOUT")? {
+            config.connect_timeout_secs = secs;
+        }
+        if let Some(secs) = env_override_secs("SIGNING_DEADLINE")? {
+            config.deadline_secs = Some(secs);
+        }
+
+        if config.nodes.is_empty() {
+            return Err(Error::Config("no nodes configured".to_owned()));
+        }
+        Ok(config)
+    }
+
+    /// Returns the id this client reports to the nodes
+    pub fn client_id(&self) -> String {
+        self.client_id
+            .clone()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns the deadline of calls to `func_name`, if any
+    pub fn deadline(&self, func_name: &str) -> Option<Duration> {
+        self.deadlines
+            .get(func_name)
+            .copied()
+            .or(self.deadline_secs)
+            .map(Duration::from_secs)
+    }
+
+    fn tls_config(&self) -> Result<Option<ClientTlsConfig>, Error> {
+        let https = self.nodes.iter().any(|node| node.starts_with("https://"));
+        if !https && self.ca_cert.is_none() && self.client_cert.is_none() {
+            return Ok(None);
+        }
+
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca_cert) = &self.ca_cert {
+            tls = tls.ca_certificate(Certificate::from_pem(read_file(ca_cert)?));
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(read_file(cert)?, read_file(key)?));
+            }
+            (None, None) => {}
+            _ => return Err(Error::Config("client_cert and client_key must be given together".to_owned())),
+        }
+        if let Some(domain) = &self.tls_domain {
+            tls = tls.domain_name(domain.clone());
+        }
+        Ok(Some(tls))
+    }
+
+    fn channel(&self, node: &str, tls: &Option<ClientTlsConfig>) -> Result<DecExecClient<Channel>, Error> {
+        let connect_error = |source| Error::Connect { node: node.to_owned(), source };
+
+        let mut endpoint = Endpoint::from_shared(node.to_owned())
+            .map_err(connect_error)?
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs));
+        if let Some(tls) = tls {
+            endpoint = endpoint.tls_config(tls.clone()).map_err(connect_error)?;
+        }
+        Ok(DecExecClient::new(endpoint.connect_lazy()))
+    }
+
+    /// Sets up a channel to every configured node, in party order. Connections are made on first
+    /// use, so that an unreachable node is reported in the results of the request instead of
+    /// preventing requests to the other nodes.
+    pub fn channels(&self) -> Result<Vec<DecExecClient<Channel>>, Error> {
+        let tls = self.tls_config()?;
+        self.nodes.iter().map(|node| self.channel(node, &tls)).collect()
+    }
+}
@@ -0,0 +1,336 @@
+//! EIP-712 hashing of typed structured data, as signed by `eth_signTypedData_v4`.
+
+use serde_json::{json, Map, Value};
+use sha3::{Digest, Keccak256};
+
+use crate::eth::{decode_hex, parse_address};
+use crate::Error;
+
+const DOMAIN_TYPE: &str = "EIP712Domain";
+
+/// Fields of the domain in the order EIP-712 lists them, used when a document leaves out the
+/// `EIP712Domain` type
+const DOMAIN_FIELDS: [(&str, &str); 5] = [
+    ("name", "string"),
+    ("version", "string"),
+    ("chainId", "uint256"),
+    ("verifyingContract", "address"),
+    ("salt", "bytes32"),
+];
+
+fn invalid(message: String) -> Error {
+    Error::InvalidRequest(format!("invalid typed data: {}", message))
+}
+
+fn keccak(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// Computes the digest that `eth_signTypedData_v4` signs for a document with `types`,
+/// `primaryType`, `domain` and `message`:
+/// `keccak256(0x19 0x01 || hashStruct(domain) || hashStruct(message))`
+pub fn typed_data_hash(document: &Value) -> Result<[u8; 32], Error> {
+    let mut types = document["types"]
+        .as_object()
+        .cloned()
+        .ok_or_else(|| invalid("types is missing".to_owned()))?;
+    let domain = &document["domain"];
+    if !domain.is_object() {
+        return Err(invalid("domain is missing".to_owned()));
+    }
+    if !types.contains_key(DOMAIN_TYPE) {
+        let fields: Vec<Value> = DOMAIN_FIELDS
+            .iter()
+            .filter(|(name, _)| !domain[*name].is_null())
+            .map(|(name, ty)| json!({ "name": name, "type": ty }))
+            .collect();
+        types.insert(DOMAIN_TYPE.to_owned(), Value::Array(fields));
+    }
+    let primary_type = document["primaryType"]
+        .as_str()
+        .ok_or_else(|| invalid("primaryType is missing".to_owned()))?;
+
+    let encoder = Encoder { types: &types };
+    let mut preimage = vec![0x19, 0x01];
+    preimage.extend_from_slice(&encoder.hash_struct(DOMAIN_TYPE, domain)?);
+    // A document whose primary type is the domain only signs the domain
+    if primary_type != DOMAIN_TYPE {
+        preimage.extend_from_slice(&encoder.hash_struct(primary_type, &document["message"])?);
+    }
+    Ok(keccak(&preimage))
+}
+
+struct Encoder<'a> {
+    types: &'a Map<String, Value>,
+}
+
+impl Encoder<'_> {
+    /// Returns the name and type of each member of the struct type `name`
+    fn members(&self, name: &str) -> Result<Vec<(&str, &str)>, Error> {
+        let members = self
+            .types
+            .get(name)
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid(format!("type {} is not an array of members", name)))?;
+        members
+            .iter()
+            .map(|member| match (member["name"].as_str(), member["type"].as_str()) {
+                (Some(name), Some(ty)) => Ok((name, ty)),
+                _ => Err(invalid(format!("a member of {} lacks a name or type", name))),
+            })
+            .collect()
+    }
+
+    /// Collects the struct types that `name` refers to, directly or through other structs
+    fn dependencies<'b>(&'b self, name: &'b str, found: &mut Vec<&'b str>) -> Result<(), Error> {
+        let name = name.split('[').next().unwrap_or(name);
+        if found.contains(&name) || !self.types.contains_key(name) {
+            return Ok(());
+        }
+        found.push(name);
+        for (_, ty) in self.members(name)? {
+            self.dependencies(ty, found)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes `name` followed by the types it refers to in alphabetical order, such as
+    /// `Mail(Person from,Person to,string contents)Person(string name,address wallet)`
+    fn encode_type(&self, name: &str) -> Result<String, Error> {
+        let mut dependencies = vec![];
+        self.dependencies(name, &mut dependencies)?;
+        dependencies.retain(|dependency| *dependency != name);
+        dependencies.sort_unstable();
+
+        let mut encoded = String::new();
+        for ty in std::iter::once(name).chain(dependencies) {
+            let members: Vec<String> =
+                self.members(ty)?.iter().map(|(name, ty)| format!("{} {}", ty, name)).collect();
+            encoded.push_str(&format!("{}({})", ty, members.join(",")));
+        }
+        Ok(encoded)
+    }
+
+    fn hash_struct(&self, name: &str, data: &Value) -> Result<[u8; 32], Error> {
+        if !self.types.contains_key(name) {
+            return Err(invalid(format!("type {} is not defined", name)));
+        }
+        if !data.is_object() {
+            return Err(invalid(format!("value of type {} is not an object", name)));
+        }
+
+        let mut encoded = keccak(self.encode_type(name)?.as_bytes()).to_vec();
+        for (member, ty) in self.members(name)? {
+            let value = &data[member];
+            if value.is_null() {
+                return Err(invalid(format!("{}.{} is missing", name, member)));
+            }
+            encoded.extend_from_slice(&self.encode_value(ty, value)?);
+        }
+        Ok(keccak(&encoded))
+    }
+
+    /// Encodes one value as a 32-byte word: atomic values in place and dynamic values, arrays
+    /// and structs by their hash
+    fn encode_value(&self, ty: &str, value: &Value) -> Result<[u8; 32], Error> {
+        if let Some(element_type) = ty.strip_suffix(']') {
+            let (element_type, length) = element_type
+                .rsplit_once('[')
+                .ok_or_else(|| invalid(format!("{} is not a valid array type", ty)))?;
+            let elements = value
+                .as_array()
+                .ok_or_else(|| invalid(format!("value of type {} is not an array", ty)))?;
+            if !length.is_empty() && length.parse() != Ok(elements.len()) {
+                return Err(invalid(format!("array of type {} has {} elements", ty, elements.len())));
+            }
+            let mut encoded = vec![];
+            for element in elements {
+                encoded.extend_from_slice(&self.encode_value(element_type, element)?);
+            }
+            return Ok(keccak(&encoded));
+        }
+        if self.types.contains_key(ty) {
+            return self.hash_struct(ty, value);
+        }
+
+        let not_a = |what: &str| invalid(format!("{} is not {}", value, what));
+        let hex_bytes = || {
+            value
+                .as_str()
+                .and_then(|bytes| decode_hex(bytes).ok())
+                .ok_or_else(|| not_a("hex bytes"))
+        };
+        let mut word = [0u8; 32];
+        match ty {
+            "string" => return Ok(keccak(value.as_str().ok_or_else(|| not_a("a string"))?.as_bytes())),
+            "bytes" => return Ok(keccak(&hex_bytes()?)),
+            "bool" => word[31] = value.as_bool().ok_or_else(|| not_a("a bool"))? as u8,
+            "address" => {
+                let address = value.as_str().ok_or_else(|| not_a("an address"))?;
+                word[12..].copy_from_slice(&parse_address(address)?);
+            }
+            _ => {
+                if let Some(size) = ty.strip_prefix("bytes") {
+                    let bytes = hex_bytes()?;
+                    if size.parse() != Ok(bytes.len()) || bytes.is_empty() || bytes.len() > 32 {
+                        return Err(invalid(format!("{} is not a {}", value, ty)));
+                    }
+                    word[..bytes.len()].copy_from_slice(&bytes);
+                } else if let Some(bits) = ty.strip_prefix("uint") {
+                    word = encode_integer(ty, bits, false, value)?;
+                } else if let Some(bits) = ty.strip_prefix("int") {
+                    word = encode_integer(ty, bits, true, value)?;
+                } else {
+                    return Err(invalid(format!("type {} is not defined", ty)));
+                }
+            }
+        }
+        Ok(word)
+    }
+}
+
+/// Parses a non-negative integer in decimal or 0x-prefixed hex into a 256-bit big-endian word
+fn parse_u256(text: &str) -> Option<[u8; 32]> {
+    let mut word = [0u8; 32];
+    if let Some(digits) = text.strip_prefix("0x") {
+        if digits.is_empty() || digits.len() > 64 {
+            return None;
+        }
+        let digits = format!("{:0>64}", digits);
+        hex::decode_to_slice(digits, &mut word).ok()?;
+        return Some(word);
+    }
+
+    if text.is_empty() {
+        return None;
+    }
+    for digit in text.chars() {
+        let mut carry = digit.to_digit(10)?;
+        for byte in word.iter_mut().rev() {
+            let product = *byte as u32 * 10 + carry;
+            *byte = product as u8;
+            carry = product >> 8;
+        }
+        if carry != 0 {
+            return None;
+        }
+    }
+    Some(word)
+}
+
+/// Encodes an integer of type `uint<bits>` or `int<bits>`, negative values in two's complement
+fn encode_integer(ty: &str, bits: &str, signed: bool, value: &Value) -> Result<[u8; 32], Error> {
+    let undefined = || invalid(format!("type {} is not defined", ty));
+    let bits: usize = if bits.is_empty() { 256 } else { bits.parse().map_err(|_| undefined())? };
+    if bits == 0 || bits > 256 || bits % 8 != 0 {
+        return Err(undefined());
+    }
+
+    let text = match value {
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => text.clone(),
+        _ => return Err(invalid(format!("{} is not an integer", value))),
+    };
+    let out_of_range = || invalid(format!("{} is out of range for {}", text, ty));
+    let (negative, magnitude) = match text.strip_prefix('-') {
+        Some(magnitude) if signed => (true, magnitude),
+        Some(_) => return Err(out_of_range()),
+        None => (false, text.as_str()),
+    };
+    let mut word = parse_u256(magnitude).ok_or_else(|| invalid(format!("{} is not an integer", text)))?;
+    if negative {
+        let mut carry = true;
+        for byte in word.iter_mut().rev() {
+            let (sum, overflow) = (!*byte).overflowing_add(carry as u8);
+            *byte = sum;
+            carry = overflow;
+        }
+    }
+
+    // Every byte above the width of the type must repeat the sign, and the sign must be right
+    let prefix = 32 - bits / 8;
+    let is_negative = signed && word[prefix] & 0x80 != 0;
+    let fill = if is_negative { 0xff } else { 0 };
+    if word[..prefix].iter().any(|byte| *byte != fill) || (is_negative != negative && word.iter().any(|b| *b != 0)) {
+        return Err(out_of_range());
+    }
+    Ok(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `Mail` example of EIP-712
+    fn mail() -> Value {
+        json!({
+            "types": {
+                "EIP712Domain": [
+                    { "name": "name", "type": "string" },
+                    { "name": "version", "type": "string" },
+                    { "name": "chainId", "type": "uint256" },
+                    { "name": "verifyingContract", "type": "address" },
+                ],
+                "Person": [
+                    { "name": "name", "type": "string" },
+                    { "name": "wallet", "type": "address" },
+                ],
+                "Mail": [
+                    { "name": "from", "type": "Person" },
+                    { "name": "to", "type": "Person" },
+                    { "name": "contents", "type": "string" },
+                ],
+            },
+            "primaryType": "Mail",
+            "domain": {
+                "name": "Ether Mail",
+                "version": "1",
+                "chainId": 1,
+                "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC",
+            },
+            "message": {
+                "from": { "name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826" },
+                "to": { "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" },
+                "contents": "Hello, Bob!",
+            },
+        })
+    }
+
+    const MAIL_HASH: &str = "be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2";
+
+    #[test]
+    fn mail_type_encoding() {
+        let document = mail();
+        let encoder = Encoder { types: document["types"].as_object().unwrap() };
+        assert_eq!(
+            encoder.encode_type("Mail").unwrap(),
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)"
+        );
+    }
+
+    #[test]
+    fn mail_hash_matches_eip712() {
+        assert_eq!(hex::encode(typed_data_hash(&mail()).unwrap()), MAIL_HASH);
+    }
+
+    #[test]
+    fn domain_type_defaults_to_the_fields_present() {
+        let mut document = mail();
+        document["types"].as_object_mut().unwrap().remove(DOMAIN_TYPE);
+        assert_eq!(hex::encode(typed_data_hash(&document).unwrap()), MAIL_HASH);
+    }
+
+    #[test]
+    fn changed_message_changes_the_hash() {
+        let mut document = mail();
+        document["message"]["contents"] = json!("Hello, Alice!");
+        assert_ne!(hex::encode(typed_data_hash(&document).unwrap()), MAIL_HASH);
+    }
+
+    #[test]
+    fn unknown_primary_type_is_rejected() {
+        let mut document = mail();
+        document["primaryType"] = json!("Letter");
+        assert!(typed_data_hash(&document).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+
+use crate::NodeResults;
+
+/// Errors returned by [`SigningClient`](crate::SigningClient)
+#[derive(Debug)]
+pub enum Error {
+    /// The client configuration is invalid or could not be read
+    Config(String),
+    /// The request was rejected before being sent to the nodes
+    InvalidRequest(String),
+    /// The endpoint of a node is invalid
+    Connect {
+        node: String,
+        source: tonic::transport::Error,
+    },
+    /// Too few nodes succeeded. `requirement` describes which nodes had to succeed.
+    Quorum {
+        requirement: String,
+        nodes: NodeResults,
+    },
+    /// A node replied with something that is not a valid response
+    InvalidResponse { node: usize, message: String },
+    /// Nodes returned results that do not agree with each other
+    Inconsistent { message: String, nodes: NodeResults },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "invalid configuration: {}", message),
+            Error::InvalidRequest(message) => write!(f, "{}", message),
+            Error::Connect { node, source } => write!(f, "cannot connect to {}: {}", node, source),
+            Error::Quorum { requirement, nodes } => {
+                write!(f, "quorum not reached, {}\n{}", requirement, nodes)
+            }
+            Error::InvalidResponse { node, message } => {
+                write!(f, "party {} sent an invalid response: {}", node + 1, message)
+            }
+            Error::Inconsistent { message, nodes } => write!(f, "{}\n{}", message, nodes),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connect { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
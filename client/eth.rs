@@ -0,0 +1,648 @@
+//! Ethereum transactions and messages: decoding, signing hashes and signed encodings.
+//!
+//! Legacy (optionally EIP-155), EIP-2930 access list and EIP-1559 dynamic fee transactions are
+//! supported. Unsigned transactions are read from the JSON accepted by `eth_signTransaction` or
+//! from their RLP encoding, which is also what the client sends to the nodes. Messages are signed
+//! as `personal_sign` does, following EIP-191.
+
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use curv::BigInt;
+use rlp::{Rlp, RlpStream};
+use serde_json::{json, Map, Value};
+use sha3::{Digest, Keccak256};
+
+use crate::hd::DerivationPath;
+use crate::{Error, SignatureRecid};
+
+fn invalid(message: String) -> Error {
+    Error::InvalidRequest(format!("invalid transaction: {}", message))
+}
+
+/// Returns the Ethereum address of a public key: the last 20 bytes of the Keccak-256 hash of its
+/// uncompressed encoding
+pub fn address(public_key: &Point<Secp256k1>) -> [u8; 20] {
+    let hash = Keccak256::digest(&public_key.to_bytes(false)[1..]);
+    let mut address = [0; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// Formats an address with the EIP-55 mixed-case checksum
+pub fn checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = hex::encode(Keccak256::digest(lower.as_bytes()));
+    let checksummed: String = lower
+        .chars()
+        .zip(hash.chars())
+        .map(|(c, h)| if h >= '8' { c.to_ascii_uppercase() } else { c })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+/// Computes the EIP-191 digest that `personal_sign` signs:
+/// `keccak256("\x19Ethereum Signed Message:\n" || len(message) || message)`
+pub fn personal_message_hash(message: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(format!("\x19Ethereum Signed Message:\n{}", message.len()));
+    hasher.update(message);
+    hasher.finalize().into()
+}
+
+/// Encodes a signature as the 65 bytes `r || s || v` returned by `personal_sign` and
+/// `eth_signTypedData_v4`, with `v` being 27 or 28
+pub fn signature_bytes(signature: &SignatureRecid) -> Result<[u8; 65], Error> {
+    let signature = normalize_s(signature);
+    if signature.recid > 1 {
+        return Err(Error::InvalidRequest(format!("recovery id {} cannot be encoded", signature.recid)));
+    }
+    let mut bytes = [0; 65];
+    bytes[..32].copy_from_slice(&signature.r.to_bytes());
+    bytes[32..64].copy_from_slice(&signature.s.to_bytes());
+    bytes[64] = 27 + signature.recid;
+    Ok(bytes)
+}
+
+/// Parses a 20-byte address in hex, with or without the 0x prefix
+pub fn parse_address(address: &str) -> Result<[u8; 20], Error> {
+    let bytes = decode_hex(address).map_err(|_| invalid(format!("{} is not an address", address)))?;
+    bytes.try_into().map_err(|_| invalid(format!("{} is not 20 bytes long", address)))
+}
+
+/// Decodes hex with an optional 0x prefix
+pub fn decode_hex(value: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(value.trim().trim_start_matches("0x"))
+}
+
+/// How the fee of a transaction is set, which also determines its type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fee {
+    /// Legacy transaction, type 0
+    Legacy { gas_price: u128 },
+    /// EIP-2930 access list transaction, type 1
+    AccessList { gas_price: u128 },
+    /// EIP-1559 dynamic fee transaction, type 2
+    DynamicFee { max_priority_fee_per_gas: u128, max_fee_per_gas: u128 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessListItem {
+    pub address: [u8; 20],
+    pub storage_keys: Vec<[u8; 32]>,
+}
+
+/// An unsigned Ethereum transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub fee: Fee,
+    /// Chain the transaction is valid on. Only legacy transactions may leave it out, in which case
+    /// they can be replayed on every chain.
+    pub chain_id: Option<u64>,
+    pub nonce: u64,
+    pub gas_limit: u64,
+    /// Recipient, `None` for contract creation
+    pub to: Option<[u8; 20]>,
+    /// Amount in wei
+    pub value: u128,
+    pub data: Vec<u8>,
+    /// Addresses and storage keys the transaction accesses, empty for legacy transactions
+    pub access_list: Vec<AccessListItem>,
+}
+
+/// A transaction with its signature
+#[derive(Clone, Debug)]
+pub struct SignedTransaction {
+    /// Signed encoding, ready for `eth_sendRawTransaction`
+    pub raw: Vec<u8>,
+    /// Transaction hash, the Keccak-256 hash of `raw`
+    pub hash: [u8; 32],
+    pub signature: SignatureRecid,
+}
+
+/// An Ethereum account backed by a threshold key or one of its BIP32 children
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub address: [u8; 20],
+    pub key_file: String,
+    /// Path of the child key, empty for the key itself
+    pub path: DerivationPath,
+    pub threshold: u16,
+    pub num_parties: u16,
+}
+
+/// Reads a quantity given as a JSON number, a decimal string or a 0x-prefixed hex string
+fn quantity(tx: &Value, field: &str) -> Result<Option<u128>, Error> {
+    let not_quantity = || invalid(format!("{} is not a quantity", field));
+    match &tx[field] {
+        Value::Null => Ok(None),
+        Value::Number(number) => number.as_u64().map(|n| Some(n as u128)).ok_or_else(not_quantity),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(digits) => u128::from_str_radix(digits, 16).map(Some).map_err(|_| not_quantity()),
+            None => s.parse().map(Some).map_err(|_| not_quantity()),
+        },
+        _ => Err(not_quantity()),
+    }
+}
+
+fn quantity_u64(tx: &Value, field: &str) -> Result<Option<u64>, Error> {
+    quantity(tx, field)?
+        .map(|n| u64::try_from(n).map_err(|_| invalid(format!("{} is too large", field))))
+        .transpose()
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T, Error> {
+    value.ok_or_else(|| invalid(format!("{} is missing", field)))
+}
+
+/// Minimal big-endian encoding of an integer, as RLP encodes it
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn hex_quantity(n: u128) -> String {
+    format!("{:#x}", n)
+}
+
+impl Transaction {
+    /// Reads a transaction from JSON or from the hex of its unsigned encoding
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let input = input.trim();
+        if input.starts_with('{') {
+            let tx: Value = serde_json::from_str(input).map_err(|e| invalid(e.to_string()))?;
+            Transaction::from_json(&tx)
+        } else {
+            let bytes = decode_hex(input).map_err(|e| invalid(e.to_string()))?;
+            Transaction::decode_unsigned(&bytes)
+        }
+    }
+
+    /// Reads a transaction in the JSON format of `eth_signTransaction`. The type is taken from
+    /// `type` if given, otherwise from the fee fields that are present.
+    pub fn from_json(tx: &Value) -> Result<Self, Error> {
+        let tx_type = match quantity(tx, "type")? {
+            Some(tx_type) => tx_type,
+            None if !tx["maxFeePerGas"].is_null() => 2,
+            None if !tx["accessList"].is_null() => 1,
+            None => 0,
+        };
+        let fee = match tx_type {
+            0 => Fee::Legacy { gas_price: required(quantity(tx, "gasPrice")?, "gasPrice")? },
+            1 => Fee::AccessList { gas_price: required(quantity(tx, "gasPrice")?, "gasPrice")? },
+            2 => Fee::DynamicFee {
+                max_priority_fee_per_gas: required(
+                    quantity(tx, "maxPriorityFeePerGas")?,
+                    "maxPriorityFeePerGas",
+                )?,
+                max_fee_per_gas: required(quantity(tx, "maxFeePerGas")?, "maxFeePerGas")?,
+            },
+            other => return Err(invalid(format!("type {} is not supported", other))),
+        };
+
+        let gas_limit = match quantity_u64(tx, "gas")? {
+            Some(gas) => gas,
+            None => required(quantity_u64(tx, "gasLimit")?, "gas")?,
+        };
+        let to = match tx["to"].as_str() {
+            Some(to) if !to.is_empty() => Some(parse_address(to)?),
+            _ => None,
+        };
+        let data = match tx["data"].as_str().or_else(|| tx["input"].as_str()) {
+            Some(data) => decode_hex(data).map_err(|_| invalid("data is not valid hex".to_owned()))?,
+            None => vec![],
+        };
+
+        let mut access_list = vec![];
+        if let Some(items) = tx["accessList"].as_array() {
+            if tx_type == 0 && !items.is_empty() {
+                return Err(invalid("legacy transactions have no access list".to_owned()));
+            }
+            for item in items {
+                let address = parse_address(item["address"].as_str().unwrap_or_default())?;
+                let storage_keys = item["storageKeys"]
+                    .as_array()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|key| {
+                        let bytes = decode_hex(key.as_str().unwrap_or_default())
+                            .map_err(|_| invalid("storage key is not valid hex".to_owned()))?;
+                        bytes.try_into().map_err(|_| invalid("storage key is not 32 bytes long".to_owned()))
+                    })
+                    .collect::<Result<_, _>>()?;
+                access_list.push(AccessListItem { address, storage_keys });
+            }
+        }
+
+        let transaction = Transaction {
+            fee,
+            chain_id: quantity_u64(tx, "chainId")?,
+            nonce: required(quantity_u64(tx, "nonce")?, "nonce")?,
+            gas_limit,
+            to,
+            value: quantity(tx, "value")?.unwrap_or(0),
+            data,
+            access_list,
+        };
+        transaction.validate()?;
+        Ok(transaction)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.chain_id.is_none() && !matches!(self.fee, Fee::Legacy { .. }) {
+            return Err(invalid("chainId is missing".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Decodes the unsigned encoding of a transaction: the RLP list of a legacy transaction, with
+    /// the EIP-155 chain id if it has one, or the type byte followed by the RLP list of the fields
+    pub fn decode_unsigned(bytes: &[u8]) -> Result<Self, Error> {
+        let decode_err = |e: rlp::DecoderError| invalid(e.to_string());
+        let (tx_type, payload) = match bytes.first() {
+            Some(&tx_type) if tx_type < 0x80 => (tx_type, &bytes[1..]),
+            Some(_) => (0, bytes),
+            None => return Err(invalid("transaction is empty".to_owned())),
+        };
+
+        let rlp = Rlp::new(payload);
+        if !rlp.is_list() || rlp.payload_info().map_err(decode_err)?.total() != payload.len() {
+            return Err(invalid("transaction is not a single RLP list".to_owned()));
+        }
+        let fields = rlp.item_count().map_err(decode_err)?;
+
+        // Fields that follow the fee in every type: gas limit, recipient, value and data
+        let body = |start: usize| -> Result<(u64, Option<[u8; 20]>, u128, Vec<u8>), Error> {
+            let to = rlp.at(start + 1).map_err(decode_err)?;
+            let to = match to.data().map_err(decode_err)? {
+                [] => None,
+                address => Some(
+                    address.try_into().map_err(|_| invalid("recipient is not 20 bytes long".to_owned()))?,
+                ),
+            };
+            Ok((
+                rlp.val_at(start).map_err(decode_err)?,
+                to,
+                rlp.val_at(start + 2).map_err(decode_err)?,
+                rlp.val_at(start + 3).map_err(decode_err)?,
+            ))
+        };
+
+        let transaction = match (tx_type, fields) {
+            (0, 6) | (0, 9) => {
+                let (gas_limit, to, value, data) = body(2)?;
+                let chain_id = if fields == 9 {
+                    let (zero_r, zero_s): (u64, u64) =
+                        (rlp.val_at(7).map_err(decode_err)?, rlp.val_at(8).map_err(decode_err)?);
+                    if zero_r != 0 || zero_s != 0 {
+                        return Err(invalid("transaction is already signed".to_owned()));
+                    }
+                    Some(rlp.val_at(6).map_err(decode_err)?)
+                } else {
+                    None
+                };
+                Transaction {
+                    fee: Fee::Legacy { gas_price: rlp.val_at(1).map_err(decode_err)? },
+                    chain_id,
+                    nonce: rlp.val_at(0).map_err(decode_err)?,
+                    gas_limit,
+                    to,
+                    value,
+                    data,
+                    access_list: vec![],
+                }
+            }
+            (1, 8) => {
+                let (gas_limit, to, value, data) = body(3)?;
+                Transaction {
+                    fee: Fee::AccessList { gas_price: rlp.val_at(2).map_err(decode_err)? },
+                    chain_id: Some(rlp.val_at(0).map_err(decode_err)?),
+                    nonce: rlp.val_at(1).map_err(decode_err)?,
+                    gas_limit,
+                    to,
+                    value,
+                    data,
+                    access_list: decode_access_list(&rlp.at(7).map_err(decode_err)?)?,
+                }
+            }
+            (2, 9) => {
+                let (gas_limit, to, value, data) = body(4)?;
+                Transaction {
+                    fee: Fee::DynamicFee {
+                        max_priority_fee_per_gas: rlp.val_at(2).map_err(decode_err)?,
+                        max_fee_per_gas: rlp.val_at(3).map_err(decode_err)?,
+                    },
+                    chain_id: Some(rlp.val_at(0).map_err(decode_err)?),
+                    nonce: rlp.val_at(1).map_err(decode_err)?,
+                    gas_limit,
+                    to,
+                    value,
+                    data,
+                    access_list: decode_access_list(&rlp.at(8).map_err(decode_err)?)?,
+                }
+            }
+            (0..=2, _) => {
+                return Err(invalid(format!("{} fields do not make an unsigned type {} transaction", fields, tx_type)))
+            }
+            (other, _) => return Err(invalid(format!("type {} is not supported", other))),
+        };
+        Ok(transaction)
+    }
+
+    /// The EIP-2718 type of the transaction
+    pub fn tx_type(&self) -> u8 {
+        match self.fee {
+            Fee::Legacy { .. } => 0,
+            Fee::AccessList { .. } => 1,
+            Fee::DynamicFee { .. } => 2,
+        }
+    }
+
+    /// Appends every field but the signature, in the order of the transaction type
+    fn append_fields(&self, stream: &mut RlpStream) {
+        let chain_id = self.chain_id.unwrap_or_default();
+        match &self.fee {
+            Fee::Legacy { gas_price } => {
+                stream.append(&self.nonce).append(gas_price);
+            }
+            Fee::AccessList { gas_price } => {
+                stream.append(&chain_id).append(&self.nonce).append(gas_price);
+            }
+            Fee::DynamicFee { max_priority_fee_per_gas, max_fee_per_gas } => {
+                stream
+                    .append(&chain_id)
+                    .append(&self.nonce)
+                    .append(max_priority_fee_per_gas)
+                    .append(max_fee_per_gas);
+            }
+        }
+        stream.append(&self.gas_limit);
+        match &self.to {
+            Some(to) => stream.append(&to.as_slice()),
+            None => stream.append_empty_data(),
+        };
+        stream.append(&self.value).append(&self.data);
+
+        if self.tx_type() != 0 {
+            stream.begin_list(self.access_list.len());
+            for item in &self.access_list {
+                stream.begin_list(2).append(&item.address.as_slice());
+                stream.begin_list(item.storage_keys.len());
+                for key in &item.storage_keys {
+                    stream.append(&key.as_slice());
+                }
+            }
+        }
+    }
+
+    /// Number of fields of the unsigned transaction
+    fn num_fields(&self) -> usize {
+        match self.fee {
+            Fee::Legacy { .. } if self.chain_id.is_some() => 9,
+            Fee::Legacy { .. } => 6,
+            Fee::AccessList { .. } => 8,
+            Fee::DynamicFee { .. } => 9,
+        }
+    }
+
+    fn with_type(&self, rlp: &[u8]) -> Vec<u8> {
+        match self.tx_type() {
+            0 => rlp.to_vec(),
+            tx_type => [&[tx_type], rlp].concat(),
+        }
+    }
+
+    /// Encodes the unsigned transaction, which is the preimage of its signing hash
+    pub fn encode_unsigned(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(self.num_fields());
+        self.append_fields(&mut stream);
+        if let (Fee::Legacy { .. }, Some(chain_id)) = (&self.fee, self.chain_id) {
+            // EIP-155 replay protection
+            stream.append(&chain_id).append(&0u8).append(&0u8);
+        }
+        self.with_type(&stream.out())
+    }
+
+    /// Keccak-256 hash of the unsigned encoding, which is what the key signs
+    pub fn signing_hash(&self) -> [u8; 32] {
+        Keccak256::digest(self.encode_unsigned()).into()
+    }
+
+    /// Encodes the transaction with `signature`. Ethereum only accepts signatures with a low `s`,
+    /// so a high `s` is negated, which flips the recovery id.
+    pub fn encode_signed(&self, signature: &SignatureRecid) -> Result<SignedTransaction, Error> {
+        let signature = normalize_s(signature);
+        if signature.recid > 1 {
+            return Err(Error::InvalidRequest(format!(
+                "recovery id {} cannot be encoded in a transaction",
+                signature.recid
+            )));
+        }
+
+        let recid = signature.recid as u128;
+        let v = match (self.tx_type(), self.chain_id) {
+            (0, Some(chain_id)) => chain_id as u128 * 2 + 35 + recid,
+            (0, None) => 27 + recid,
+            _ => recid,
+        };
+        let num_fields = match self.fee {
+            Fee::Legacy { .. } => 6,
+            _ => self.num_fields(),
+        };
+
+        let mut stream = RlpStream::new_list(num_fields + 3);
+        self.append_fields(&mut stream);
+        stream
+            .append(&v)
+            .append(&trim_leading_zeros(&signature.r.to_bytes()))
+            .append(&trim_leading_zeros(&signature.s.to_bytes()));
+        let raw = self.with_type(&stream.out());
+
+        Ok(SignedTransaction { hash: Keccak256::digest(&raw).into(), raw, signature })
+    }
+
+    /// Describes the transaction in the JSON format of `eth_signTransaction`, with quantities in hex
+    pub fn to_json(&self) -> Value {
+        let mut tx = Map::new();
+        tx.insert("type".to_owned(), json!(hex_quantity(self.tx_type() as u128)));
+        if let Some(chain_id) = self.chain_id {
+            tx.insert("chainId".to_owned(), json!(hex_quantity(chain_id as u128)));
+        }
+        tx.insert("nonce".to_owned(), json!(hex_quantity(self.nonce as u128)));
+        match &self.fee {
+            Fee::Legacy { gas_price } | Fee::AccessList { gas_price } => {
+                tx.insert("gasPrice".to_owned(), json!(hex_quantity(*gas_price)));
+            }
+            Fee::DynamicFee { max_priority_fee_per_gas, max_fee_per_gas } => {
+                tx.insert("maxPriorityFeePerGas".to_owned(), json!(hex_quantity(*max_priority_fee_per_gas)));
+                tx.insert("maxFeePerGas".to_owned(), json!(hex_quantity(*max_fee_per_gas)));
+            }
+        }
+        tx.insert("gas".to_owned(), json!(hex_quantity(self.gas_limit as u128)));
+        tx.insert("to".to_owned(), json!(self.to.as_ref().map(checksum_address)));
+        tx.insert("value".to_owned(), json!(hex_quantity(self.value)));
+        tx.insert("data".to_owned(), json!(format!("0x{}", hex::encode(&self.data))));
+        if self.tx_type() != 0 {
+            let access_list: Vec<Value> = self
+                .access_list
+                .iter()
+                .map(|item| {
+                    json!({
+                        "address": checksum_address(&item.address),
+                        "storageKeys": item
+                            .storage_keys
+                            .iter()
+                            .map(|key| format!("0x{}", hex::encode(key)))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+            tx.insert("accessList".to_owned(), json!(access_list));
+        }
+        Value::Object(tx)
+    }
+}
+
+fn decode_access_list(rlp: &Rlp) -> Result<Vec<AccessListItem>, Error> {
+    let decode_err = |e: rlp::DecoderError| invalid(e.to_string());
+    let mut access_list = vec![];
+    for item in rlp.iter() {
+        let address = item.at(0).and_then(|address| address.data()).map_err(decode_err)?;
+        let storage_keys = item
+            .at(1)
+            .map_err(decode_err)?
+            .iter()
+            .map(|key| {
+                let key = key.data().map_err(decode_err)?;
+                key.try_into().map_err(|_| invalid("storage key is not 32 bytes long".to_owned()))
+            })
+            .collect::<Result<_, _>>()?;
+        access_list.push(AccessListItem {
+            address: address.try_into().map_err(|_| invalid("address is not 20 bytes long".to_owned()))?,
+            storage_keys,
+        });
+    }
+    Ok(access_list)
+}
+
+/// Replaces a signature whose `s` is above half the group order by the equivalent one with `-s`,
+/// as Ethereum requires since EIP-2
+pub fn normalize_s(signature: &SignatureRecid) -> SignatureRecid {
+    let half_order = Scalar::<Secp256k1>::group_order() / BigInt::from(2);
+    if signature.s.to_bigint() > half_order {
+        SignatureRecid { r: signature.r.clone(), s: -&signature.s, recid: signature.recid ^ 1 }
+    } else {
+        signature.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curv::arithmetic::Converter;
+
+    /// Transaction of the example in EIP-155
+    fn eip155_transaction() -> Transaction {
+        Transaction {
+            fee: Fee::Legacy { gas_price: 20_000_000_000 },
+            chain_id: Some(1),
+            nonce: 9,
+            gas_limit: 21000,
+            to: Some([0x35; 20]),
+            value: 1_000_000_000_000_000_000,
+            data: vec![],
+            access_list: vec![],
+        }
+    }
+
+    fn scalar(hex: &str) -> Scalar<Secp256k1> {
+        Scalar::from_bigint(&BigInt::from_hex(hex).unwrap())
+    }
+
+    /// Signature of the EIP-155 example with the private key 0x4646...46
+    fn eip155_signature() -> SignatureRecid {
+        SignatureRecid {
+            r: scalar("28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276"),
+            s: scalar("67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"),
+            recid: 0,
+        }
+    }
+
+    const EIP155_SIGNED: &str = concat!(
+        "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080",
+        "25a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+        "a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+    );
+
+    #[test]
+    fn eip155_signing_hash() {
+        let transaction = eip155_transaction();
+        assert_eq!(
+            hex::encode(transaction.encode_unsigned()),
+            "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080"
+        );
+        assert_eq!(
+            hex::encode(transaction.signing_hash()),
+            "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+    }
+
+    #[test]
+    fn eip155_transaction_from_json_and_rlp() {
+        let tx = json!({
+            "nonce": "0x9",
+            "gasPrice": "20000000000",
+            "gas": 21000,
+            "to": "0x3535353535353535353535353535353535353535",
+            "value": "0xde0b6b3a7640000",
+            "chainId": 1,
+        });
+        assert_eq!(Transaction::from_json(&tx).unwrap(), eip155_transaction());
+
+        let unsigned = hex::encode(eip155_transaction().encode_unsigned());
+        assert_eq!(Transaction::parse(&format!("0x{}", unsigned)).unwrap(), eip155_transaction());
+    }
+
+    #[test]
+    fn eip155_signed_encoding_has_chain_id_in_v() {
+        let signed = eip155_transaction().encode_signed(&eip155_signature()).unwrap();
+        // v = chain_id * 2 + 35 + recid = 37, encoded as 0x25
+        assert_eq!(hex::encode(&signed.raw), EIP155_SIGNED);
+        assert_eq!(hex::encode(signed.hash), "33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788");
+    }
+
+    #[test]
+    fn high_s_is_negated_and_flips_v() {
+        let signature = eip155_signature();
+        let high_s = SignatureRecid { r: signature.r.clone(), s: -&signature.s, recid: 1 };
+        let signed = eip155_transaction().encode_signed(&high_s).unwrap();
+        assert_eq!(hex::encode(&signed.raw), EIP155_SIGNED);
+    }
+
+    #[test]
+    fn address_of_eip155_key() {
+        let public_key = Point::generator() * &scalar(&"46".repeat(32));
+        assert_eq!(checksum_address(&address(&public_key)), "0x9d8A62f656a8d1615C1294fd71e9CFb3E4855A4F");
+    }
+
+    #[test]
+    fn checksum_address_matches_eip55() {
+        // EIP-55 examples
+        for expected in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            let address = parse_address(&expected.to_lowercase()).unwrap();
+            assert_eq!(checksum_address(&address), expected);
+        }
+    }
+
+    #[test]
+    fn personal_message_hash_prefixes_length() {
+        assert_eq!(
+            hex::encode(personal_message_hash(b"hello world")),
+            "d9eba16ed0ecae432b71fe008c98cc872bb4cc214d3220a36f365326cf807d68"
+        );
+    }
+}
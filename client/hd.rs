@@ -0,0 +1,252 @@
+//! Non-hardened BIP32 derivation on top of threshold keys.
+//!
+//! No party holds the master secret, so only non-hardened children can be derived: the child
+//! secret is the master secret plus a tweak computed from public data, which every node adds to
+//! its share. A threshold key has no chain code of its own, so the master chain code is derived
+//! from the joint public key.
+//!
+//! This departs from BIP32, where the chain code is as secret as the seed. Here anyone who knows
+//! the joint public key can compute the chain code, and with it every child public key, so the
+//! addresses derived from one key can all be linked to it and to each other.
+//!
+//! A child secret is the master secret plus a tweak that anyone can compute, so whoever learns one
+//! child secret learns the master secret of the threshold key, and with it every other child. In
+//! BIP32 a child secret together with the parent xpub gives away the parent secret too, but here
+//! the xpub is public by construction. Child secrets must therefore never be exported: nodes only
+//! derive the tweaked shares in memory while signing, and no party ever holds a whole child secret.
+
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use curv::BigInt;
+use hmac::{Hmac, Mac};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// Version bytes of a mainnet extended public key
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+
+/// Domain separator of the master chain code
+const CHAIN_CODE_DOMAIN: &[u8] = b"DOTS signing chain code";
+
+const HARDENED: u32 = 1 << 31;
+
+/// Path of non-hardened child indices, written as `m/0/1/2`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<Self, Error> {
+        let invalid = |message: String| Error::InvalidRequest(format!("invalid derivation path {}: {}", path, message));
+
+        let mut components = path.split('/');
+        if components.next() != Some("m") {
+            return Err(invalid("must start with m".to_owned()));
+        }
+        let mut indices = vec![];
+        for component in components {
+            if component.ends_with('\'') || component.ends_with('h') {
+                return Err(invalid("hardened derivation is not possible with threshold keys".to_owned()));
+            }
+            let index: u32 = component.parse().map_err(|_| invalid(format!("{} is not an index", component)))?;
+            if index >= HARDENED {
+                return Err(invalid(format!("index {} is hardened", index)));
+            }
+            indices.push(index);
+        }
+        Ok(DerivationPath(indices))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Public key, chain code and position of a node in the derivation tree
+#[derive(Clone, Debug)]
+pub struct ExtendedPublicKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: Point<Secp256k1>,
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+impl ExtendedPublicKey {
+    /// Returns the root of the tree of the threshold key with joint public key `public_key`. Its
+    /// chain code is public, unlike in BIP32: see the module documentation.
+    pub fn master(public_key: &Point<Secp256k1>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(CHAIN_CODE_DOMAIN);
+        hasher.update(public_key.to_bytes(true));
+
+        ExtendedPublicKey {
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code: hasher.finalize().into(),
+            public_key: public_key.clone(),
+        }
+    }
+
+    /// First four bytes of the HASH160 of the public key, which identify it to its children
+    pub fn fingerprint(&self) -> [u8; 4] {
+        let mut fingerprint = [0; 4];
+        fingerprint.copy_from_slice(&hash160(&self.public_key.to_bytes(true))[..4]);
+        fingerprint
+    }
+
+    /// Derives the non-hardened child `index`, returning it with the tweak that was added to the
+    /// secret key
+    pub fn derive_child(&self, index: u32) -> Result<(Self, Scalar<Secp256k1>), Error> {
+        if index >= HARDENED {
+            return Err(Error::InvalidRequest(format!("index {} is hardened", index)));
+        }
+
+        let mut mac = Hmac::<Sha512>::new_from_slice(&self.chain_code).expect("HMAC takes keys of any length");
+        mac.update(&self.public_key.to_bytes(true));
+        mac.update(&index.to_be_bytes());
+        let output = mac.finalize().into_bytes();
+        let (il, ir) = output.split_at(32);
+
+        // BIP32 skips indices whose tweak is out of range or yields the point at infinity
+        let tweak_int = BigInt::from_bytes(il);
+        if &tweak_int >= Scalar::<Secp256k1>::group_order() {
+            return Err(Error::InvalidRequest(format!("index {} yields an invalid key, use the next one", index)));
+        }
+        let tweak = Scalar::from_bigint(&tweak_int);
+        let public_key = &self.public_key + Point::generator() * &tweak;
+        if public_key.is_zero() {
+            return Err(Error::InvalidRequest(format!("index {} yields an invalid key, use the next one", index)));
+        }
+
+        let mut chain_code = [0; 32];
+        chain_code.copy_from_slice(ir);
+        let child = ExtendedPublicKey {
+            depth: self.depth.checked_add(1).ok_or_else(|| Error::InvalidRequest("path is too deep".to_owned()))?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            public_key,
+        };
+        Ok((child, tweak))
+    }
+
+    /// Derives the key at `path` below this one, returning it with the sum of the tweaks along the
+    /// path, which is added to the secret key
+    pub fn derive(&self, path: &DerivationPath) -> Result<(Self, Scalar<Secp256k1>), Error> {
+        let mut key = self.clone();
+        let mut tweak = Scalar::zero();
+        for index in &path.0 {
+            let (child, child_tweak) = key.derive_child(*index)?;
+            key = child;
+            tweak = tweak + child_tweak;
+        }
+        Ok((key, tweak))
+    }
+
+    /// Encodes the key as a base58check `xpub` string
+    pub fn to_xpub(&self) -> String {
+        let mut data = Vec::with_capacity(78);
+        data.extend_from_slice(&XPUB_VERSION);
+        data.push(self.depth);
+        data.extend_from_slice(&self.parent_fingerprint);
+        data.extend_from_slice(&self.child_number.to_be_bytes());
+        data.extend_from_slice(&self.chain_code);
+        data.extend_from_slice(&self.public_key.to_bytes(true));
+        bs58::encode(data).with_check().into_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Extended public keys of BIP32 test vector 1
+    const M: &str = concat!(
+        "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGh",
+        "ePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+    );
+    const M_0H: &str = concat!(
+        "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WE",
+        "jWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+    );
+    const M_0H_1: &str = concat!(
+        "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf",
+        "3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+    );
+    const M_0H_1_2H: &str = concat!(
+        "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4V",
+        "UNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+    );
+    const M_0H_1_2H_2_1000000000: &str = concat!(
+        "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FS",
+        "VqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+    );
+
+    fn parse_xpub(xpub: &str) -> ExtendedPublicKey {
+        let data = bs58::decode(xpub).with_check(None).into_vec().unwrap();
+        assert_eq!(data.len(), 78);
+        assert_eq!(data[..4], XPUB_VERSION);
+        ExtendedPublicKey {
+            depth: data[4],
+            parent_fingerprint: data[5..9].try_into().unwrap(),
+            child_number: u32::from_be_bytes(data[9..13].try_into().unwrap()),
+            chain_code: data[13..45].try_into().unwrap(),
+            public_key: Point::from_bytes(&data[45..78]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn xpub_encoding_round_trips() {
+        for xpub in [M, M_0H, M_0H_1_2H] {
+            assert_eq!(parse_xpub(xpub).to_xpub(), xpub);
+        }
+    }
+
+    #[test]
+    fn non_hardened_derivation_matches_bip32_vector_1() {
+        let path: DerivationPath = "m/1".parse().unwrap();
+        let (child, _) = parse_xpub(M_0H).derive(&path).unwrap();
+        assert_eq!(child.to_xpub(), M_0H_1);
+
+        let path: DerivationPath = "m/2/1000000000".parse().unwrap();
+        let (child, _) = parse_xpub(M_0H_1_2H).derive(&path).unwrap();
+        assert_eq!(child.to_xpub(), M_0H_1_2H_2_1000000000);
+    }
+
+    #[test]
+    fn tweak_moves_the_public_key() {
+        // Nodes add the tweak to their shares, so it must move the public key to the child's
+        let parent = parse_xpub(M_0H_1_2H);
+        let (child, tweak) = parent.derive(&"m/2/1000000000".parse().unwrap()).unwrap();
+        assert_eq!(child.public_key, &parent.public_key + Point::generator() * &tweak);
+    }
+
+    #[test]
+    fn derivation_paths() {
+        let path: DerivationPath = "m/0/7".parse().unwrap();
+        assert_eq!(path, DerivationPath(vec![0, 7]));
+        assert_eq!(path.to_string(), "m/0/7");
+        assert_eq!("m".parse::<DerivationPath>().unwrap(), DerivationPath::default());
+
+        for invalid in ["0/7", "m/0'/1", "m/0h", "m/2147483648", "m/x", "m//1"] {
+            assert!(invalid.parse::<DerivationPath>().is_err(), "{}", invalid);
+        }
+    }
+}
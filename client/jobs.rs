@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Progress of a ceremony on one node, identified by the request id that started it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job: String,
+    /// Function of the request, such as keygen or signing
+    pub function: String,
+    pub username: String,
+    pub state: JobState,
+    /// Round of the ceremony the node has reached, counting from 1
+    pub round: Option<u32>,
+    /// Parties that take part in the ceremony
+    pub parties: Vec<u16>,
+    /// Result the node returned, once the job succeeded
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    /// Seconds since the Unix epoch at which the node started the job
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
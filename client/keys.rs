@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+/// Metadata a node recorded for its share of a key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyInfo {
+    pub key_file: String,
+    /// Identifies the key on every node
+    pub id: String,
+    pub owner: String,
+    pub num_parties: u16,
+    pub threshold: u16,
+    /// Joint public key, compressed and hex encoded
+    pub public_key: String,
+    /// Seconds since the Unix epoch at which the node stored its share
+    pub created_at: u64,
+    pub refresh_epoch: u64,
+    /// Party index of the node's share
+    pub party_index: u16,
+    /// Party indices of all shares of the key
+    pub party_indices: Vec<u16>,
+    /// Seconds since the Unix epoch at which the node deleted its share
+    pub deleted_at: Option<u64>,
+}
+
+impl KeyInfo {
+    /// Checks that two nodes describe the same key. The creation and deletion times and the party
+    /// index are specific to each node and are not compared.
+    pub fn same_key(&self, other: &KeyInfo) -> bool {
+        self.key_file == other.key_file
+            && self.id == other.id
+            && self.owner == other.owner
+            && self.num_parties == other.num_parties
+            && self.threshold == other.threshold
+            && self.public_key == other.public_key
+            && self.refresh_epoch == other.refresh_epoch
+            && self.party_indices == other.party_indices
+            && self.deleted_at.is_some() == other.deleted_at.is_some()
+    }
+}
+
+/// Metadata of one key as reported by every node of the cluster
+#[derive(Clone, Debug)]
+pub struct ClusterKey {
+    pub key_file: String,
+    /// Metadata of each node in party order, `None` where the node has no record or did not answer
+    pub nodes: Vec<Option<KeyInfo>>,
+}
+
+impl ClusterKey {
+    /// Returns the metadata that the most nodes agree on
+    pub fn consensus(&self) -> Option<&KeyInfo> {
+        let recorded = self.nodes.iter().flatten();
+        recorded
+            .clone()
+            .max_by_key(|info| recorded.clone().filter(|other| info.same_key(other)).count())
+    }
+
+    /// Returns the parties, counting from 1, whose metadata is missing, differs from the consensus
+    /// or names another party index than their own
+    pub fn disagreeing(&self) -> Vec<usize> {
+        let consensus = self.consensus();
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(node, info)| match (info, consensus) {
+                (Some(info), Some(consensus)) => {
+                    !info.same_key(consensus) || info.party_index as usize != node + 1
+                }
+                _ => true,
+            })
+            .map(|(node, _)| node + 1)
+            .collect()
+    }
+}
+
+/// Groups the metadata returned by each node, in party order, by key file
+pub(crate) fn cluster_keys(per_node: &[Vec<KeyInfo>]) -> Vec<ClusterKey> {
+    let mut key_files: Vec<&str> = per_node.iter().flatten().map(|info| info.key_file.as_str()).collect();
+    key_files.sort();
+    key_files.dedup();
+
+    key_files
+        .into_iter()
+        .map(|key_file| ClusterKey {
+            key_file: key_file.to_owned(),
+            nodes: per_node
+                .iter()
+                .map(|keys| keys.iter().find(|info| info.key_file == key_file).cloned())
+                .collect(),
+        })
+        .collect()
+}
@@ -0,0 +1,1235 @@
+//! Client library of the DOTS threshold signing application.
+//!
+//! [`SigningClient`] holds a connection to every node of a cluster and sends each request to all
+//! of them, so that services can generate keys and sign without going through the `client` binary.
+
+use curv::arithmetic::Converter;
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use curv::BigInt;
+use data_encoding::BASE32_NOPAD;
+use dotspb::dec_exec::dec_exec_client::DecExecClient;
+use dotspb::dec_exec::App;
+use futures::future;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use std::time::Duration;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Status};
+use uuid::Uuid;
+
+use bitcoin::psbt::Psbt;
+use eth::{SignedTransaction, Transaction};
+use hd::{DerivationPath, ExtendedPublicKey};
+
+mod config;
+pub mod eip712;
+mod error;
+pub mod eth;
+pub mod hd;
+mod jobs;
+mod keys;
+mod nodes;
+pub mod psbt;
+
+pub use config::ClientConfig;
+pub use error::Error;
+pub use jobs::{JobState, JobStatus};
+pub use keys::{ClusterKey, KeyInfo};
+pub use nodes::{FailureKind, NodeResult, NodeResults, NodeStatus, Reply};
+pub use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+
+const APP_NAME: &str = "signing";
+
+fn uuid_to_uuidpb(id: Uuid) -> dotspb::dec_exec::Uuid {
+    dotspb::dec_exec::Uuid {
+        hi: (id.as_u128() >> 64) as u64,
+        lo: id.as_u128() as u64,
+    }
+}
+
+/// Parameters of a request, sent as JSON in the first argument of the DOTS `App` message
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Params {
+    User {
+        username: String,
+        password: String,
+    },
+    /// Asks for the progress of the job started by request `job`
+    Job {
+        username: String,
+        password: String,
+        job: String,
+    },
+    /// Checks the password and, if enrolled, the second factor of a user
+    Login {
+        username: String,
+        password: String,
+        totp: Option<String>,
+    },
+    K {
+        username: String,
+        password: String,
+        key_file: String,
+        num_parties: u16,
+        num_threshold: u16,
+        totp: Option<String>,
+        replace: bool,
+    },
+    S {
+        username: String,
+        password: String,
+        key_file: String,
+        num_threshold: u16,
+        active_parties: Vec<u16>,
+        /// Hex-encoded 32-byte digest of the message
+        digest: String,
+        /// BIP32 path of the child key to sign with
+        derivation_path: Option<String>,
+        /// Data the digest was computed from, which the nodes decode and hash themselves
+        preimage: Option<Preimage>,
+        totp: Option<String>,
+    },
+    /// Signs every input of a PSBT that the key owns
+    Psbt {
+        username: String,
+        password: String,
+        key_file: String,
+        num_threshold: u16,
+        active_parties: Vec<u16>,
+        /// Base64-encoded PSBT
+        psbt: String,
+        /// Sign owned inputs that ask for another sighash type than `SIGHASH_ALL`
+        any_sighash: bool,
+        totp: Option<String>,
+    },
+    Register {
+        username: String,
+        password: String,
+        new_username: String,
+        new_password: String,
+        role: Option<String>,
+        totp_secret: Option<String>,
+    },
+    SetRole {
+        username: String,
+        password: String,
+        target: String,
+        role: String,
+    },
+    Unlock {
+        username: String,
+        password: String,
+        target: String,
+    },
+    ChangePassword {
+        username: String,
+        password: String,
+        new_password: String,
+        totp: Option<String>,
+    },
+    DeleteUser {
+        username: String,
+        password: String,
+        target: String,
+        transfer_to: Option<String>,
+    },
+    Key {
+        username: String,
+        password: String,
+        key_file: String,
+    },
+    DeleteKey {
+        username: String,
+        password: String,
+        key_file: String,
+        totp: Option<String>,
+    },
+    ExportBackup {
+        username: String,
+        password: String,
+        key_file: String,
+        party: u16,
+        passphrase: Option<String>,
+        recipient: Option<String>,
+        totp: Option<String>,
+    },
+    RecoverShare {
+        username: String,
+        password: String,
+        key_file: String,
+        lost_party: u16,
+        helpers: Vec<u16>,
+        num_parties: u16,
+        totp: Option<String>,
+    },
+    ImportBackup {
+        username: String,
+        password: String,
+        key_file: String,
+        party: u16,
+        backup: Value,
+        passphrase: Option<String>,
+        secret_key: Option<String>,
+        totp: Option<String>,
+    },
+}
+
+/// Credentials of the user on whose behalf requests are made
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// One-time code of the user's TOTP second factor
+    pub totp: Option<String>,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Self {
+        Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+            totp: None,
+        }
+    }
+
+    pub fn with_totp(mut self, code: &str) -> Self {
+        self.totp = Some(code.to_owned());
+        self
+    }
+}
+
+/// A registered user as listed by [`SigningClient::list_users`]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub role: String,
+}
+
+/// Who can decrypt a share backup
+pub enum BackupRecipient {
+    Passphrase(String),
+    /// Compressed secp256k1 public key of the operator in hex
+    PublicKey(String),
+}
+
+/// Secret that decrypts a share backup
+pub enum BackupSecret {
+    Passphrase(String),
+    /// secp256k1 secret key of the operator in hex
+    SecretKey(String),
+}
+
+/// Generates a secp256k1 key pair for encrypting share backups, returning the secret key and the
+/// compressed public key in hex
+pub fn generate_backup_keypair() -> (String, String) {
+    let secret = Scalar::<Secp256k1>::random();
+    let public = Point::generator() * &secret;
+    (hex::encode(secret.to_bytes()), hex::encode(public.to_bytes(true)))
+}
+
+/// Generates a random 160-bit TOTP secret encoded in base32
+pub fn generate_totp_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// Issuer that authenticator apps show next to the accounts enrolled with [`totp_uri`]
+pub const TOTP_ISSUER: &str = "DOTS signing";
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Returns the otpauth URI that enrolls the TOTP `secret` of `username` in an authenticator app.
+/// The issuer and username are percent-encoded, so that a username holding `:`, `&` or `#` cannot
+/// change the label or parameters the app reads.
+pub fn totp_uri(username: &str, secret: &str) -> String {
+    let issuer = percent_encode(TOTP_ISSUER);
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}", issuer, percent_encode(username), secret, issuer)
+}
+
+/// Hash function applied to a message to obtain the digest that the nodes sign
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashScheme {
+    Sha256,
+    /// Keccak-256 as used by Ethereum, which differs from standard SHA3-256 in its padding
+    Keccak256,
+}
+
+impl HashScheme {
+    pub fn digest(self, message: &[u8]) -> [u8; 32] {
+        match self {
+            HashScheme::Sha256 => Sha256::digest(message).into(),
+            HashScheme::Keccak256 => Keccak256::digest(message).into(),
+        }
+    }
+}
+
+/// Structured data whose digest is signed. Nodes decode it, check that it hashes to the digest
+/// they were asked to sign and record the decoded fields in the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Preimage {
+    /// Hex-encoded unsigned Ethereum transaction
+    EthTransaction { transaction: String },
+    /// Hex-encoded message signed with the EIP-191 prefix of `personal_sign`
+    PersonalMessage { message: String },
+    /// EIP-712 typed data document as signed by `eth_signTypedData_v4`
+    TypedData { typed_data: Value },
+}
+
+impl Preimage {
+    /// Computes the digest that signing the preimage means signing
+    pub fn digest(&self) -> Result<[u8; 32], Error> {
+        match self {
+            Preimage::EthTransaction { transaction } => {
+                Ok(Transaction::decode_unsigned(&decode_preimage_hex(transaction)?)?.signing_hash())
+            }
+            Preimage::PersonalMessage { message } => Ok(eth::personal_message_hash(&decode_preimage_hex(message)?)),
+            Preimage::TypedData { typed_data } => eip712::typed_data_hash(typed_data),
+        }
+    }
+
+    /// Describes the decoded fields of the preimage
+    pub fn decode(&self) -> Result<Value, Error> {
+        match self {
+            Preimage::EthTransaction { transaction } => {
+                Ok(Transaction::decode_unsigned(&decode_preimage_hex(transaction)?)?.to_json())
+            }
+            // Messages are usually text, which is easier to review than its hex
+            Preimage::PersonalMessage { message } => {
+                let bytes = decode_preimage_hex(message)?;
+                Ok(match String::from_utf8(bytes) {
+                    Ok(text) => json!({ "message": text }),
+                    Err(_) => json!({ "message_hex": message }),
+                })
+            }
+            Preimage::TypedData { typed_data } => Ok(typed_data.clone()),
+        }
+    }
+}
+
+fn decode_preimage_hex(value: &str) -> Result<Vec<u8>, Error> {
+    eth::decode_hex(value).map_err(|e| Error::InvalidRequest(format!("preimage is not valid hex: {}", e)))
+}
+
+/// Checks an ECDSA signature over a 32-byte `digest`, which is signed as a big-endian integer in
+/// the same way as the nodes do
+pub fn verify_signature(public_key: &Point<Secp256k1>, digest: &[u8], signature: &SignatureRecid) -> bool {
+    if signature.r.is_zero() || signature.s.is_zero() {
+        return false;
+    }
+    let s_inv = match signature.s.invert() {
+        Some(s_inv) => s_inv,
+        None => return false,
+    };
+
+    let m = Scalar::<Secp256k1>::from_bigint(&BigInt::from_bytes(digest));
+    let u1 = &m * &s_inv;
+    let u2 = &signature.r * &s_inv;
+    let point = Point::generator() * &u1 + public_key * &u2;
+
+    point
+        .x_coord()
+        .map_or(false, |x| Scalar::<Secp256k1>::from_bigint(&x) == signature.r)
+}
+
+/// Checks that a key with `threshold` out of `parties` can be generated on a cluster of `nodes` nodes
+pub fn validate_keygen(parties: u16, threshold: u16, nodes: usize) -> Result<(), Error> {
+    if parties < 2 {
+        return Err(Error::InvalidRequest("a key needs at least 2 parties".to_owned()));
+    }
+    if threshold == 0 || threshold >= parties {
+        return Err(Error::InvalidRequest(format!("threshold must be between 1 and {}", parties - 1)));
+    }
+    if parties as usize != nodes {
+        return Err(Error::InvalidRequest(format!(
+            "{} parties requested but {} nodes are configured",
+            parties, nodes
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that exactly `threshold + 1` distinct parties out of `1..=parties` take part in signing
+pub fn validate_active_parties(active_parties: &[u16], threshold: u16, parties: u16) -> Result<(), Error> {
+    if active_parties.len() != threshold as usize + 1 {
+        return Err(Error::InvalidRequest(format!(
+            "{} active parties given but a threshold of {} needs exactly {}",
+            active_parties.len(),
+            threshold,
+            threshold + 1
+        )));
+    }
+    for (i, party) in active_parties.iter().enumerate() {
+        if *party == 0 || *party > parties {
+            return Err(Error::InvalidRequest(format!(
+                "active party {} is not between 1 and {}",
+                party, parties
+            )));
+        }
+        if active_parties[..i].contains(party) {
+            return Err(Error::InvalidRequest(format!("active party {} is given twice", party)));
+        }
+    }
+    Ok(())
+}
+
+fn parse_field<T: DeserializeOwned>(node: usize, result: &Value, field: &str) -> Result<T, Error> {
+    serde_json::from_value(result[field].clone()).map_err(|e| Error::InvalidResponse { node, message: e.to_string() })
+}
+
+/// Classifies the response of a node. Every node replies with {"status": "ok", "result": ...} or
+/// {"status": "error", "error": ..., "kind": ...}.
+fn node_status(response: Result<Vec<u8>, Status>) -> NodeStatus {
+    let response = match response {
+        Ok(response) => response,
+        Err(status) if status.code() == Code::DeadlineExceeded => return NodeStatus::TimedOut,
+        Err(status) => return NodeStatus::Unreachable(status.message().to_owned()),
+    };
+    let reply: Value = match serde_json::from_slice(&response) {
+        Ok(reply) => reply,
+        Err(e) => return NodeStatus::InvalidResponse(e.to_string()),
+    };
+    if reply["status"] != "ok" {
+        return NodeStatus::Failed {
+            kind: serde_json::from_value(reply["kind"].clone()).unwrap_or_default(),
+            message: reply["error"].as_str().unwrap_or("unknown error").to_owned(),
+        };
+    }
+    NodeStatus::Succeeded(reply["result"].clone())
+}
+
+fn parse_public_key(node: usize, result: &Value) -> Result<Point<Secp256k1>, Error> {
+    let invalid = |message: String| Error::InvalidResponse { node, message };
+
+    let public_key = result["public_key"]
+        .as_str()
+        .ok_or_else(|| invalid("missing public key".to_owned()))?;
+    let bytes = hex::decode(public_key).map_err(|e| invalid(e.to_string()))?;
+    Point::from_bytes(&bytes).map_err(|e| invalid(e.to_string()))
+}
+
+/// Connections to every node of a signing cluster
+pub struct SigningClient {
+    config: ClientConfig,
+    clients: Vec<DecExecClient<Channel>>,
+    /// Id sent with every request instead of a fresh one, so that a retry is recognized
+    request_id: Option<Uuid>,
+}
+
+impl SigningClient {
+    /// Sets up connections to every node listed in `config`. Nodes are connected to on first use.
+    pub async fn connect(config: ClientConfig) -> Result<Self, Error> {
+        let clients = config.channels()?;
+        Ok(SigningClient { config, clients, request_id: None })
+    }
+
+    /// Sends every request under `request_id`. Nodes answer a request whose id and parameters
+    /// match one they already completed with its stored result, so a call that failed in transit
+    /// can be retried with the same id without running twice. Calls that only read are not
+    /// tracked by the nodes, so the id may be shared by all calls of one operation.
+    pub fn with_request_id(mut self, request_id: Uuid) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    fn request_id(&self) -> Uuid {
+        self.request_id.unwrap_or_else(Uuid::new_v4)
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Sends the same request to every node and returns the outcome on each of them. A failing
+    /// node does not fail the call; callers decide which nodes must succeed.
+    pub async fn exec_all(&self, func_name: &str, params: &Params) -> Result<NodeResults, Error> {
+        self.exec(func_name, params, self.request_id(), false).await
+    }
+
+    /// Sends a request with the given id to every node. A detached ceremony is acknowledged by the
+    /// nodes as soon as they start it, and runs on in the background.
+    async fn exec(
+        &self,
+        func_name: &str,
+        params: &Params,
+        request_id: Uuid,
+        detach: bool,
+    ) -> Result<NodeResults, Error> {
+        let mut params = serde_json::to_value(params).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        params["client_id"] = Value::String(self.config.client_id());
+        params["request_id"] = Value::String(request_id.to_string());
+        if detach {
+            params["detach"] = Value::Bool(true);
+        }
+        let params_json = serde_json::to_vec(&params).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        let deadline = self.config.deadline(func_name);
+
+        let responses = future::join_all(
+                self.clients
+                    .iter()
+                    .map(|client| {
+                        let mut client = client.clone();
+                        let mut request = Request::new(App {
+                            app_name: APP_NAME.to_owned(),
+                            app_uid: 0,
+                            request_id: Some(uuid_to_uuidpb(request_id)),
+                            client_id: "".to_owned(),
+                            func_name: func_name.to_owned(),
+                            in_files: vec![],
+                            out_files: vec![],
+                            args: vec![params_json.clone()],
+                        });
+                        if let Some(deadline) = deadline {
+                            request.set_timeout(deadline);
+                        }
+                        async move { client.exec(request).await }
+                    })
+            )
+            .await;
+
+        let results = responses
+            .into_iter()
+            .zip(&self.config.nodes)
+            .map(|(response, node)| NodeResult {
+                node: node.clone(),
+                status: node_status(response.map(|response| response.into_inner().result)),
+            })
+            .collect();
+        Ok(NodeResults(results))
+    }
+
+    /// Sends a request that changes the user store, which has to take effect on every node
+    async fn exec_everywhere(&self, func_name: &str, params: &Params) -> Result<NodeResults, Error> {
+        let results = self.exec_all(func_name, params).await?;
+        results.require_all()?;
+        Ok(results)
+    }
+
+    /// Registers a new user, enrolling the base32 `totp_secret` as its second factor if given
+    pub async fn register(
+        &self,
+        creds: &Credentials,
+        new_username: &str,
+        new_password: &str,
+        role: Option<&str>,
+        totp_secret: Option<&str>,
+    ) -> Result<Reply<()>, Error> {
+        let params = Params::Register {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            new_username: new_username.to_owned(),
+            new_password: new_password.to_owned(),
+            role: role.map(str::to_owned),
+            totp_secret: totp_secret.map(str::to_owned),
+        };
+        let nodes = self.exec_everywhere("register", &params).await?;
+        Ok(Reply { value: (), nodes })
+    }
+
+    pub async fn set_role(&self, creds: &Credentials, target: &str, role: &str) -> Result<Reply<()>, Error> {
+        let params = Params::SetRole {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            target: target.to_owned(),
+            role: role.to_owned(),
+        };
+        let nodes = self.exec_everywhere("set_role", &params).await?;
+        Ok(Reply { value: (), nodes })
+    }
+
+    /// Lifts the login lockout of `target`
+    pub async fn unlock_user(&self, creds: &Credentials, target: &str) -> Result<Reply<()>, Error> {
+        let params = Params::Unlock {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            target: target.to_owned(),
+        };
+        let nodes = self.exec_everywhere("unlock_user", &params).await?;
+        Ok(Reply { value: (), nodes })
+    }
+
+    pub async fn change_password(&self, creds: &Credentials, new_password: &str) -> Result<Reply<()>, Error> {
+        let params = Params::ChangePassword {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            new_password: new_password.to_owned(),
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_everywhere("change_password", &params).await?;
+        Ok(Reply { value: (), nodes })
+    }
+
+    /// Deletes `target`, handing its keys over to `transfer_to`. Returns the keys that changed hands.
+    pub async fn delete_user(
+        &self,
+        creds: &Credentials,
+        target: &str,
+        transfer_to: Option<&str>,
+    ) -> Result<Reply<Vec<String>>, Error> {
+        let params = Params::DeleteUser {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            target: target.to_owned(),
+            transfer_to: transfer_to.map(str::to_owned),
+        };
+        let nodes = self.exec_everywhere("delete_user", &params).await?;
+
+        let value = nodes.agreed("list of transferred keys", |node, result| {
+            let mut keys: Vec<String> = parse_field(node, result, "transferred_keys")?;
+            keys.sort();
+            Ok(Some(keys))
+        })?;
+        Ok(Reply { value, nodes })
+    }
+
+    /// Checks the credentials, including the one-time code if the user enrolled a second factor,
+    /// and returns the role of the user, which every responding node must agree on
+    pub async fn login(&self, creds: &Credentials) -> Result<Reply<UserInfo>, Error> {
+        let params = Params::Login {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("login", &params).await?;
+        nodes.require_any()?;
+
+        let user = nodes.agreed("user", |node, result| parse_field(node, result, "user").map(Some))?;
+        Ok(Reply { value: user, nodes })
+    }
+
+    /// Lists the registered users, which every responding node must agree on
+    pub async fn list_users(&self, creds: &Credentials) -> Result<Reply<Vec<UserInfo>>, Error> {
+        let params = Params::User {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+        };
+        let nodes = self.exec_all("list_users", &params).await?;
+        nodes.require_any()?;
+
+        let users = nodes.agreed("list of users", |node, result| {
+            let mut users: Vec<UserInfo> = parse_field(node, result, "users")?;
+            users.sort_by(|a, b| a.username.cmp(&b.username));
+            Ok(Some(users))
+        })?;
+        Ok(Reply { value: users, nodes })
+    }
+
+    /// Generates a key shared by all nodes and returns its joint public key. Nodes refuse to
+    /// overwrite a key already stored in `key_file` unless `replace` is set and the user owns the
+    /// key or may manage keys, in which case they archive the previous share.
+    pub async fn keygen(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        num_parties: u16,
+        num_threshold: u16,
+        replace: bool,
+    ) -> Result<Reply<Point<Secp256k1>>, Error> {
+        let params = self.keygen_params(creds, key_file, num_parties, num_threshold, replace)?;
+        let nodes = self.exec_all("keygen", &params).await?;
+        nodes.require_all()?;
+
+        let public_key = nodes.agreed("public key", |node, result| parse_public_key(node, result).map(Some))?;
+        Ok(Reply { value: public_key, nodes })
+    }
+
+    fn keygen_params(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        num_parties: u16,
+        num_threshold: u16,
+        replace: bool,
+    ) -> Result<Params, Error> {
+        validate_keygen(num_parties, num_threshold, self.num_nodes())?;
+        Ok(Params::K {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            num_parties,
+            num_threshold,
+            totp: creds.totp.clone(),
+            replace,
+        })
+    }
+
+    /// Starts generating a key as a job and returns its id once every node has started, without
+    /// waiting for the key. [`job_status`](Self::job_status) reports its progress and public key.
+    pub async fn submit_keygen(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        num_parties: u16,
+        num_threshold: u16,
+        replace: bool,
+    ) -> Result<Reply<Uuid>, Error> {
+        let params = self.keygen_params(creds, key_file, num_parties, num_threshold, replace)?;
+        self.submit("keygen", &params).await
+    }
+
+    /// Signs the 32-byte `digest` of a message with the key in `key_file`, or with its BIP32 child
+    /// at `derivation_path`, using the given `threshold + 1` active parties. Nodes that do not take
+    /// part may fail without failing the request.
+    pub async fn sign(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        digest: &[u8],
+    ) -> Result<Reply<SignatureRecid>, Error> {
+        self.sign_preimage(creds, key_file, derivation_path, num_threshold, active_parties, digest, None)
+            .await
+    }
+
+    /// Signs an Ethereum transaction with the key in `key_file` or its child at `derivation_path`
+    /// and returns the signed transaction
+    pub async fn sign_eth_transaction(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        transaction: &Transaction,
+    ) -> Result<Reply<SignedTransaction>, Error> {
+        let preimage = Preimage::EthTransaction { transaction: hex::encode(transaction.encode_unsigned()) };
+        let reply = self
+            .sign_preimage(
+                creds,
+                key_file,
+                derivation_path,
+                num_threshold,
+                active_parties,
+                &transaction.signing_hash(),
+                Some(preimage),
+            )
+            .await?;
+        let signed = transaction.encode_signed(&reply.value)?;
+        Ok(Reply { value: signed, nodes: reply.nodes })
+    }
+
+    /// Signs `message` as `personal_sign` does and returns the 65-byte signature, which recovers
+    /// to the Ethereum address of the key
+    pub async fn sign_personal_message(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        message: &[u8],
+    ) -> Result<Reply<[u8; 65]>, Error> {
+        let preimage = Preimage::PersonalMessage { message: hex::encode(message) };
+        self.sign_eth_preimage(creds, key_file, derivation_path, num_threshold, active_parties, preimage)
+            .await
+    }
+
+    /// Signs an EIP-712 typed data document as `eth_signTypedData_v4` does and returns the 65-byte
+    /// signature
+    pub async fn sign_typed_data(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        typed_data: &Value,
+    ) -> Result<Reply<[u8; 65]>, Error> {
+        let preimage = Preimage::TypedData { typed_data: typed_data.clone() };
+        self.sign_eth_preimage(creds, key_file, derivation_path, num_threshold, active_parties, preimage)
+            .await
+    }
+
+    async fn sign_eth_preimage(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        preimage: Preimage,
+    ) -> Result<Reply<[u8; 65]>, Error> {
+        let digest = preimage.digest()?;
+        let reply = self
+            .sign_preimage(creds, key_file, derivation_path, num_threshold, active_parties, &digest, Some(preimage))
+            .await?;
+        Ok(Reply { value: eth::signature_bytes(&reply.value)?, nodes: reply.nodes })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn sign_preimage(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        digest: &[u8],
+        preimage: Option<Preimage>,
+    ) -> Result<Reply<SignatureRecid>, Error> {
+        let params =
+            self.sign_params(creds, key_file, derivation_path, num_threshold, active_parties, digest, preimage)?;
+        let nodes = self.exec_all("signing", &params).await?;
+        nodes.require_parties(active_parties)?;
+
+        // Only the active parties produce a signature, and all of them produce the same one
+        let signature = nodes.agreed("signature", |node, result| {
+            if !active_parties.contains(&(node as u16 + 1)) {
+                return Ok(None);
+            }
+            serde_json::from_value::<SignatureRecid>(result.clone())
+                .map(Some)
+                .map_err(|e| Error::InvalidResponse { node, message: e.to_string() })
+        })?;
+        Ok(Reply { value: signature, nodes })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sign_params(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        digest: &[u8],
+        preimage: Option<Preimage>,
+    ) -> Result<Params, Error> {
+        validate_active_parties(active_parties, num_threshold, self.num_nodes() as u16)?;
+        if digest.len() != 32 {
+            return Err(Error::InvalidRequest(format!("digest is {} bytes long instead of 32", digest.len())));
+        }
+
+        Ok(Params::S {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            num_threshold,
+            active_parties: active_parties.to_owned(),
+            digest: hex::encode(digest),
+            derivation_path: derivation_path.map(DerivationPath::to_string),
+            preimage,
+            totp: creds.totp.clone(),
+        })
+    }
+
+    /// Starts signing `digest` as a job and returns its id once every node has started, without
+    /// waiting for the signature. [`job_status`](Self::job_status) reports its progress and the
+    /// signature.
+    pub async fn submit_sign(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        derivation_path: Option<&DerivationPath>,
+        num_threshold: u16,
+        active_parties: &[u16],
+        digest: &[u8],
+    ) -> Result<Reply<Uuid>, Error> {
+        let params = self.sign_params(creds, key_file, derivation_path, num_threshold, active_parties, digest, None)?;
+        self.submit("signing", &params).await
+    }
+
+    async fn submit(&self, func_name: &str, params: &Params) -> Result<Reply<Uuid>, Error> {
+        let job = self.request_id();
+        let nodes = self.exec(func_name, params, job, true).await?;
+        nodes.require_all()?;
+        Ok(Reply { value: job, nodes })
+    }
+
+    /// Fetches the progress of a job from every node, `None` where the node does not know the job
+    /// or did not answer
+    pub async fn job_status(&self, creds: &Credentials, job: Uuid) -> Result<Reply<Vec<Option<JobStatus>>>, Error> {
+        let params = Params::Job {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            job: job.to_string(),
+        };
+        let nodes = self.exec_all("job_status", &params).await?;
+        nodes.require_any()?;
+
+        let mut statuses = vec![];
+        for node in 0..nodes.0.len() {
+            statuses.push(match nodes.value(node) {
+                Some(result) => Some(parse_field(node, result, "job")?),
+                None => None,
+            });
+        }
+        Ok(Reply { value: statuses, nodes })
+    }
+
+    /// Polls the progress of a job every `interval` until no node is running it any more
+    pub async fn wait_job(
+        &self,
+        creds: &Credentials,
+        job: Uuid,
+        interval: Duration,
+    ) -> Result<Reply<Vec<Option<JobStatus>>>, Error> {
+        loop {
+            let reply = self.job_status(creds, job).await?;
+            let running = reply.value.iter().flatten().any(|status| status.state == JobState::Running);
+            if !running {
+                return Ok(reply);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Signs every input of `psbt` that spends a P2WPKH or P2PKH output of the key in `key_file` or
+    /// of one of its children, and returns the PSBT with the signatures added. All inputs are signed
+    /// in one request, one ceremony after the other. Inputs are only signed with `SIGHASH_ALL`
+    /// unless `any_sighash` is set.
+    pub async fn sign_psbt(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        num_threshold: u16,
+        active_parties: &[u16],
+        psbt: &Psbt,
+        any_sighash: bool,
+    ) -> Result<Reply<Psbt>, Error> {
+        validate_active_parties(active_parties, num_threshold, self.num_nodes() as u16)?;
+
+        // The nodes find the owned inputs the same way; doing it here too lets the signatures be
+        // checked before they are added
+        let public_key = self.pubkey(creds, key_file).await?.value;
+        let owned = psbt::owned_inputs(psbt, &public_key, any_sighash)?;
+        if owned.is_empty() {
+            return Err(Error::InvalidRequest("no input of the PSBT is owned by the key".to_owned()));
+        }
+
+        let params = Params::Psbt {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            num_threshold,
+            active_parties: active_parties.to_owned(),
+            psbt: psbt::encode(psbt),
+            any_sighash,
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("sign_psbt", &params).await?;
+        nodes.require_parties(active_parties)?;
+
+        let signatures = nodes.agreed("signatures", |node, result| {
+            if !active_parties.contains(&(node as u16 + 1)) {
+                return Ok(None);
+            }
+            let signatures: Vec<(usize, SignatureRecid)> = parse_field(node, result, "signatures")?;
+            Ok(Some(signatures))
+        })?;
+
+        let mut signed = psbt.clone();
+        for input in &owned {
+            let signature = signatures
+                .iter()
+                .find(|(index, _)| *index == input.index)
+                .map(|(_, signature)| signature)
+                .ok_or_else(|| Error::Inconsistent {
+                    message: format!("nodes did not sign input {}", input.index),
+                    nodes: nodes.clone(),
+                })?;
+            if !verify_signature(&input.public_key, &input.digest, signature) {
+                return Err(Error::Inconsistent {
+                    message: format!("signature of input {} is not valid", input.index),
+                    nodes: nodes.clone(),
+                });
+            }
+            psbt::insert_signature(&mut signed, input, signature)?;
+        }
+        Ok(Reply { value: signed, nodes })
+    }
+
+    /// Lists the Ethereum accounts of the keys visible to the user, one for each key and path in
+    /// `paths`. Deleted keys and keys whose nodes disagree are left out.
+    pub async fn eth_accounts(
+        &self,
+        creds: &Credentials,
+        paths: &[DerivationPath],
+    ) -> Result<Reply<Vec<eth::Account>>, Error> {
+        let reply = self.list_keys(creds).await?;
+
+        let mut accounts = vec![];
+        for key in &reply.value {
+            let info = match key.consensus() {
+                Some(info) if key.disagreeing().is_empty() && info.deleted_at.is_none() => info,
+                _ => continue,
+            };
+            let public_key = hex::decode(&info.public_key)
+                .ok()
+                .and_then(|bytes| Point::<Secp256k1>::from_bytes(&bytes).ok())
+                .ok_or_else(|| Error::Inconsistent {
+                    message: format!("nodes agree on an invalid public key for {}", key.key_file),
+                    nodes: reply.nodes.clone(),
+                })?;
+            let master = ExtendedPublicKey::master(&public_key);
+            for path in paths {
+                let (child, _) = master.derive(path)?;
+                accounts.push(eth::Account {
+                    address: eth::address(&child.public_key),
+                    key_file: key.key_file.clone(),
+                    path: path.clone(),
+                    threshold: info.threshold,
+                    num_parties: info.num_parties,
+                });
+            }
+        }
+        Ok(Reply { value: accounts, nodes: reply.nodes })
+    }
+
+    /// Lists the metadata of every key visible to the user, as recorded by each node. Keys owned
+    /// by other users are only visible to administrators and auditors.
+    pub async fn list_keys(&self, creds: &Credentials) -> Result<Reply<Vec<ClusterKey>>, Error> {
+        let params = Params::User {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+        };
+        let nodes = self.exec_all("list_keys", &params).await?;
+        nodes.require_any()?;
+
+        let mut per_node = vec![];
+        for node in 0..nodes.0.len() {
+            per_node.push(match nodes.value(node) {
+                Some(result) => parse_field(node, result, "keys")?,
+                None => vec![],
+            });
+        }
+        Ok(Reply { value: keys::cluster_keys(&per_node), nodes })
+    }
+
+    /// Fetches the metadata of a key from every node. Nodes without a record of the key are
+    /// reported as failed in the reply.
+    pub async fn key_info(&self, creds: &Credentials, key_file: &str) -> Result<Reply<ClusterKey>, Error> {
+        let params = Params::Key {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+        };
+        let nodes = self.exec_all("key_info", &params).await?;
+        nodes.require_any()?;
+
+        let mut infos = vec![];
+        for node in 0..nodes.0.len() {
+            infos.push(match nodes.value(node) {
+                Some(result) => Some(
+                    serde_json::from_value(result.clone())
+                        .map_err(|e| Error::InvalidResponse { node, message: e.to_string() })?,
+                ),
+                None => None,
+            });
+        }
+        let key = ClusterKey { key_file: key_file.to_owned(), nodes: infos };
+        Ok(Reply { value: key, nodes })
+    }
+
+    /// Deletes a key on every node. Each node overwrites its share before removing it and keeps a
+    /// tombstone so that the key file cannot be reused. Returns for each node whether it still held
+    /// a share to remove.
+    pub async fn delete_key(&self, creds: &Credentials, key_file: &str) -> Result<Reply<Vec<bool>>, Error> {
+        let params = Params::DeleteKey {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("delete_key", &params).await?;
+        nodes.require_all()?;
+
+        let removed = nodes
+            .succeeded()
+            .map(|(node, result)| parse_field(node, result, "share_removed"))
+            .collect::<Result<_, _>>()?;
+        Ok(Reply { value: removed, nodes })
+    }
+
+    fn validate_party(&self, party: u16) -> Result<(), Error> {
+        if party == 0 || party as usize > self.num_nodes() {
+            return Err(Error::InvalidRequest(format!("party must be between 1 and {}", self.num_nodes())));
+        }
+        Ok(())
+    }
+
+    /// Exports the share of `party` encrypted to `recipient`, returning the backup document
+    pub async fn export_backup(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        party: u16,
+        recipient: &BackupRecipient,
+    ) -> Result<Reply<Value>, Error> {
+        self.validate_party(party)?;
+
+        let (passphrase, public_key) = match recipient {
+            BackupRecipient::Passphrase(passphrase) => (Some(passphrase.clone()), None),
+            BackupRecipient::PublicKey(public_key) => (None, Some(public_key.clone())),
+        };
+        let params = Params::ExportBackup {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            party,
+            passphrase,
+            recipient: public_key,
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("export_backup", &params).await?;
+        nodes.require_parties(&[party])?;
+
+        let node = party as usize - 1;
+        let backup = nodes.value(node).map(|result| result["backup"].clone()).unwrap_or_default();
+        if !backup.is_object() {
+            return Err(Error::InvalidResponse { node, message: "missing backup".to_owned() });
+        }
+        Ok(Reply { value: backup, nodes })
+    }
+
+    /// Restores the share of `party` from a backup and confirms that the cluster agrees on the
+    /// joint public key of the restored share, which is returned
+    pub async fn import_backup(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        party: u16,
+        backup: &Value,
+        secret: &BackupSecret,
+    ) -> Result<Reply<Point<Secp256k1>>, Error> {
+        self.validate_party(party)?;
+
+        let (passphrase, secret_key) = match secret {
+            BackupSecret::Passphrase(passphrase) => (Some(passphrase.clone()), None),
+            BackupSecret::SecretKey(secret_key) => (None, Some(secret_key.clone())),
+        };
+        let params = Params::ImportBackup {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            party,
+            backup: backup.clone(),
+            passphrase,
+            secret_key,
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("import_backup", &params).await?;
+        nodes.require_parties(&[party])?;
+
+        let node = party as usize - 1;
+        let restored = match nodes.value(node) {
+            Some(result) => parse_public_key(node, result)?,
+            None => return Err(Error::InvalidResponse { node, message: "missing public key".to_owned() }),
+        };
+
+        // The restored node and every other node that answers must report the same key
+        let cluster = self.pubkey(creds, key_file).await?;
+        cluster.nodes.require_parties(&[party])?;
+        if cluster.value != restored {
+            return Err(Error::Inconsistent {
+                message: format!("restored share of party {} does not match the joint public key", party),
+                nodes: cluster.nodes,
+            });
+        }
+        Ok(Reply { value: restored, nodes })
+    }
+
+    /// Fetches the extended public key of the key in `key_file`, or of its child at `path`, whose
+    /// chain code is derived from the joint public key
+    pub async fn xpub(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        path: &DerivationPath,
+    ) -> Result<Reply<ExtendedPublicKey>, Error> {
+        let reply = self.pubkey(creds, key_file).await?;
+        let (xpub, _) = ExtendedPublicKey::master(&reply.value).derive(path)?;
+        Ok(Reply { value: xpub, nodes: reply.nodes })
+    }
+
+    /// Rebuilds the share of `lost_party` on its freshly provisioned node from the shares of the
+    /// `helpers`, which must be t + 1 other parties. Every node takes part, since all of them
+    /// accept the new Paillier key of the lost party. Returns the joint public key, which every
+    /// node must agree on.
+    pub async fn recover_share(
+        &self,
+        creds: &Credentials,
+        key_file: &str,
+        lost_party: u16,
+        helpers: &[u16],
+    ) -> Result<Reply<Point<Secp256k1>>, Error> {
+        self.validate_party(lost_party)?;
+        for (i, helper) in helpers.iter().enumerate() {
+            self.validate_party(*helper)?;
+            if *helper == lost_party || helpers[..i].contains(helper) {
+                return Err(Error::InvalidRequest(format!("invalid helper {}", helper)));
+            }
+        }
+
+        let params = Params::RecoverShare {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+            lost_party,
+            helpers: helpers.to_owned(),
+            num_parties: self.num_nodes() as u16,
+            totp: creds.totp.clone(),
+        };
+        let nodes = self.exec_all("recover_share", &params).await?;
+        nodes.require_all()?;
+
+        let public_key = nodes.agreed("public key", |node, result| parse_public_key(node, result).map(Some))?;
+        Ok(Reply { value: public_key, nodes })
+    }
+
+    /// Fetches the joint public key of a key from every node and checks that the responding nodes agree
+    pub async fn pubkey(&self, creds: &Credentials, key_file: &str) -> Result<Reply<Point<Secp256k1>>, Error> {
+        let params = Params::Key {
+            username: creds.username.clone(),
+            password: creds.password.clone(),
+            key_file: key_file.to_owned(),
+        };
+        let nodes = self.exec_all("pubkey", &params).await?;
+        nodes.require_any()?;
+
+        let public_key = nodes.agreed("public key", |node, result| parse_public_key(node, result).map(Some))?;
+        Ok(Reply { value: public_key, nodes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_uri_escapes_label_and_issuer() {
+        assert_eq!(
+            totp_uri("alice", "JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/DOTS%20signing:alice?secret=JBSWY3DPEHPK3PXP&issuer=DOTS%20signing",
+        );
+        assert_eq!(
+            totp_uri("eve:x?secret=A&issuer=B#", "JBSWY3DPEHPK3PXP"),
+            concat!(
+                "otpauth://totp/DOTS%20signing:eve%3Ax%3Fsecret%3DA%26issuer%3DB%23",
+                "?secret=JBSWY3DPEHPK3PXP&issuer=DOTS%20signing",
+            ),
+        );
+    }
+}
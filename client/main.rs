@@ -1,274 +1,809 @@
-use std::env;
 use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::process::ExitCode;
+use std::time::Duration;
 
-use dotspb::dec_exec::dec_exec_client::DecExecClient;
-use dotspb::dec_exec::App;
-use futures::future;
-use serde::{Deserialize, Serialize};
-use tonic::transport::Channel;
-use tonic::Request;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use curv::elliptic::curves::{Point, Secp256k1};
+use signing::{
+    generate_backup_keypair, generate_totp_secret, totp_uri, validate_active_parties, verify_signature,
+    BackupRecipient, BackupSecret, ClientConfig, Credentials, HashScheme, JobState, JobStatus, NodeResults, Reply,
+    SignatureRecid, SigningClient,
+};
+use signing::eth::{self, Transaction};
+use signing::hd::DerivationPath;
 use uuid::Uuid;
 
-const APP_NAME: &str = "signing";
+#[derive(Clone, Copy, ValueEnum)]
+enum RoleArg {
+    Admin,
+    Operator,
+    Signer,
+    Auditor,
+}
+
+impl RoleArg {
+    fn as_str(self) -> &'static str {
+        match self {
+            RoleArg::Admin => "admin",
+            RoleArg::Operator => "operator",
+            RoleArg::Signer => "signer",
+            RoleArg::Auditor => "auditor",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HashArg {
+    Sha256,
+    Keccak256,
+}
+
+impl HashArg {
+    fn scheme(self) -> HashScheme {
+        match self {
+            HashArg::Sha256 => HashScheme::Sha256,
+            HashArg::Keccak256 => HashScheme::Keccak256,
+        }
+    }
+}
+
+/// Where the message to sign or verify comes from. Exactly one of the inputs must be given.
+#[derive(Args)]
+struct MessageArgs {
+    /// Message given on the command line
+    #[arg(long, conflicts_with_all = ["message_file", "stdin", "digest"])]
+    message: Option<String>,
+    /// Read the message from this file
+    #[arg(long, conflicts_with_all = ["stdin", "digest"])]
+    message_file: Option<String>,
+    /// Read the message from standard input
+    #[arg(long, conflicts_with = "digest")]
+    stdin: bool,
+    /// The message is hex encoded and is decoded before hashing
+    #[arg(long, conflicts_with = "digest")]
+    hex: bool,
+    /// Precomputed 32-byte digest in hex, which is signed as is
+    #[arg(long, required_unless_present_any = ["message", "message_file", "stdin"])]
+    digest: Option<String>,
+    /// Hash applied to the message
+    #[arg(long, value_enum, default_value = "sha256", conflicts_with = "digest")]
+    hash: HashArg,
+}
+
+impl MessageArgs {
+    /// Reads the message from the chosen input and returns its digest
+    fn digest(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if let Some(digest) = &self.digest {
+            let digest = hex::decode(digest.trim())?;
+            if digest.len() != 32 {
+                return Err(format!("--digest is {} bytes long instead of 32", digest.len()).into());
+            }
+            return Ok(digest);
+        }
+
+        let mut message = match (&self.message, &self.message_file) {
+            (Some(message), _) => message.clone().into_bytes(),
+            (None, Some(path)) => fs::read(path)?,
+            (None, None) => {
+                let mut message = vec![];
+                io::stdin().read_to_end(&mut message)?;
+                message
+            }
+        };
+        if self.hex {
+            message = hex::decode(String::from_utf8(message)?.trim())?;
+        }
+        Ok(self.hash.scheme().digest(&message).to_vec())
+    }
+}
 
-fn uuid_to_uuidpb(id: Uuid) -> dotspb::dec_exec::Uuid {
-    dotspb::dec_exec::Uuid {
-        hi: (id.as_u128() >> 64) as u64,
-        lo: id.as_u128() as u64,
+/// Key and parties that sign
+#[derive(Args)]
+struct SignerArgs {
+    /// File each node stores its key share in
+    #[arg(long)]
+    key_file: String,
+    /// Number of parties holding a share of the key, by default the number of nodes
+    #[arg(long)]
+    parties: Option<u16>,
+    /// Threshold t of the key
+    #[arg(long)]
+    threshold: u16,
+    /// Comma-separated indices of the t + 1 parties taking part, starting at 1
+    #[arg(long, value_delimiter = ',', required = true)]
+    active_parties: Vec<u16>,
+    /// Sign with the non-hardened BIP32 child at this path, such as m/0/7
+    #[arg(long)]
+    path: Option<DerivationPath>,
+}
+
+impl SignerArgs {
+    /// Checks the active parties against the number of parties, if given
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(parties) = self.parties {
+            validate_active_parties(&self.active_parties, self.threshold, parties)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum Params {
-    User {
-        username: String,
-        password: String,
+/// Reads a file, or standard input if `path` is -
+fn read_input(path: &str) -> Result<String, Box<dyn Error>> {
+    if path == "-" {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        Ok(input)
+    } else {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+/// Client of the DOTS threshold signing application
+#[derive(Parser)]
+#[command(name = "client", version)]
+struct Cli {
+    /// User to authenticate as
+    #[arg(short, long, env = "SIGNING_USERNAME", global = true)]
+    username: Option<String>,
+
+    /// Password of the user
+    #[arg(short, long, env = "SIGNING_PASSWORD", hide_env_values = true, global = true)]
+    password: Option<String>,
+
+    /// Current one-time code, required for key generation and signing if the user enrolled a TOTP second factor
+    #[arg(long, global = true)]
+    totp: Option<String>,
+
+    /// Id to send the request under, to retry a command that failed without running it twice
+    #[arg(long, global = true)]
+    request_id: Option<Uuid>,
+
+    /// Print the outcome on every node, even if all of them succeeded
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Register a new user (admins only)
+    Register {
+        /// Name of the new user
+        #[arg(long)]
+        new_username: String,
+        /// Password of the new user
+        #[arg(long)]
+        new_password: String,
+        /// Role of the new user
+        #[arg(long, value_enum, default_value = "signer")]
+        role: RoleArg,
+        /// Enroll a TOTP second factor for the new user and print its otpauth URI
+        #[arg(long)]
+        enroll_totp: bool,
+    },
+    /// Change the role of a user (admins only)
+    SetRole {
+        #[arg(long)]
+        target: String,
+        #[arg(long, value_enum)]
+        role: RoleArg,
+    },
+    /// Lift the login lockout of a user (admins only)
+    UnlockUser {
+        #[arg(long)]
+        target: String,
     },
-    K {
-        username: String,
-        password: String,
+    /// Check the credentials, including the one-time code, and print the role of the user
+    Login,
+    /// Change the password of the authenticated user
+    ChangePassword {
+        #[arg(long)]
+        new_password: String,
+    },
+    /// Delete a user, by default the authenticated user
+    DeleteUser {
+        /// User to delete, only admins may delete other users
+        #[arg(long)]
+        target: Option<String>,
+        /// User who takes over the keys of the deleted user
+        #[arg(long)]
+        transfer_to: Option<String>,
+    },
+    /// List all users and their roles (admins and auditors only)
+    ListUsers,
+    /// Generate a new threshold key shared by all nodes
+    Keygen {
+        /// Number of parties holding a share, which must match the number of nodes
+        #[arg(long)]
+        parties: u16,
+        /// Threshold t, so that t + 1 parties are needed to sign
+        #[arg(long)]
+        threshold: u16,
+        /// File each node stores its key share in
+        #[arg(long)]
         key_file: String,
-        num_parties: u16,
-        num_threshold: u16,
+        /// Archive the key already stored in the key file and replace it with the new key
+        #[arg(long)]
+        replace: bool,
+        /// Print the job id as soon as the nodes start, instead of waiting for the key
+        #[arg(long)]
+        detach: bool,
+    },
+    /// Sign a message with a threshold key
+    Sign {
+        #[command(flatten)]
+        signer: SignerArgs,
+        #[command(flatten)]
+        message: MessageArgs,
+        /// Also write the signature to this file
+        #[arg(long, conflicts_with = "detach")]
+        output: Option<String>,
+        /// Print the job id as soon as the nodes start, instead of waiting for the signature
+        #[arg(long)]
+        detach: bool,
+    },
+    /// Show the progress of a keygen or signing job on each node
+    Status {
+        /// Id of the job, as printed by --detach or the request id of a blocking request
+        job: Uuid,
+    },
+    /// Wait for a keygen or signing job to finish and print its result
+    Wait {
+        job: Uuid,
+        /// Seconds between polls of the nodes
+        #[arg(long, default_value = "2")]
+        interval: u64,
     },
-    S {
-        username: String,
-        password: String,
+    /// Sign an Ethereum transaction and print it signed, ready to broadcast
+    SignTransaction {
+        #[command(flatten)]
+        signer: SignerArgs,
+        /// File holding the unsigned transaction as eth_signTransaction JSON or as hex of its RLP
+        /// encoding, or - for standard input
+        #[arg(long)]
+        transaction: String,
+        /// Also write the hex of the signed transaction to this file
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Sign a message as Ethereum personal_sign does, following EIP-191
+    PersonalSign {
+        #[command(flatten)]
+        signer: SignerArgs,
+        /// Message given on the command line
+        #[arg(long, conflicts_with = "message_file", required_unless_present = "message_file")]
+        message: Option<String>,
+        /// Read the message from this file
+        #[arg(long)]
+        message_file: Option<String>,
+        /// The message is hex encoded, as dapps usually pass it
+        #[arg(long)]
+        hex: bool,
+    },
+    /// Sign an EIP-712 typed data document as eth_signTypedData_v4 does
+    SignTypedData {
+        #[command(flatten)]
+        signer: SignerArgs,
+        /// File holding the typed data JSON, or - for standard input
+        #[arg(long)]
+        typed_data: String,
+    },
+    /// Sign the inputs of a Bitcoin PSBT that spend P2WPKH or P2PKH outputs of a key or of its
+    /// BIP32 children
+    SignPsbt {
+        /// File each node stores its key share in
+        #[arg(long)]
         key_file: String,
-        num_threshold: u16,
+        /// Number of parties holding a share of the key, by default the number of nodes
+        #[arg(long)]
+        parties: Option<u16>,
+        /// Threshold t of the key
+        #[arg(long)]
+        threshold: u16,
+        /// Comma-separated indices of the t + 1 parties taking part, starting at 1
+        #[arg(long, value_delimiter = ',', required = true)]
         active_parties: Vec<u16>,
-        message: String,
+        /// File holding the base64 PSBT, or - for standard input
+        #[arg(long)]
+        psbt: String,
+        /// Also sign inputs that ask for another sighash type than SIGHASH_ALL
+        #[arg(long)]
+        any_sighash: bool,
+        /// Also write the signed PSBT to this file
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// List the keys visible to the user and flag nodes whose metadata disagree
+    ListKeys,
+    /// Show the metadata each node recorded for a key
+    KeyInfo {
+        /// File each node stores its key share in
+        #[arg(long)]
+        key_file: String,
+    },
+    /// Delete a key on every node, destroying the shares (owners and admins only)
+    DeleteKey {
+        /// File each node stores its key share in
+        #[arg(long)]
+        key_file: String,
+    },
+    /// Generate a key pair that share backups can be encrypted to
+    BackupKeypair,
+    /// Export the share of one party encrypted to a passphrase or public key (admins only)
+    ExportBackup {
+        /// File the node stores its key share in
+        #[arg(long)]
+        key_file: String,
+        /// Party whose share is exported, starting at 1
+        #[arg(long)]
+        party: u16,
+        /// Passphrase the backup is encrypted with
+        #[arg(long, env = "SIGNING_BACKUP_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+        /// Compressed public key in hex that the backup is encrypted to, as printed by backup-keypair
+        #[arg(long)]
+        recipient: Option<String>,
+        /// File the backup is written to
+        #[arg(long)]
+        output: String,
+    },
+    /// Restore the share of one party from a backup (admins only)
+    ImportBackup {
+        /// File the node stores the restored key share in
+        #[arg(long)]
+        key_file: String,
+        /// Party whose share is restored, starting at 1
+        #[arg(long)]
+        party: u16,
+        /// Backup file written by export-backup
+        #[arg(long)]
+        backup: String,
+        /// Passphrase the backup is encrypted with
+        #[arg(long, env = "SIGNING_BACKUP_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+        /// Secret key in hex that the backup was encrypted to
+        #[arg(long, env = "SIGNING_BACKUP_SECRET_KEY", hide_env_values = true)]
+        secret_key: Option<String>,
+    },
+    /// Rebuild the share of a party that lost its key file from t + 1 other parties (admins only)
+    RecoverShare {
+        /// File each node stores its key share in
+        #[arg(long)]
+        key_file: String,
+        /// Party whose share is rebuilt on its freshly provisioned node
+        #[arg(long)]
+        lost_party: u16,
+        /// Comma-separated indices of the t + 1 parties whose shares are used
+        #[arg(long, value_delimiter = ',', required = true)]
+        helpers: Vec<u16>,
+    },
+    /// Print the joint public key of a key
+    Pubkey {
+        /// File each node stores its key share in
+        #[arg(long)]
+        key_file: String,
+        /// Print the public key of the BIP32 child at this path instead
+        #[arg(long)]
+        path: Option<DerivationPath>,
+    },
+    /// Print the extended public key (xpub) of a key or of one of its BIP32 children. Its chain code
+    /// is derived from the public key, so anyone who knows the public key can link all its children.
+    Xpub {
+        /// File each node stores its key share in
+        #[arg(long)]
+        key_file: String,
+        /// Path of the child, by default the master key m
+        #[arg(long, default_value = "m")]
+        path: DerivationPath,
+    },
+    /// Verify a signature produced by the sign subcommand
+    Verify {
+        #[command(flatten)]
+        message: MessageArgs,
+        /// File holding the signature as printed by the sign subcommand
+        #[arg(long)]
+        signature: String,
+        /// Compressed public key in hex
+        #[arg(long, conflicts_with = "key_file", required_unless_present = "key_file")]
+        public_key: Option<String>,
+        /// Fetch the public key of this key from the nodes instead
+        #[arg(long)]
+        key_file: Option<String>,
+        /// Verify against the BIP32 child of the key at this path
+        #[arg(long, requires = "key_file")]
+        path: Option<DerivationPath>,
     },
 }
 
-async fn register(
-    clients: &mut [DecExecClient<Channel>],
-    username: &str,
-    password: &str,
-) -> Result<(), Box<dyn Error>> {
-    let request_id = Uuid::new_v4();
-    let params = Params::User {
-        username: username.to_owned(),
-        password: password.to_owned(),
-    };
-    let params_json = serde_json::to_vec(&params)?;
-
-    future::join_all(
-            clients
-                .iter_mut()
-                .map(|client| {
-                    client.exec(Request::new(App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "register".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![params_json.clone()],
-                    }))
-                })
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-
-    Ok(())
+impl Cli {
+    fn credentials(&self) -> Result<Credentials, Box<dyn Error>> {
+        Ok(Credentials {
+            username: self.username.clone().ok_or("--username is required")?,
+            password: self.password.clone().ok_or("--password is required")?,
+            totp: self.totp.clone(),
+        })
+    }
 }
 
-async fn keygen(
-    clients: &mut [DecExecClient<Channel>],
-    username: &str,
-    password: &str,
-    key_file: &str,
-    num_parties: u16,
-    num_threshold: u16,
-) -> Result<(), Box<dyn Error>> {
-    let params = Params::K {
-        username: username.to_owned(),
-        password: password.to_owned(),
-        key_file: key_file.to_owned(),
-        num_parties,
-        num_threshold,
-    };
-    let params_json = serde_json::to_vec(&params)?;
-
-    let request_id = Uuid::new_v4();
-    future::join_all(
-            clients
-                .iter_mut()
-                .map(|client|
-                    client.exec(Request::new(App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "keygen".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![params_json.clone()],
-                    }))
-                )
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
+/// Prints the outcome on each node to stderr if some node failed or `verbose` is set
+fn report<T>(reply: Reply<T>, verbose: bool) -> T {
+    if verbose || !reply.nodes.all_succeeded() {
+        eprintln!("Node results:\n{}", reply.nodes);
+    }
+    reply.value
+}
 
+fn check_signature(public_key: &Point<Secp256k1>, digest: &[u8], signature_file: &str) -> Result<(), Box<dyn Error>> {
+    let signature: SignatureRecid = serde_json::from_slice(&fs::read(signature_file)?)?;
+    if !verify_signature(public_key, digest, &signature) {
+        return Err("signature is not valid".into());
+    }
+    println!("Signature is valid");
     Ok(())
 }
 
-async fn sign(
-    clients: &mut [DecExecClient<Channel>],
-    username: &str,
-    password: &str,
-    key_file: &str,
-    num_threshold: u16, 
-    active_parties: &[u16],
-    message: &str,
-) -> Result<(), Box<dyn Error>> {
-    let params = Params::S {
-        username: username.to_owned(),
-        password: password.to_owned(),
-        key_file: key_file.to_owned(),
-        num_threshold,
-        active_parties: active_parties.to_owned(),
-        message: message.to_owned(),
-    };
-    let params_json = serde_json::to_vec(&params)?;
-
-    let request_id = Uuid::new_v4();
-    future::join_all(
-            clients
-                .iter_mut()
-                .map(|client|
-                    client.exec(Request::new(App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "signing".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![params_json.clone()],
-                    }))
+/// Prints the progress of a job on each node
+fn print_job(statuses: &[Option<JobStatus>], nodes: &NodeResults) {
+    for (node, (status, result)) in statuses.iter().zip(&nodes.0).enumerate() {
+        let status = match status {
+            Some(status) => status,
+            None => {
+                println!("Party {}: {}", node + 1, result.status);
+                continue;
+            }
+        };
+        let state = match (status.state, status.round) {
+            (JobState::Running, Some(round)) => format!("running round {}", round),
+            (JobState::Running, None) => "starting".to_owned(),
+            (JobState::Succeeded, _) => "succeeded".to_owned(),
+            (JobState::Failed, _) => format!("failed: {}", status.error.as_deref().unwrap_or_default()),
+        };
+        println!(
+            "Party {}: {} by {} with parties {:?}, {}",
+            node + 1,
+            status.function,
+            status.username,
+            status.parties,
+            state
+        );
+    }
+}
+
+async fn run(cli: Cli, request_id: Uuid) -> Result<(), Box<dyn Error>> {
+    // Neither generating a backup key pair nor verifying against a given public key needs the nodes
+    if let Command::BackupKeypair = &cli.command {
+        let (secret_key, public_key) = generate_backup_keypair();
+        println!("Secret key: {}", secret_key);
+        println!("Public key: {}", public_key);
+        return Ok(());
+    }
+    if let Command::Verify { message, signature, public_key: Some(public_key), .. } = &cli.command {
+        let public_key = Point::<Secp256k1>::from_bytes(&hex::decode(public_key)?)?;
+        return check_signature(&public_key, &message.digest()?, signature);
+    }
+
+    let creds = cli.credentials()?;
+    let client = SigningClient::connect(ClientConfig::load()?).await?.with_request_id(request_id);
+    let verbose = cli.verbose;
+
+    match &cli.command {
+        Command::Register { new_username, new_password, role, enroll_totp } => {
+            let totp_secret = if *enroll_totp { Some(generate_totp_secret()) } else { None };
+            let reply = client
+                .register(&creds, new_username, new_password, Some(role.as_str()), totp_secret.as_deref())
+                .await?;
+            report(reply, verbose);
+
+            println!("User {} registered as {}", new_username, role.as_str());
+            if let Some(secret) = totp_secret {
+                println!("Enroll the second factor in an authenticator app:");
+                println!("{}", totp_uri(new_username, &secret));
+            }
+        }
+        Command::SetRole { target, role } => {
+            report(client.set_role(&creds, target, role.as_str()).await?, verbose);
+            println!("User {} is now {}", target, role.as_str());
+        }
+        Command::UnlockUser { target } => {
+            report(client.unlock_user(&creds, target).await?, verbose);
+            println!("User {} unlocked", target);
+        }
+        Command::Login => {
+            let user = report(client.login(&creds).await?, verbose);
+            println!("Logged in as {} ({})", user.username, user.role);
+        }
+        Command::ChangePassword { new_password } => {
+            report(client.change_password(&creds, new_password).await?, verbose);
+            println!("Password of user {} changed", creds.username);
+        }
+        Command::DeleteUser { target, transfer_to } => {
+            let target = target.as_deref().unwrap_or(&creds.username);
+            let reply = client.delete_user(&creds, target, transfer_to.as_deref()).await?;
+            let transferred_keys = report(reply, verbose);
+
+            println!("User {} deleted", target);
+            for key in transferred_keys {
+                println!("Transferred key {} to {}", key, transfer_to.as_deref().unwrap_or_default());
+            }
+        }
+        Command::ListUsers => {
+            for user in report(client.list_users(&creds).await?, verbose) {
+                println!("{}\t{}", user.username, user.role);
+            }
+        }
+        Command::Keygen { parties, threshold, key_file, replace, detach: true } => {
+            let reply = client.submit_keygen(&creds, key_file, *parties, *threshold, *replace).await?;
+            let job = report(reply, verbose);
+            println!("Submitted job {}", job);
+        }
+        Command::Keygen { parties, threshold, key_file, replace, detach: false } => {
+            let reply = client.keygen(&creds, key_file, *parties, *threshold, *replace).await?;
+            let public_key = report(reply, verbose);
+            println!("Key generation complete");
+            println!("Public key: {}", hex::encode(public_key.to_bytes(true)));
+        }
+        Command::Sign { signer, message, detach: true, .. } => {
+            signer.validate()?;
+
+            let digest = message.digest()?;
+            eprintln!("Signing digest {}", hex::encode(&digest));
+
+            let reply = client
+                .submit_sign(
+                    &creds,
+                    &signer.key_file,
+                    signer.path.as_ref(),
+                    signer.threshold,
+                    &signer.active_parties,
+                    &digest,
                 )
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
+                .await?;
+            println!("Submitted job {}", report(reply, verbose));
+        }
+        Command::Sign { signer, message, output, detach: false } => {
+            signer.validate()?;
 
-    Ok(())
-}
+            let digest = message.digest()?;
+            eprintln!("Signing digest {}", hex::encode(&digest));
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let cmd = &args[1];
-
-    let node_addrs = ["http://localhost:50050", "http://localhost:50051", "http://localhost:50052"];
-
-    let mut clients = future::join_all(
-            node_addrs
-                .iter()
-                .map(|addr| DecExecClient::connect(addr.clone()))
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-
-    let username: String = match args[2].parse() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("error: key_file not a string");
-            panic!("");
-        }
-    };
-
-    let password: String = match args[3].parse() {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("error: key_file not a string");
-            panic!("");
-        }
-    };
-
-    match &cmd[..] {
-        "register" => {
-            register(&mut clients, &username, &password).await?;
-        }
-        "keygen" => {
-            let num_parties: u16 = match args[4].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: num_parties not a string");
-                    panic!("");
-                }
-            };
-            let num_threshold: u16 = match args[5].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: num_threshold not a string");
-                    panic!("");
-                }
-            };
-            let key_file: String = match args[6].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: key_file not a string");
-                    panic!("");
-                }
+            let reply = client
+                .sign(&creds, &signer.key_file, signer.path.as_ref(), signer.threshold, &signer.active_parties, &digest)
+                .await?;
+            let signature = report(reply, verbose);
+            let signature = serde_json::to_string_pretty(&signature)?;
+            if let Some(output) = output {
+                fs::write(output, &signature)?;
+            }
+            println!("{}", signature);
+        }
+        Command::SignTransaction { signer, transaction, output } => {
+            signer.validate()?;
+
+            let transaction = Transaction::parse(&read_input(transaction)?)?;
+            eprintln!("Signing transaction {}", serde_json::to_string_pretty(&transaction.to_json())?);
+
+            let reply = client
+                .sign_eth_transaction(
+                    &creds,
+                    &signer.key_file,
+                    signer.path.as_ref(),
+                    signer.threshold,
+                    &signer.active_parties,
+                    &transaction,
+                )
+                .await?;
+            let signed = report(reply, verbose);
+            let raw = format!("0x{}", hex::encode(&signed.raw));
+            if let Some(output) = output {
+                fs::write(output, &raw)?;
+            }
+            eprintln!("Transaction hash: 0x{}", hex::encode(signed.hash));
+            println!("{}", raw);
+        }
+        Command::PersonalSign { signer, message, message_file, hex: is_hex } => {
+            signer.validate()?;
+
+            let mut message = match (message, message_file) {
+                (Some(message), _) => message.clone().into_bytes(),
+                (None, Some(path)) => fs::read(path)?,
+                (None, None) => return Err("--message or --message-file is required".into()),
             };
-            keygen(&mut clients, &username, &password, &key_file, num_parties, num_threshold).await?;
-        }
-        "sign" => {
-            let num_parties: u16 = match args[4].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: num_parties not a string");
-                    panic!("");
+            if *is_hex {
+                message = eth::decode_hex(&String::from_utf8(message)?)?;
+            }
+
+            let reply = client
+                .sign_personal_message(
+                    &creds,
+                    &signer.key_file,
+                    signer.path.as_ref(),
+                    signer.threshold,
+                    &signer.active_parties,
+                    &message,
+                )
+                .await?;
+            println!("0x{}", hex::encode(report(reply, verbose)));
+        }
+        Command::SignTypedData { signer, typed_data } => {
+            signer.validate()?;
+
+            let typed_data = serde_json::from_str(&read_input(typed_data)?)?;
+            let reply = client
+                .sign_typed_data(
+                    &creds,
+                    &signer.key_file,
+                    signer.path.as_ref(),
+                    signer.threshold,
+                    &signer.active_parties,
+                    &typed_data,
+                )
+                .await?;
+            println!("0x{}", hex::encode(report(reply, verbose)));
+        }
+        Command::SignPsbt { key_file, parties, threshold, active_parties, psbt, any_sighash, output } => {
+            if let Some(parties) = parties {
+                validate_active_parties(active_parties, *threshold, *parties)?;
+            }
+
+            let psbt = signing::psbt::parse(&read_input(psbt)?)?;
+            eprintln!("Signing {}", serde_json::to_string_pretty(&signing::psbt::describe(&psbt))?);
+
+            let reply = client.sign_psbt(&creds, key_file, *threshold, active_parties, &psbt, *any_sighash).await?;
+            let signed = signing::psbt::encode(&report(reply, verbose));
+            if let Some(output) = output {
+                fs::write(output, &signed)?;
+            }
+            println!("{}", signed);
+        }
+        Command::Status { job } => {
+            let reply = client.job_status(&creds, *job).await?;
+            print_job(&reply.value, &reply.nodes);
+        }
+        Command::Wait { job, interval } => {
+            let reply = client.wait_job(&creds, *job, Duration::from_secs(*interval)).await?;
+            print_job(&reply.value, &reply.nodes);
+
+            let statuses: Vec<&JobStatus> = reply.value.iter().flatten().collect();
+            if statuses.iter().any(|status| status.state == JobState::Failed) {
+                return Err(format!("job {} failed", job).into());
+            }
+            // Parties that sit out a signing job have no result
+            let result = statuses.iter().filter_map(|status| status.result.as_ref()).find(|result| !result.is_null());
+            if let Some(result) = result {
+                println!("{}", serde_json::to_string_pretty(result)?);
+            }
+        }
+        Command::ListKeys => {
+            for key in report(client.list_keys(&creds).await?, verbose) {
+                let disagreeing = key.disagreeing();
+                match key.consensus() {
+                    Some(info) if disagreeing.is_empty() && info.deleted_at.is_some() => {
+                        println!("{}\t{}\tdeleted", key.key_file, info.id)
+                    }
+                    Some(info) if disagreeing.is_empty() => println!(
+                        "{}\t{}\towner={}\tt={}\tn={}\tepoch={}\t{}",
+                        key.key_file,
+                        info.id,
+                        info.owner,
+                        info.threshold,
+                        info.num_parties,
+                        info.refresh_epoch,
+                        info.public_key
+                    ),
+                    _ => println!("{}\tDISAGREE: parties {:?}", key.key_file, disagreeing),
                 }
-            };
-            let num_threshold: u16 = match args[5].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: num_threshold not a string");
-                    panic!("");
+            }
+        }
+        Command::KeyInfo { key_file } => {
+            let key = report(client.key_info(&creds, key_file).await?, verbose);
+            if let Some(info) = key.consensus() {
+                println!("Key file:      {}", key.key_file);
+                println!("Id:            {}", info.id);
+                println!("Owner:         {}", info.owner);
+                println!("Threshold:     {} of {}", info.threshold, info.num_parties);
+                println!("Public key:    {}", info.public_key);
+                println!("Refresh epoch: {}", info.refresh_epoch);
+                println!("Parties:       {:?}", info.party_indices);
+                if info.deleted_at.is_some() {
+                    println!("Status:        deleted");
                 }
-            };
-            let key_file: String = match args[6].parse() {
-                Ok(s) => s,
-                Err(_) => {
-                    eprintln!("error: key_file not a string");
-                    panic!("");
+            }
+            for (node, info) in key.nodes.iter().enumerate() {
+                match info {
+                    Some(info) => println!(
+                        "Party {}: share {} stored at {}",
+                        node + 1,
+                        info.party_index,
+                        info.created_at
+                    ),
+                    None => println!("Party {}: no metadata", node + 1),
                 }
-            };
+            }
 
-            let active_parties: String = match args[7].parse() {
-                Ok(n) => n,
-                Err(_) => {
-                    eprintln!("error: active_parties not a string");
-                    panic!("");
+            let disagreeing = key.disagreeing();
+            if !disagreeing.is_empty() {
+                return Err(format!("metadata of parties {:?} disagrees with the other nodes", disagreeing).into());
+            }
+        }
+        Command::DeleteKey { key_file } => {
+            let removed = report(client.delete_key(&creds, key_file).await?, verbose);
+            for (node, removed) in removed.iter().enumerate() {
+                if *removed {
+                    println!("Party {}: share destroyed", node + 1);
+                } else {
+                    println!("Party {}: no share left to destroy", node + 1);
                 }
+            }
+            println!("Key {} deleted", key_file);
+        }
+        Command::BackupKeypair => unreachable!("handled before connecting"),
+        Command::ExportBackup { key_file, party, passphrase, recipient, output } => {
+            let recipient = match (passphrase, recipient) {
+                (Some(passphrase), None) => BackupRecipient::Passphrase(passphrase.clone()),
+                (None, Some(recipient)) => BackupRecipient::PublicKey(recipient.clone()),
+                _ => return Err("exactly one of --passphrase and --recipient is required".into()),
             };
-
-            let message: String = match args[8].parse() {
-                Ok(n) => n,
-                Err(_) => {
-                    eprintln!("error: message not a string");
-                    panic!("");
-                }
+            let backup = report(client.export_backup(&creds, key_file, *party, &recipient).await?, verbose);
+            fs::write(output, serde_json::to_string_pretty(&backup)?)?;
+            println!("Backup of party {} written to {}", party, output);
+        }
+        Command::ImportBackup { key_file, party, backup, passphrase, secret_key } => {
+            let secret = match (passphrase, secret_key) {
+                (Some(passphrase), None) => BackupSecret::Passphrase(passphrase.clone()),
+                (None, Some(secret_key)) => BackupSecret::SecretKey(secret_key.clone()),
+                _ => return Err("exactly one of --passphrase and --secret-key is required".into()),
             };
-
-            let active_parties: Vec<u16> = active_parties.split(",")
-                .map(|s| s.parse::<u16>().unwrap())
-                .collect();
-
-            sign(&mut clients, &username, &password, &key_file, num_threshold, &active_parties, &message).await?;
+            let backup = serde_json::from_slice(&fs::read(backup)?)?;
+            let reply = client.import_backup(&creds, key_file, *party, &backup, &secret).await?;
+            let public_key = report(reply, verbose);
+            println!("Share of party {} restored", party);
+            println!("Public key: {}", hex::encode(public_key.to_bytes(true)));
         }
-
-        _ => println!("Missing/wrong arguments"),
-    };
+        Command::RecoverShare { key_file, lost_party, helpers } => {
+            let reply = client.recover_share(&creds, key_file, *lost_party, helpers).await?;
+            let public_key = report(reply, verbose);
+            println!("Share of party {} rebuilt", lost_party);
+            println!("Public key: {}", hex::encode(public_key.to_bytes(true)));
+        }
+        Command::Pubkey { key_file, path } => {
+            let path = path.clone().unwrap_or_default();
+            let xpub = report(client.xpub(&creds, key_file, &path).await?, verbose);
+            println!("{}", hex::encode(xpub.public_key.to_bytes(true)));
+        }
+        Command::Xpub { key_file, path } => {
+            let xpub = report(client.xpub(&creds, key_file, path).await?, verbose);
+            eprintln!(
+                "warning: the chain code of this xpub is derived from the joint public key, not kept secret. \
+                 Anyone who knows the public key can derive every child address and link them to each other."
+            );
+            println!("{}", xpub.to_xpub());
+        }
+        Command::Verify { message, signature, key_file, path, .. } => {
+            let key_file = key_file.as_deref().ok_or("--public-key or --key-file is required")?;
+            let path = path.clone().unwrap_or_default();
+            let xpub = report(client.xpub(&creds, key_file, &path).await?, verbose);
+            check_signature(&xpub.public_key, &message.digest()?, signature)?;
+        }
+    }
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let request_id = cli.request_id.unwrap_or_else(Uuid::new_v4);
+
+    match run(cli, request_id).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("To retry, run the command again with --request-id {}", request_id);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+use crate::Error;
+
+/// Why a node refused or failed a request, reported next to its error message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The password or one-time code of the user is wrong
+    Unauthenticated,
+    /// The user failed to log in too often and must wait
+    Throttled,
+    /// The role of the user or the owner of the key does not allow the request
+    Forbidden,
+    /// The request would overwrite something that exists, such as a key share
+    Conflict,
+    #[default]
+    Other,
+}
+
+/// What became of a request on one node
+#[derive(Clone, Debug)]
+pub enum NodeStatus {
+    /// The node handled the request and returned this result
+    Succeeded(Value),
+    /// The node handled the request and reported an error
+    Failed { kind: FailureKind, message: String },
+    /// The node did not answer before the deadline of the call
+    TimedOut,
+    /// The node could not be reached or the call failed in transport
+    Unreachable(String),
+    /// The node answered with something that is not a valid reply
+    InvalidResponse(String),
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeStatus::Succeeded(_) => write!(f, "ok"),
+            NodeStatus::Failed { message, .. } => write!(f, "failed: {}", message),
+            NodeStatus::TimedOut => write!(f, "timed out"),
+            NodeStatus::Unreachable(message) => write!(f, "unreachable: {}", message),
+            NodeStatus::InvalidResponse(message) => write!(f, "invalid response: {}", message),
+        }
+    }
+}
+
+/// Outcome of a request on one node
+#[derive(Clone, Debug)]
+pub struct NodeResult {
+    /// Endpoint of the node
+    pub node: String,
+    pub status: NodeStatus,
+}
+
+/// Outcome of a request on every node, in party order
+#[derive(Clone, Debug)]
+pub struct NodeResults(pub Vec<NodeResult>);
+
+impl fmt::Display for NodeResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, result) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  party {} ({}): {}", i + 1, result.node, result.status)?;
+        }
+        Ok(())
+    }
+}
+
+impl NodeResults {
+    /// Returns the result of node `node`, counting from 0, if it succeeded
+    pub fn value(&self, node: usize) -> Option<&Value> {
+        match &self.0.get(node)?.status {
+            NodeStatus::Succeeded(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the index and result of every node that succeeded
+    pub fn succeeded(&self) -> impl Iterator<Item = (usize, &Value)> {
+        (0..self.0.len()).filter_map(|node| self.value(node).map(|value| (node, value)))
+    }
+
+    pub fn all_succeeded(&self) -> bool {
+        self.succeeded().count() == self.0.len()
+    }
+
+    /// Fails unless every node succeeded
+    pub(crate) fn require_all(&self) -> Result<(), Error> {
+        if self.all_succeeded() {
+            return Ok(());
+        }
+        let succeeded = self.succeeded().count();
+        Err(self.quorum_error(format!(
+            "all {} nodes must succeed but {} did{}",
+            self.0.len(),
+            succeeded,
+            if succeeded > 0 { ", and the request took effect on those nodes only" } else { "" }
+        )))
+    }
+
+    /// Fails unless every one of `parties`, counting from 1, succeeded
+    pub(crate) fn require_parties(&self, parties: &[u16]) -> Result<(), Error> {
+        let missing: Vec<String> = parties
+            .iter()
+            .filter(|party| self.value(**party as usize - 1).is_none())
+            .map(|party| party.to_string())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(self.quorum_error(format!("active parties {} did not succeed", missing.join(", "))))
+    }
+
+    /// Fails unless at least one node succeeded
+    pub(crate) fn require_any(&self) -> Result<(), Error> {
+        if self.succeeded().next().is_some() {
+            return Ok(());
+        }
+        Err(self.quorum_error("no node succeeded".to_owned()))
+    }
+
+    fn quorum_error(&self, requirement: String) -> Error {
+        Error::Quorum { requirement, nodes: self.clone() }
+    }
+
+    /// Parses the result of every node that succeeded with `parse` and checks that they all agree,
+    /// returning the agreed value. Nodes for which `parse` returns `None` are left out.
+    pub(crate) fn agreed<T: PartialEq>(
+        &self,
+        what: &str,
+        parse: impl Fn(usize, &Value) -> Result<Option<T>, Error>,
+    ) -> Result<T, Error> {
+        let mut values: Vec<(usize, T)> = vec![];
+        for (node, result) in self.succeeded() {
+            if let Some(value) = parse(node, result)? {
+                values.push((node, value));
+            }
+        }
+
+        let (first_node, first) = match values.first() {
+            Some(first) => first,
+            None => return Err(self.quorum_error(format!("no node returned {}", what))),
+        };
+        let disagreeing: Vec<String> = values
+            .iter()
+            .filter(|(_, value)| value != first)
+            .map(|(node, _)| (node + 1).to_string())
+            .collect();
+        if !disagreeing.is_empty() {
+            return Err(Error::Inconsistent {
+                message: format!(
+                    "parties {} returned a different {} than party {}",
+                    disagreeing.join(", "),
+                    what,
+                    first_node + 1
+                ),
+                nodes: self.clone(),
+            });
+        }
+
+        Ok(values.swap_remove(0).1)
+    }
+}
+
+/// Result of a request together with the outcome on each node, which may include nodes that failed
+/// without preventing the request from succeeding
+#[derive(Debug)]
+pub struct Reply<T> {
+    pub value: T,
+    pub nodes: NodeResults,
+}
@@ -0,0 +1,324 @@
+//! Signing of Bitcoin PSBTs (BIP174).
+//!
+//! An input is owned by a key if it spends a P2WPKH or P2PKH output of the key itself or of one
+//! of its non-hardened BIP32 children. Children are found through the `bip32_derivation` entries of
+//! the input whose fingerprint is that of the key, as `xpub` exports it.
+//!
+//! Owned inputs are only signed with `SIGHASH_ALL` unless the caller allows other sighash types,
+//! since a signature that does not commit to every input and output can be reused in a
+//! transaction the signer never saw.
+
+use bitcoin::bip32::ChildNumber;
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1;
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::{ecdsa, PublicKey, ScriptBuf, TxOut};
+use curv::elliptic::curves::{Point, Secp256k1};
+use data_encoding::BASE64;
+use serde_json::{json, Value};
+
+use crate::hd::{DerivationPath, ExtendedPublicKey};
+use crate::{Error, SignatureRecid};
+
+fn invalid(message: String) -> Error {
+    Error::InvalidRequest(format!("invalid PSBT: {}", message))
+}
+
+/// An input of a PSBT that the key can sign
+#[derive(Clone, Debug)]
+pub struct OwnedInput {
+    pub index: usize,
+    /// Path of the child key that owns the input, empty for the key itself
+    pub path: DerivationPath,
+    pub public_key: Point<Secp256k1>,
+    pub sighash_type: EcdsaSighashType,
+    /// BIP143 sighash for P2WPKH inputs and legacy sighash for P2PKH inputs
+    pub digest: [u8; 32],
+}
+
+/// Decodes a base64 PSBT
+pub fn parse(psbt: &str) -> Result<Psbt, Error> {
+    let bytes = BASE64.decode(psbt.trim().as_bytes()).map_err(|e| invalid(e.to_string()))?;
+    Psbt::deserialize(&bytes).map_err(|e| invalid(e.to_string()))
+}
+
+/// Encodes a PSBT in base64
+pub fn encode(psbt: &Psbt) -> String {
+    BASE64.encode(&psbt.serialize())
+}
+
+fn bitcoin_public_key(public_key: &Point<Secp256k1>) -> PublicKey {
+    PublicKey::from_slice(&public_key.to_bytes(true)).expect("curve points are valid public keys")
+}
+
+/// Returns the output spent by input `index`. A P2PKH input needs the full previous transaction,
+/// whose id is checked against the outpoint, while a P2WPKH input may only give the output.
+fn spent_output(psbt: &Psbt, index: usize) -> Result<Option<(TxOut, bool)>, Error> {
+    let input = &psbt.inputs[index];
+    let outpoint = psbt.unsigned_tx.input[index].previous_output;
+    if let Some(previous) = &input.non_witness_utxo {
+        if previous.txid() != outpoint.txid {
+            return Err(invalid(format!("previous transaction of input {} does not match its outpoint", index)));
+        }
+        let output = previous
+            .output
+            .get(outpoint.vout as usize)
+            .ok_or_else(|| invalid(format!("input {} spends an output that does not exist", index)))?;
+        return Ok(Some((output.clone(), true)));
+    }
+    Ok(input.witness_utxo.clone().map(|output| (output, false)))
+}
+
+/// Finds the inputs that the key with joint public key `public_key` owns and computes their
+/// sighashes. Inputs that are already finalized are skipped. An owned input that asks for another
+/// sighash type than `SIGHASH_ALL` is an error unless `any_sighash` is set.
+pub fn owned_inputs(psbt: &Psbt, public_key: &Point<Secp256k1>, any_sighash: bool) -> Result<Vec<OwnedInput>, Error> {
+    if psbt.inputs.len() != psbt.unsigned_tx.input.len() {
+        return Err(invalid("number of inputs does not match the transaction".to_owned()));
+    }
+
+    let master = ExtendedPublicKey::master(public_key);
+    let fingerprint = master.fingerprint();
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut owned = vec![];
+
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let (spent, full_previous) = match spent_output(psbt, index)? {
+            Some(spent) => spent,
+            None => continue,
+        };
+
+        // The key itself and every child the input names under the key's fingerprint
+        let mut paths = vec![DerivationPath::default()];
+        for (source_fingerprint, source_path) in input.bip32_derivation.values() {
+            if source_fingerprint.to_bytes() != fingerprint {
+                continue;
+            }
+            let indices: Option<Vec<u32>> = source_path
+                .into_iter()
+                .map(|child| match child {
+                    ChildNumber::Normal { index } => Some(*index),
+                    ChildNumber::Hardened { .. } => None,
+                })
+                .collect();
+            if let Some(indices) = indices {
+                paths.push(DerivationPath(indices));
+            }
+        }
+
+        for path in paths {
+            let (child, _) = master.derive(&path)?;
+            let key = bitcoin_public_key(&child.public_key);
+            let sighash_type = input.ecdsa_hash_ty().map_err(|e| invalid(e.to_string()))?;
+
+            let wpkh = key.wpubkey_hash().expect("keys are compressed");
+            let digest = if spent.script_pubkey == ScriptBuf::new_v0_p2wpkh(&wpkh) {
+                let script_code = spent.script_pubkey.p2wpkh_script_code().expect("script is P2WPKH");
+                cache
+                    .segwit_signature_hash(index, &script_code, spent.value, sighash_type)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .to_byte_array()
+            } else if spent.script_pubkey == ScriptBuf::new_p2pkh(&key.pubkey_hash()) {
+                if !full_previous {
+                    return Err(invalid(format!("P2PKH input {} lacks its previous transaction", index)));
+                }
+                cache
+                    .legacy_signature_hash(index, &spent.script_pubkey, sighash_type.to_u32())
+                    .map_err(|e| invalid(e.to_string()))?
+                    .to_byte_array()
+            } else {
+                continue;
+            };
+            if sighash_type != EcdsaSighashType::All && !any_sighash {
+                return Err(invalid(format!(
+                    "input {} asks for {}, which is only signed if other sighash types are allowed",
+                    index, sighash_type
+                )));
+            }
+
+            owned.push(OwnedInput { index, path, public_key: child.public_key, sighash_type, digest });
+            break;
+        }
+    }
+    Ok(owned)
+}
+
+/// Adds the signature of an owned input to its partial signatures, DER encoded with the sighash
+/// type. A high `s` is negated, since Bitcoin only relays signatures with a low `s`.
+pub fn insert_signature(psbt: &mut Psbt, input: &OwnedInput, signature: &SignatureRecid) -> Result<(), Error> {
+    let mut compact = [0u8; 64];
+    compact[..32].copy_from_slice(&signature.r.to_bytes());
+    compact[32..].copy_from_slice(&signature.s.to_bytes());
+    let mut sig = secp256k1::ecdsa::Signature::from_compact(&compact)
+        .map_err(|e| Error::InvalidRequest(format!("invalid signature: {}", e)))?;
+    sig.normalize_s();
+
+    psbt.inputs[input.index]
+        .partial_sigs
+        .insert(bitcoin_public_key(&input.public_key), ecdsa::Signature { sig, hash_ty: input.sighash_type });
+    Ok(())
+}
+
+/// Describes the transaction a PSBT spends into, with the fee if the value of every input is known.
+/// Only values taken from a previous transaction are known: the `witness_utxo` of an input is not
+/// checked against anything, and a BIP143 signature only commits to the value of its own input,
+/// so a PSBT could claim a smaller value for each input in turn and hide part of the fee.
+pub fn describe(psbt: &Psbt) -> Value {
+    let input_values: Vec<Option<u64>> = (0..psbt.inputs.len().min(psbt.unsigned_tx.input.len()))
+        .map(|index| match spent_output(psbt, index) {
+            Ok(Some((output, true))) => Some(output.value),
+            _ => None,
+        })
+        .collect();
+    let inputs: Vec<Value> = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(input_values.iter().chain(std::iter::repeat(&None)))
+        .map(|(input, value)| json!({ "previous_output": input.previous_output.to_string(), "value": value }))
+        .collect();
+    let outputs: Vec<Value> = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|output| json!({ "script_pubkey": hex::encode(output.script_pubkey.as_bytes()), "value": output.value }))
+        .collect();
+
+    let input_total: Option<u64> = input_values.iter().copied().sum();
+    let output_total: u64 = psbt.unsigned_tx.output.iter().map(|output| output.value).sum();
+    json!({
+        "txid": psbt.unsigned_tx.txid().to_string(),
+        "inputs": inputs,
+        "outputs": outputs,
+        "fee": input_total.and_then(|total| total.checked_sub(output_total)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::psbt::PsbtSighashType;
+    use bitcoin::{OutPoint, Transaction, TxIn};
+
+    /// Unsigned transaction of the native P2WPKH example in BIP143
+    const BIP143_TX: &str = concat!(
+        "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffff",
+        "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb20600",
+        "0000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e",
+        "4dbe6a21b2d50ce2f0167faa815988ac11000000",
+    );
+    /// Key of the second input of the example, which spends a P2WPKH output of 6 BTC
+    const BIP143_KEY: &str = "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357";
+    const BIP143_KEY_HASH: &str = "1d0f172a0ecb48aee1be1f2687d2963ae33f71a1";
+
+    fn script(hex: &str) -> ScriptBuf {
+        ScriptBuf::from(hex::decode(hex).unwrap())
+    }
+
+    fn public_key() -> Point<Secp256k1> {
+        Point::from_bytes(&hex::decode(BIP143_KEY).unwrap()).unwrap()
+    }
+
+    /// PSBT of the example, with the spent outputs of both inputs. The first one is a P2PK
+    /// output of another key.
+    fn bip143_psbt() -> Psbt {
+        let tx: Transaction = deserialize(&hex::decode(BIP143_TX).unwrap()).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 625_000_000,
+            script_pubkey: script("2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac"),
+        });
+        psbt.inputs[1].witness_utxo =
+            Some(TxOut { value: 600_000_000, script_pubkey: script(&format!("0014{}", BIP143_KEY_HASH)) });
+        psbt
+    }
+
+    #[test]
+    fn p2wpkh_sighash_matches_bip143() {
+        let owned = owned_inputs(&bip143_psbt(), &public_key(), false).unwrap();
+        assert_eq!(owned.len(), 1);
+        assert_eq!(owned[0].index, 1);
+        assert!(owned[0].path.0.is_empty());
+        assert_eq!(owned[0].sighash_type, EcdsaSighashType::All);
+        assert_eq!(hex::encode(owned[0].digest), "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670");
+    }
+
+    #[test]
+    fn finalized_inputs_are_skipped() {
+        let mut psbt = bip143_psbt();
+        psbt.inputs[1].final_script_witness = Some(Default::default());
+        assert!(owned_inputs(&psbt, &public_key(), false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn p2pkh_input_needs_its_previous_transaction() {
+        let mut psbt = bip143_psbt();
+        psbt.inputs[1].witness_utxo =
+            Some(TxOut { value: 600_000_000, script_pubkey: script(&format!("76a914{}88ac", BIP143_KEY_HASH)) });
+        assert!(matches!(owned_inputs(&psbt, &public_key(), false), Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn other_sighash_types_need_to_be_allowed() {
+        let mut psbt = bip143_psbt();
+        psbt.inputs[1].sighash_type = Some(PsbtSighashType::from(EcdsaSighashType::SinglePlusAnyoneCanPay));
+        assert!(matches!(owned_inputs(&psbt, &public_key(), false), Err(Error::InvalidRequest(_))));
+
+        let owned = owned_inputs(&psbt, &public_key(), true).unwrap();
+        assert_eq!(owned[0].sighash_type, EcdsaSighashType::SinglePlusAnyoneCanPay);
+    }
+
+    /// Transaction paying `value` to the key, which a PSBT can give as the previous transaction
+    fn previous_transaction(value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value, script_pubkey: script(&format!("0014{}", BIP143_KEY_HASH)) }],
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips_and_fee_is_described() {
+        let psbt = bip143_psbt();
+        assert_eq!(parse(&encode(&psbt)).unwrap(), psbt);
+
+        // The example spends outputs whose transactions are not published, so the fee is tested
+        // on a transaction that spends two made up ones
+        let previous = [previous_transaction(625_000_000), previous_transaction(600_000_000)];
+        let mut tx: Transaction = deserialize(&hex::decode(BIP143_TX).unwrap()).unwrap();
+        for (input, previous) in tx.input.iter_mut().zip(&previous) {
+            input.previous_output = OutPoint { txid: previous.txid(), vout: 0 };
+        }
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        for (input, previous) in psbt.inputs.iter_mut().zip(previous) {
+            input.witness_utxo = Some(previous.output[0].clone());
+            input.non_witness_utxo = Some(previous);
+        }
+        assert_eq!(parse(&encode(&psbt)).unwrap(), psbt);
+
+        let description = describe(&psbt);
+        assert_eq!(description["inputs"][1]["value"], 600_000_000);
+        assert_eq!(description["outputs"][0]["value"], 112_340_000);
+        assert_eq!(description["fee"], 625_000_000 + 600_000_000 - 112_340_000 - 223_450_000);
+
+        // The value of an output given without its transaction is not trusted
+        let mut unknown = psbt;
+        unknown.inputs[0].non_witness_utxo = None;
+        let description = describe(&unknown);
+        assert!(description["inputs"][0]["value"].is_null());
+        assert!(description["fee"].is_null());
+    }
+
+    #[test]
+    fn garbage_is_not_a_psbt() {
+        assert!(matches!(parse("not a psbt"), Err(Error::InvalidRequest(_))));
+        assert!(matches!(parse(&BASE64.encode(b"psbt\xff")), Err(Error::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,148 @@
+//! Authentication of gateway callers, who log in as users of the cluster.
+//!
+//! The nodes throttle failed logins by user, but only see the gateway, and the client id that
+//! clients send is their own choice. The gateway therefore also throttles the addresses that
+//! connect to it, with the backoff and lockout that the nodes apply to users by default.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::{header, HeaderMap};
+use data_encoding::BASE64;
+use signing::Credentials;
+
+/// Delay after the first failed login of a client, doubled by every further failure
+const BASE_DELAY_SECS: u64 = 1;
+const MAX_DELAY_SECS: u64 = 60;
+/// Consecutive failed logins after which a client is locked out
+const MAX_ATTEMPTS: u32 = 5;
+const LOCKOUT_SECS: u64 = 900;
+
+/// Header that carries the one-time code of users who enrolled a TOTP second factor
+pub const TOTP_HEADER: &str = "x-signing-totp";
+
+/// Value of the `WWW-Authenticate` header of responses to callers without credentials
+pub const CHALLENGE: &str = "Basic realm=\"signing\"";
+
+/// Reads the credentials of the caller from the basic auth and TOTP headers
+pub fn credentials(headers: &HeaderMap) -> Option<Credentials> {
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim().as_bytes()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    let creds = Credentials::new(username, password);
+    match headers.get(TOTP_HEADER).and_then(|code| code.to_str().ok()) {
+        Some(code) => Some(creds.with_totp(code)),
+        None => Some(creds),
+    }
+}
+
+/// What the nodes made of the credentials of a request, ordered so that the outcome of several
+/// calls is the largest of their outcomes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Login {
+    /// The request failed before the nodes checked the credentials
+    Unchecked,
+    Accepted,
+    /// The nodes rejected the credentials or are throttling the user
+    Refused,
+}
+
+#[derive(Default)]
+struct Attempts {
+    /// Consecutive failed logins since the last accepted one or lockout
+    failed: u32,
+    /// Unix time before which no further attempt is accepted
+    retry_at: u64,
+    /// An attempt whose credentials were not checked yet is running
+    pending: bool,
+    /// The last credentials of the client were accepted
+    trusted: bool,
+}
+
+impl Attempts {
+    /// Whether the client is indistinguishable from one never seen, so that it can be forgotten
+    fn idle(&self, now: u64) -> bool {
+        self.failed == 0 && self.retry_at <= now && !self.pending && !self.trusted
+    }
+}
+
+/// Failed logins by client address, kept in memory
+#[derive(Default)]
+pub struct ClientThrottle {
+    clients: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// A request admitted by [`ClientThrottle::admit`]. Its outcome is recorded when it is dropped, so
+/// that a request whose caller went away still releases its client.
+pub struct Admission {
+    throttle: Arc<ClientThrottle>,
+    client: IpAddr,
+    pub login: Login,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.throttle.finish(self.client, self.login);
+    }
+}
+
+impl ClientThrottle {
+    /// Admits a request of `client`, or returns the seconds it has to wait. A client whose last
+    /// credentials were accepted may run requests concurrently. Any other client runs one request
+    /// at a time until the nodes accept its credentials, so that parallel attempts cannot skip
+    /// the backoff.
+    pub fn admit(self: &Arc<Self>, client: IpAddr) -> Result<Admission, u64> {
+        self.reserve(client)?;
+        Ok(Admission { throttle: Arc::clone(self), client, login: Login::Unchecked })
+    }
+
+    fn reserve(&self, client: IpAddr) -> Result<(), u64> {
+        let now = unix_time();
+        let mut clients = self.clients.lock().unwrap();
+        let attempts = clients.entry(client).or_default();
+        if now < attempts.retry_at {
+            return Err(attempts.retry_at - now);
+        }
+        if attempts.trusted {
+            return Ok(());
+        }
+        if attempts.pending {
+            return Err(1);
+        }
+        attempts.pending = true;
+        Ok(())
+    }
+
+    fn finish(&self, client: IpAddr, login: Login) {
+        let now = unix_time();
+        let mut clients = self.clients.lock().unwrap();
+        let attempts = clients.entry(client).or_default();
+        attempts.pending = false;
+        match login {
+            Login::Unchecked => {}
+            Login::Accepted => *attempts = Attempts { trusted: true, ..Default::default() },
+            Login::Refused => {
+                attempts.trusted = false;
+                attempts.failed += 1;
+                if attempts.failed >= MAX_ATTEMPTS {
+                    attempts.failed = 0;
+                    attempts.retry_at = now.saturating_add(LOCKOUT_SECS);
+                } else {
+                    let delay = BASE_DELAY_SECS.saturating_mul(1 << (attempts.failed - 1).min(32));
+                    attempts.retry_at = now.saturating_add(delay.min(MAX_DELAY_SECS));
+                }
+            }
+        }
+        if attempts.idle(now) {
+            clients.remove(&client);
+        }
+    }
+}
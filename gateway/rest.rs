@@ -0,0 +1,424 @@
+//! REST gateway to the signing cluster for services that do not speak DOTS gRPC.
+//!
+//! Every endpoint makes the same requests to the nodes as the matching `client` subcommand and
+//! returns the aggregated result along with the outcome on each node. Callers authenticate as a
+//! user of the cluster with HTTP basic auth and the `X-Signing-Totp` header. The API is described
+//! by the OpenAPI document served at `/openapi.json`.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::Parser;
+use curv::elliptic::curves::{Point, Secp256k1};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use signing::hd::DerivationPath;
+use signing::{
+    generate_totp_secret, totp_uri, validate_active_parties, verify_signature, ClientConfig, Credentials,
+    FailureKind, HashScheme, NodeResults, NodeStatus, SignatureRecid, SigningClient,
+};
+
+mod auth;
+
+use auth::{ClientThrottle, Login};
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+/// REST gateway to a DOTS threshold signing cluster
+#[derive(Parser)]
+#[command(name = "rest_gateway", version)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+}
+
+type Client = Arc<SigningClient>;
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// Outcome on each node, for errors that the nodes reported
+    nodes: Option<NodeResults>,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError { status: StatusCode::BAD_REQUEST, message: message.into(), nodes: None }
+    }
+}
+
+/// Picks the status of a request that too many nodes failed from the kind of failure the first
+/// node that refused it reported
+fn failure_status(nodes: &NodeResults) -> StatusCode {
+    for result in &nodes.0 {
+        if let NodeStatus::Failed { kind, .. } = &result.status {
+            match kind {
+                FailureKind::Unauthenticated => return StatusCode::UNAUTHORIZED,
+                FailureKind::Throttled => return StatusCode::TOO_MANY_REQUESTS,
+                FailureKind::Forbidden => return StatusCode::FORBIDDEN,
+                FailureKind::Conflict => return StatusCode::CONFLICT,
+                FailureKind::Other => {}
+            }
+        }
+    }
+    StatusCode::BAD_GATEWAY
+}
+
+impl From<signing::Error> for ApiError {
+    fn from(e: signing::Error) -> Self {
+        match e {
+            signing::Error::InvalidRequest(message) => ApiError::bad_request(message),
+            signing::Error::Quorum { requirement, nodes } => ApiError {
+                status: failure_status(&nodes),
+                message: format!("quorum not reached, {}", requirement),
+                nodes: Some(nodes),
+            },
+            signing::Error::Inconsistent { message, nodes } => {
+                ApiError { status: StatusCode::BAD_GATEWAY, message, nodes: Some(nodes) }
+            }
+            e @ signing::Error::InvalidResponse { .. } => {
+                ApiError { status: StatusCode::BAD_GATEWAY, message: e.to_string(), nodes: None }
+            }
+            e => ApiError { status: StatusCode::INTERNAL_SERVER_ERROR, message: e.to_string(), nodes: None },
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = json!({ "error": self.message });
+        if let Some(nodes) = &self.nodes {
+            body["nodes"] = nodes_json(nodes);
+        }
+        if self.status == StatusCode::UNAUTHORIZED {
+            return (self.status, [(header::WWW_AUTHENTICATE, auth::CHALLENGE)], Json(body)).into_response();
+        }
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// Lists the outcome on each node in party order
+fn nodes_json(nodes: &NodeResults) -> Value {
+    let nodes: Vec<Value> = nodes
+        .0
+        .iter()
+        .enumerate()
+        .map(|(node, result)| {
+            json!({
+                "party": node + 1,
+                "node": result.node,
+                "ok": matches!(result.status, NodeStatus::Succeeded(_)),
+                "status": result.status.to_string(),
+            })
+        })
+        .collect();
+    Value::Array(nodes)
+}
+
+/// Adds the outcome on each node to the body of a response
+fn with_nodes(mut body: Value, nodes: &NodeResults) -> Json<Value> {
+    body["nodes"] = nodes_json(nodes);
+    Json(body)
+}
+
+fn require_credentials(headers: &HeaderMap) -> Result<Credentials, ApiError> {
+    auth::credentials(headers).ok_or(ApiError {
+        status: StatusCode::UNAUTHORIZED,
+        message: "basic authentication is required".to_owned(),
+        nodes: None,
+    })
+}
+
+fn derivation_path(path: Option<&str>) -> Result<DerivationPath, ApiError> {
+    Ok(path.map(str::parse::<DerivationPath>).transpose()?.unwrap_or_default())
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Hash {
+    #[default]
+    Sha256,
+    Keccak256,
+}
+
+/// Message to sign or verify, given either as text or hex to be hashed, or as a 32-byte digest
+#[derive(Deserialize)]
+struct Message {
+    message: Option<String>,
+    /// The message is hex encoded and is decoded before hashing
+    #[serde(default)]
+    hex: bool,
+    digest: Option<String>,
+    #[serde(default)]
+    hash: Hash,
+}
+
+impl Message {
+    fn digest(&self) -> Result<Vec<u8>, ApiError> {
+        match (&self.message, &self.digest) {
+            (Some(message), None) => {
+                let message = if self.hex {
+                    hex::decode(message.trim()).map_err(|e| ApiError::bad_request(format!("invalid message: {}", e)))?
+                } else {
+                    message.as_bytes().to_vec()
+                };
+                let scheme = match self.hash {
+                    Hash::Sha256 => HashScheme::Sha256,
+                    Hash::Keccak256 => HashScheme::Keccak256,
+                };
+                Ok(scheme.digest(&message).to_vec())
+            }
+            (None, Some(digest)) => {
+                let digest =
+                    hex::decode(digest.trim()).map_err(|e| ApiError::bad_request(format!("invalid digest: {}", e)))?;
+                if digest.len() != 32 {
+                    return Err(ApiError::bad_request(format!("digest is {} bytes long instead of 32", digest.len())));
+                }
+                Ok(digest)
+            }
+            _ => Err(ApiError::bad_request("exactly one of message and digest is required")),
+        }
+    }
+}
+
+/// Throttles the callers that send credentials by their address. The nodes report rejected
+/// credentials as 401 and throttled users as 429, while any other answer from the nodes means
+/// that they accepted the credentials.
+async fn throttle_clients<B>(
+    State(throttle): State<Arc<ClientThrottle>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if auth::credentials(request.headers()).is_none() {
+        return next.run(request).await;
+    }
+    let mut admission = match throttle.admit(address.ip()) {
+        Ok(admission) => admission,
+        Err(wait) => {
+            let message = format!("too many failed logins from {}, retry in {} seconds", address.ip(), wait);
+            return ApiError { status: StatusCode::TOO_MANY_REQUESTS, message, nodes: None }.into_response();
+        }
+    };
+
+    let response = next.run(request).await;
+    admission.login = match response.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS => Login::Refused,
+        StatusCode::FORBIDDEN | StatusCode::CONFLICT => Login::Accepted,
+        status if status.is_success() => Login::Accepted,
+        _ => Login::Unchecked,
+    };
+    response
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+async fn login(State(client): State<Client>, headers: HeaderMap) -> Result<Json<Value>, ApiError> {
+    let creds = require_credentials(&headers)?;
+    let reply = client.login(&creds).await?;
+    Ok(with_nodes(json!({ "username": reply.value.username, "role": reply.value.role }), &reply.nodes))
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    username: String,
+    password: String,
+    role: Option<String>,
+    /// Enroll a TOTP second factor and return its otpauth URI
+    #[serde(default)]
+    enroll_totp: bool,
+}
+
+async fn register(
+    State(client): State<Client>,
+    headers: HeaderMap,
+    Json(request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let creds = require_credentials(&headers)?;
+    let role = request.role.as_deref().unwrap_or("signer");
+    let totp_secret = if request.enroll_totp { Some(generate_totp_secret()) } else { None };
+
+    let reply = client
+        .register(&creds, &request.username, &request.password, Some(role), totp_secret.as_deref())
+        .await?;
+    let totp_uri = totp_secret.map(|secret| totp_uri(&request.username, &secret));
+    let body = json!({ "username": request.username, "role": role, "totp_uri": totp_uri });
+    Ok((StatusCode::CREATED, with_nodes(body, &reply.nodes)))
+}
+
+async fn list_keys(State(client): State<Client>, headers: HeaderMap) -> Result<Json<Value>, ApiError> {
+    let creds = require_credentials(&headers)?;
+    let reply = client.list_keys(&creds).await?;
+
+    let keys: Vec<Value> = reply
+        .value
+        .iter()
+        .map(|key| {
+            let info = key.consensus();
+            json!({
+                "key_file": key.key_file,
+                "info": info,
+                "deleted": info.map_or(false, |info| info.deleted_at.is_some()),
+                "disagreeing_parties": key.disagreeing(),
+            })
+        })
+        .collect();
+    Ok(with_nodes(json!({ "keys": keys }), &reply.nodes))
+}
+
+#[derive(Deserialize)]
+struct KeygenRequest {
+    key_file: String,
+    /// Number of parties holding a share, by default the number of nodes
+    parties: Option<u16>,
+    threshold: u16,
+    /// Archive the key already stored in the key file and replace it
+    #[serde(default)]
+    replace: bool,
+}
+
+async fn keygen(
+    State(client): State<Client>,
+    headers: HeaderMap,
+    Json(request): Json<KeygenRequest>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let creds = require_credentials(&headers)?;
+    let parties = request.parties.unwrap_or(client.num_nodes() as u16);
+
+    let reply = client.keygen(&creds, &request.key_file, parties, request.threshold, request.replace).await?;
+    let body = json!({
+        "key_file": request.key_file,
+        "public_key": hex::encode(reply.value.to_bytes(true)),
+    });
+    Ok((StatusCode::CREATED, with_nodes(body, &reply.nodes)))
+}
+
+#[derive(Deserialize)]
+struct PubkeyQuery {
+    /// BIP32 path of a child key, such as m/0/7
+    path: Option<String>,
+}
+
+async fn pubkey(
+    State(client): State<Client>,
+    Path(key_file): Path<String>,
+    Query(query): Query<PubkeyQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let creds = require_credentials(&headers)?;
+    let path = derivation_path(query.path.as_deref())?;
+
+    let reply = client.xpub(&creds, &key_file, &path).await?;
+    let body = json!({
+        "key_file": key_file,
+        "path": path.to_string(),
+        "public_key": hex::encode(reply.value.public_key.to_bytes(true)),
+        "xpub": reply.value.to_xpub(),
+    });
+    Ok(with_nodes(body, &reply.nodes))
+}
+
+#[derive(Deserialize)]
+struct SignRequest {
+    threshold: u16,
+    active_parties: Vec<u16>,
+    /// Number of parties holding a share, checked against the active parties if given
+    parties: Option<u16>,
+    path: Option<String>,
+    #[serde(flatten)]
+    message: Message,
+}
+
+async fn sign(
+    State(client): State<Client>,
+    Path(key_file): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<SignRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let creds = require_credentials(&headers)?;
+    if let Some(parties) = request.parties {
+        validate_active_parties(&request.active_parties, request.threshold, parties)?;
+    }
+    let path = request.path.as_deref().map(str::parse::<DerivationPath>).transpose()?;
+    let digest = request.message.digest()?;
+
+    let reply = client
+        .sign(&creds, &key_file, path.as_ref(), request.threshold, &request.active_parties, &digest)
+        .await?;
+    let body = json!({ "digest": hex::encode(&digest), "signature": reply.value });
+    Ok(with_nodes(body, &reply.nodes))
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    signature: SignatureRecid,
+    /// Compressed public key in hex
+    public_key: Option<String>,
+    /// Fetch the public key of this key from the nodes instead, which needs credentials
+    key_file: Option<String>,
+    path: Option<String>,
+    #[serde(flatten)]
+    message: Message,
+}
+
+async fn verify(
+    State(client): State<Client>,
+    headers: HeaderMap,
+    Json(request): Json<VerifyRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let digest = request.message.digest()?;
+
+    let (public_key, nodes) = match (&request.public_key, &request.key_file) {
+        (Some(public_key), None) => {
+            let bytes =
+                hex::decode(public_key).map_err(|e| ApiError::bad_request(format!("invalid public key: {}", e)))?;
+            let public_key = Point::<Secp256k1>::from_bytes(&bytes)
+                .map_err(|e| ApiError::bad_request(format!("invalid public key: {}", e)))?;
+            (public_key, None)
+        }
+        (None, Some(key_file)) => {
+            let creds = require_credentials(&headers)?;
+            let path = derivation_path(request.path.as_deref())?;
+            let reply = client.xpub(&creds, key_file, &path).await?;
+            (reply.value.public_key, Some(reply.nodes))
+        }
+        _ => return Err(ApiError::bad_request("exactly one of public_key and key_file is required")),
+    };
+
+    let body = json!({ "valid": verify_signature(&public_key, &digest, &request.signature) });
+    match nodes {
+        Some(nodes) => Ok(with_nodes(body, &nodes)),
+        None => Ok(Json(body)),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let client = SigningClient::connect(ClientConfig::load()?).await?;
+    let app = Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/login", post(login))
+        .route("/users", post(register))
+        .route("/keys", get(list_keys).post(keygen))
+        .route("/keys/:key_file/pubkey", get(pubkey))
+        .route("/keys/:key_file/sign", post(sign))
+        .route("/verify", post(verify))
+        .layer(middleware::from_fn_with_state(Arc::new(ClientThrottle::default()), throttle_clients))
+        .with_state(Arc::new(client));
+
+    println!("Serving REST on {}", args.listen);
+    axum::Server::bind(&args.listen).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
@@ -0,0 +1,399 @@
+//! JSON-RPC gateway that lets Ethereum tooling use the signing cluster as a remote signer.
+//!
+//! Serves `eth_accounts`, `eth_sign`, `personal_sign`, `eth_signTransaction` and
+//! `eth_signTypedData_v4`. Callers authenticate as a user of the cluster with HTTP basic auth,
+//! and give their one-time code in the `X-Signing-Totp` header if they enrolled one. Every call is
+//! forwarded to the nodes on behalf of that user, so the nodes enforce roles and key ownership.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use clap::Parser;
+use serde_json::{json, Value};
+use signing::eth::{self, Account, Transaction};
+use signing::hd::DerivationPath;
+use signing::{ClientConfig, Credentials, FailureKind, NodeStatus, SigningClient};
+
+mod auth;
+#[allow(dead_code)]
+#[path = "../server/logging.rs"]
+mod logging;
+
+use auth::{ClientThrottle, Login};
+use logging::{Format, Level};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Failures of the cluster, such as a failed ceremony
+const SERVER_ERROR: i64 = -32000;
+/// The nodes rejected the credentials of the caller or are throttling its user
+const LOGIN_REFUSED: i64 = -32001;
+
+/// How long the accounts of a user are reused before they are listed again
+const ACCOUNTS_TTL: Duration = Duration::from_secs(60);
+
+/// Methods that run a signing ceremony and use up the one-time code of the caller
+const SIGNING_METHODS: [&str; 4] = ["eth_sign", "personal_sign", "eth_signTransaction", "eth_signTypedData_v4"];
+
+/// JSON-RPC gateway to a DOTS threshold signing cluster
+#[derive(Parser)]
+#[command(name = "rpc_gateway", version)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8545")]
+    listen: SocketAddr,
+    /// Comma-separated BIP32 paths whose accounts are offered for every key, such as m,m/0/1
+    #[arg(long, value_delimiter = ',', default_value = "m")]
+    paths: Vec<DerivationPath>,
+    /// Comma-separated parties that sign, by default the first t + 1 parties of the key
+    #[arg(long, value_delimiter = ',')]
+    active_parties: Option<Vec<u16>>,
+    /// Least severe level of the events that are logged
+    #[arg(long, value_enum, default_value = "info")]
+    log_level: Level,
+    /// Format of the logs, which are written to stderr one event per line
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: Format,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError { code: INVALID_PARAMS, message: message.into() }
+    }
+}
+
+/// Whether the nodes refused a request because of the credentials of its user
+fn login_refused(e: &signing::Error) -> bool {
+    match e {
+        signing::Error::Quorum { nodes, .. } => nodes.0.iter().any(|result| {
+            matches!(
+                result.status,
+                NodeStatus::Failed { kind: FailureKind::Unauthenticated | FailureKind::Throttled, .. }
+            )
+        }),
+        _ => false,
+    }
+}
+
+impl From<signing::Error> for RpcError {
+    fn from(e: signing::Error) -> Self {
+        match e {
+            signing::Error::InvalidRequest(message) => RpcError::invalid_params(message),
+            e if login_refused(&e) => RpcError { code: LOGIN_REFUSED, message: e.to_string() },
+            e => RpcError { code: SERVER_ERROR, message: e.to_string() },
+        }
+    }
+}
+
+struct Gateway {
+    client: SigningClient,
+    paths: Vec<DerivationPath>,
+    active_parties: Option<Vec<u16>>,
+    throttle: Arc<ClientThrottle>,
+    /// Accounts of each user and when they were listed
+    accounts: Mutex<HashMap<String, (Instant, Vec<Account>)>>,
+}
+
+fn param<'a>(params: &'a [Value], index: usize, name: &str) -> Result<&'a Value, RpcError> {
+    params
+        .get(index)
+        .filter(|param| !param.is_null())
+        .ok_or_else(|| RpcError::invalid_params(format!("missing parameter {}", name)))
+}
+
+fn param_str<'a>(params: &'a [Value], index: usize, name: &str) -> Result<&'a str, RpcError> {
+    param(params, index, name)?
+        .as_str()
+        .ok_or_else(|| RpcError::invalid_params(format!("{} must be a string", name)))
+}
+
+/// Decodes the data of `eth_sign` and `personal_sign`, which is hex, although some wallets also
+/// accept plain text. Data that starts with `0x` must be valid hex, so that a mangled hex message
+/// is refused rather than signed as text.
+fn message_bytes(data: &str) -> Result<Vec<u8>, RpcError> {
+    match data.strip_prefix("0x") {
+        Some(hex) => hex::decode(hex).map_err(|e| RpcError::invalid_params(format!("data is not valid hex: {}", e))),
+        None => Ok(data.as_bytes().to_vec()),
+    }
+}
+
+impl Gateway {
+    /// Lists the accounts of the caller on the nodes and caches them
+    async fn list_accounts(&self, creds: &Credentials) -> Result<Vec<Account>, RpcError> {
+        let accounts = self.client.eth_accounts(creds, &self.paths).await?.value;
+        let mut cache = self.accounts.lock().unwrap();
+        cache.retain(|_, (listed, _)| listed.elapsed() < ACCOUNTS_TTL);
+        cache.insert(creds.username.clone(), (Instant::now(), accounts.clone()));
+        Ok(accounts)
+    }
+
+    /// Finds the account of `address` among the accounts of the caller. Accounts listed in the
+    /// last minute are reused, so that signing calls do not ask every node for its keys first. An
+    /// address that is not cached is always looked up on the nodes, which check the credentials,
+    /// and the signing call that follows a cache hit is checked by the nodes anyway.
+    async fn account(&self, creds: &Credentials, address: &str) -> Result<Account, RpcError> {
+        let address = eth::parse_address(address)?;
+        let cached = self.accounts.lock().unwrap().get(&creds.username).and_then(|(listed, accounts)| {
+            let fresh = listed.elapsed() < ACCOUNTS_TTL;
+            accounts.iter().find(|account| fresh && account.address == address).cloned()
+        });
+        if let Some(account) = cached {
+            return Ok(account);
+        }
+        self.list_accounts(creds)
+            .await?
+            .into_iter()
+            .find(|account| account.address == address)
+            .ok_or_else(|| RpcError::invalid_params(format!("unknown account {}", eth::checksum_address(&address))))
+    }
+
+    /// Drops the cached accounts of `username`, whose keys may have changed
+    fn forget_accounts(&self, username: &str) {
+        self.accounts.lock().unwrap().remove(username);
+    }
+
+    fn active_parties(&self, account: &Account) -> Vec<u16> {
+        match &self.active_parties {
+            Some(active_parties) => active_parties.clone(),
+            None => (1..=account.threshold + 1).collect(),
+        }
+    }
+
+    async fn sign_message(&self, creds: &Credentials, address: &str, data: &str) -> Result<Value, RpcError> {
+        let account = self.account(creds, address).await?;
+        let signature = self
+            .client
+            .sign_personal_message(
+                creds,
+                &account.key_file,
+                Some(&account.path),
+                account.threshold,
+                &self.active_parties(&account),
+                &message_bytes(data)?,
+            )
+            .await?
+            .value;
+        Ok(json!(format!("0x{}", hex::encode(signature))))
+    }
+
+    async fn dispatch(&self, creds: &Credentials, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "eth_accounts" => {
+                let accounts = self.list_accounts(creds).await?;
+                let addresses: Vec<String> =
+                    accounts.iter().map(|account| eth::checksum_address(&account.address)).collect();
+                Ok(json!(addresses))
+            }
+            "eth_sign" => {
+                let address = param_str(params, 0, "address")?;
+                self.sign_message(creds, address, param_str(params, 1, "data")?).await
+            }
+            "personal_sign" => {
+                let address = param_str(params, 1, "address")?;
+                self.sign_message(creds, address, param_str(params, 0, "data")?).await
+            }
+            "eth_signTransaction" => {
+                let tx = param(params, 0, "transaction")?;
+                let from = tx["from"]
+                    .as_str()
+                    .ok_or_else(|| RpcError::invalid_params("transaction has no from address"))?;
+                let account = self.account(creds, from).await?;
+                let transaction = Transaction::from_json(tx)?;
+
+                let signed = self
+                    .client
+                    .sign_eth_transaction(
+                        creds,
+                        &account.key_file,
+                        Some(&account.path),
+                        account.threshold,
+                        &self.active_parties(&account),
+                        &transaction,
+                    )
+                    .await?
+                    .value;
+                Ok(json!(format!("0x{}", hex::encode(signed.raw))))
+            }
+            "eth_signTypedData_v4" => {
+                let account = self.account(creds, param_str(params, 0, "address")?).await?;
+                // Wallets pass the document as a JSON string, some libraries as an object
+                let typed_data = match param(params, 1, "typed data")? {
+                    Value::String(document) => serde_json::from_str(document)
+                        .map_err(|e| RpcError::invalid_params(format!("typed data is not JSON: {}", e)))?,
+                    document => document.clone(),
+                };
+
+                let signature = self
+                    .client
+                    .sign_typed_data(
+                        creds,
+                        &account.key_file,
+                        Some(&account.path),
+                        account.threshold,
+                        &self.active_parties(&account),
+                        &typed_data,
+                    )
+                    .await?
+                    .value;
+                Ok(json!(format!("0x{}", hex::encode(signature))))
+            }
+            _ => Err(RpcError { code: METHOD_NOT_FOUND, message: format!("method {} is not supported", method) }),
+        }
+    }
+
+    /// Answers one JSON-RPC call, or returns None if the call is a notification, which has no id
+    /// and gets no response. Raises `login` to what the nodes made of the credentials.
+    async fn call(&self, client: SocketAddr, creds: &Credentials, call: &Value, login: &mut Login) -> Option<Value> {
+        let result = match (call["jsonrpc"].as_str(), call["method"].as_str()) {
+            (Some("2.0"), Some(method)) => {
+                let params = call["params"].as_array().map(Vec::as_slice).unwrap_or_default();
+                self.dispatch(creds, method, params).await
+            }
+            _ => Err(RpcError { code: INVALID_REQUEST, message: "not a JSON-RPC 2.0 call".to_owned() }),
+        };
+        let outcome = match &result {
+            Ok(_) => Login::Accepted,
+            Err(e) if e.code == LOGIN_REFUSED => Login::Refused,
+            Err(_) => Login::Unchecked,
+        };
+        *login = (*login).max(outcome);
+        if let Err(e) = &result {
+            if is_signing_call(call) {
+                self.forget_accounts(&creds.username);
+            }
+            let fields = [
+                ("client", json!(client.ip().to_string())),
+                ("username", json!(creds.username)),
+                ("method", call["method"].clone()),
+                ("code", json!(e.code)),
+            ];
+            logging::event(Level::Warn, &fields, format_args!("Call failed: {}", e.message));
+        }
+        respond(call, result)
+    }
+}
+
+/// Builds the response to `call`, or None if the call is a notification
+fn respond(call: &Value, result: Result<Value, RpcError>) -> Option<Value> {
+    let id = match call.get("id") {
+        Some(id) => id.clone(),
+        None if call.is_object() => return None,
+        None => Value::Null,
+    };
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } }),
+    })
+}
+
+fn is_signing_call(call: &Value) -> bool {
+    call["method"].as_str().map_or(false, |method| SIGNING_METHODS.contains(&method))
+}
+
+async fn rpc(
+    State(gateway): State<Arc<Gateway>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let creds = match auth::credentials(&headers) {
+        Some(creds) => creds,
+        None => return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, auth::CHALLENGE)]).into_response(),
+    };
+    let mut admission = match gateway.throttle.admit(address.ip()) {
+        Ok(admission) => admission,
+        Err(wait) => {
+            let fields = [("client", json!(address.ip().to_string())), ("username", json!(creds.username))];
+            logging::event(Level::Warn, &fields, format_args!("Client throttled for {} seconds", wait));
+            let message = format!("too many failed logins from {}, retry in {} seconds", address.ip(), wait);
+            let error = json!({ "code": LOGIN_REFUSED, "message": message });
+            let response = Json(json!({ "jsonrpc": "2.0", "id": null, "error": error }));
+            return (StatusCode::TOO_MANY_REQUESTS, response).into_response();
+        }
+    };
+
+    let request: Value = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = json!({ "code": PARSE_ERROR, "message": e.to_string() });
+            return Json(json!({ "jsonrpc": "2.0", "id": null, "error": error })).into_response();
+        }
+    };
+
+    // The calls of a batch are answered one after the other, since the nodes run one ceremony
+    // at a time anyway. They all carry the same one-time code, which the nodes accept only once,
+    // so a batch may hold at most one signing call.
+    let response = match &request {
+        Value::Array(calls) if calls.is_empty() => {
+            let error = RpcError { code: INVALID_REQUEST, message: "empty batch".to_owned() };
+            respond(&Value::Null, Err(error))
+        }
+        Value::Array(calls) if calls.iter().filter(|call| is_signing_call(call)).count() > 1 => {
+            let message = "a batch may hold at most one signing call, since they share one one-time code";
+            let responses: Vec<Value> = calls
+                .iter()
+                .filter_map(|call| respond(call, Err(RpcError { code: INVALID_REQUEST, message: message.to_owned() })))
+                .collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Value::Array(calls) => {
+            let mut responses = vec![];
+            for call in calls {
+                responses.extend(gateway.call(address, &creds, call, &mut admission.login).await);
+            }
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        call => gateway.call(address, &creds, call, &mut admission.login).await,
+    };
+    match response {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    logging::init(args.log_level, args.log_format, None);
+
+    let gateway = Gateway {
+        client: SigningClient::connect(ClientConfig::load()?).await?,
+        paths: args.paths,
+        active_parties: args.active_parties,
+        throttle: Arc::new(ClientThrottle::default()),
+        accounts: Mutex::new(HashMap::new()),
+    };
+    let app = Router::new().route("/", post(rpc)).with_state(Arc::new(gateway));
+
+    logging::info(format_args!("Serving JSON-RPC on {}", args.listen));
+    axum::Server::bind(&args.listen).serve(app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_in_hex_must_be_valid() {
+        assert_eq!(message_bytes("0x68656c6c6f").ok(), Some(b"hello".to_vec()));
+        assert_eq!(message_bytes("hello").ok(), Some(b"hello".to_vec()));
+        assert_eq!(message_bytes("0xhello").err().map(|e| e.code), Some(INVALID_PARAMS));
+        assert_eq!(message_bytes("0x123").err().map(|e| e.code), Some(INVALID_PARAMS));
+    }
+}
@@ -1,4 +1,4 @@
-use curv::{arithmetic::Converter, elliptic::curves::Secp256k1, BigInt};
+use curv::{arithmetic::Converter, elliptic::curves::{Point, Secp256k1}, BigInt};
 use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::{
     keygen::{Keygen, LocalKey, ProtocolMessage},
     sign::{
@@ -12,17 +12,32 @@ use serde_json::{Value, json};
 use std::error::Error;
 use std::fs;
 use std::io::{self, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::thread;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::collections::HashMap;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use libdots::env::Env;
-use libdots::request::Request;
+use signing::hd::{DerivationPath, ExtendedPublicKey};
+use signing::{FailureKind, Preimage};
+
+mod audit;
+mod backup;
+mod config;
+mod jobs;
+mod keys;
+mod logging;
+mod metrics;
+mod recovery;
+mod requests;
+mod totp;
+mod users;
+
+use config::Config;
+use requests::{Caller, Claim};
+use users::{authenticate_user, login_refused, register_user, LoginRefused, Permission, Role};
 
 const PROTOCOL_MSG_SIZE: usize = 18000;
-const USER_DATA: &str = "users.json";
 
 /// This party receives incoming messages in present round of the keygen protocol
 ///
@@ -42,12 +57,12 @@ fn receive_keygen(
         if recipient != sender {
             let mut result_buf = [0; PROTOCOL_MSG_SIZE];
             libdots::msg::recv(&mut result_buf, sender as usize - 1, 0)?;
+            metrics::received(&result_buf);
 
             // Deserialize message
             let received_msg = serde_json::from_str::<Msg<ProtocolMessage>>(
                 &String::from_utf8_lossy(&result_buf).trim_matches(char::from(0)),
-            )
-            .unwrap();
+            )?;
 
             // Process received broadcast message
             party
@@ -76,12 +91,12 @@ fn receive_sign(
         if recipient != *sender as usize {
             let mut result_buf = [0; PROTOCOL_MSG_SIZE];
             libdots::msg::recv(&mut result_buf, *sender as usize - 1, 0)?;
+            metrics::received(&result_buf);
 
             // Deserialize message
             let received_msg = serde_json::from_str::<Msg<OfflineProtocolMessage>>(
                 &String::from_utf8_lossy(&result_buf).trim_matches(char::from(0)),
-            )
-            .unwrap();
+            )?;
             // Process received broadcast message
             party
                 .handle_incoming(received_msg)
@@ -116,6 +131,7 @@ fn broadcast_keygen(
         if recipient != sender {
             // Send message to recipient
             libdots::msg::send(serialized.as_bytes(), recipient as usize - 1, 0)?;
+            metrics::sent(serialized.len());
         }
     }
     receive_keygen(num_parties, party, party_index)?;
@@ -148,6 +164,7 @@ fn broadcast_sign(
         if *recipient != sender as u16 {
             // Send message to recipient
             libdots::msg::send(serialized.as_bytes(), *recipient as usize - 1, 0)?;
+            metrics::sent(serialized.len());
         }
     }
     receive_sign(party, party_index, &active_parties)?;
@@ -175,6 +192,7 @@ fn p2p_keygen(
         // Send to intended recipient
         let recipient = msg.receiver.unwrap();
         libdots::msg::send(serialized.as_bytes(), recipient as usize - 1, 0)?;
+        metrics::sent(serialized.len());
     }
 
     receive_keygen(num_parties, party, party_index)?;
@@ -202,6 +220,7 @@ fn p2p_sign(
         // Send to intended recipient
         let recipient = msg.receiver.unwrap();
         libdots::msg::send(serialized.as_bytes(), recipient as usize - 1, 0)?;
+        metrics::sent(serialized.len());
     }
 
     receive_sign(party, party_index, active_parties)?;
@@ -235,6 +254,7 @@ fn sign_message(
         if *recipient != sender {
             // Send message to recipient
             libdots::msg::send(serialized.as_bytes(), *recipient as usize - 1, 0)?;
+            metrics::sent(serialized.len());
         }
     }
 
@@ -245,20 +265,22 @@ fn sign_message(
         if recipient != *sender {
             let mut result_buf = [0u8; PROTOCOL_MSG_SIZE];
             libdots::msg::recv(&mut result_buf, *sender as usize - 1, 0)?;
+            metrics::received(&result_buf);
 
             // Deserialize message
             let received_share = serde_json::from_str::<PartialSignature>(
                 &String::from_utf8_lossy(&result_buf).trim_matches(char::from(0)),
-            )
-            .unwrap();
+            )?;
 
             // Process received broadcast message
             other_partial_shares.push(received_share);
         }
     }
 
-    let signature = manual_sign.complete(&other_partial_shares).unwrap();
-    println!("Signature: {:?}", serde_json::to_string(&signature).unwrap());
+    let signature = manual_sign.complete(&other_partial_shares).map_err(|e| {
+        io::Error::new(ErrorKind::InvalidData, format!("invalid partial signatures: {:?}", e))
+    })?;
+    logging::info("Signature complete");
     Ok(serde_json::to_vec_pretty(&signature).map_err(|e| io::Error::new(ErrorKind::Other, e))?)
 }
 
@@ -278,18 +300,21 @@ fn keygen(
     let mut party = Keygen::new(party_index, num_threshold, num_parties).unwrap();
 
     // Round 1
+    jobs::round(1);
     party
         .proceed()
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
     broadcast_keygen(0, num_parties, &mut party, party_index)?;
 
     // Round 2
+    jobs::round(2);
     broadcast_keygen(1, num_parties, &mut party, party_index)?;
     party
         .proceed()
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 3
+    jobs::round(3);
     let mut msg_queue = vec![];
     for i in 0..num_parties - 1 {
         let msg_index = (i + 2) as usize;
@@ -301,6 +326,8 @@ fn keygen(
         .proceed()
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
+    // Round 4
+    jobs::round(4);
     broadcast_keygen((num_parties + 1) as usize, num_parties, &mut party, party_index)?;
     party
         .proceed()
@@ -319,16 +346,16 @@ fn keygen(
 /// * `active_parties` - Parties participating in producing the signature
 /// * `key` - Local key share of current party generated in the keygen phase of the protocol
 /// * `party_index` - Index of current party
-/// * `message` - Message that must be signed
+/// * `digest` - Digest of the message that must be signed
 fn sign(
     num_threshold: u16,
     active_parties: &Vec<u16>,
     key: LocalKey<Secp256k1>,
     party_index: u16,
-    message: String,
+    digest: &[u8],
 ) -> Result<Vec<u8>, Box<dyn Error>> {
     if !active_parties.contains(&party_index) {
-        println!("Party {:?} is not needed in this signature generation.", party_index);
+        logging::info(format_args!("Party {} is not needed in this signature generation", party_index));
         return Ok(Vec::new());
     }
     // Initiate offline phase
@@ -338,12 +365,14 @@ fn sign(
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 1
+    jobs::round(1);
     broadcast_sign(0, &mut offline_stage, party_index, &active_parties)?;
     offline_stage
         .proceed()
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 2
+    jobs::round(2);
     let mut msg_queue = vec![];
     for i in 0..num_threshold {
         let msg_index = (i + 1) as usize;
@@ -360,6 +389,7 @@ fn sign(
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 3
+    jobs::round(3);
     broadcast_sign(
         (num_threshold + 1) as usize,
         &mut offline_stage,
@@ -371,6 +401,7 @@ fn sign(
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 4
+    jobs::round(4);
     broadcast_sign(
         (num_threshold + 2) as usize,
         &mut offline_stage,
@@ -382,6 +413,7 @@ fn sign(
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 5
+    jobs::round(5);
     broadcast_sign(
         (num_threshold + 3) as usize,
         &mut offline_stage,
@@ -393,6 +425,7 @@ fn sign(
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
     // Round 6
+    jobs::round(6);
     broadcast_sign(
         (num_threshold + 4) as usize,
         &mut offline_stage,
@@ -403,8 +436,9 @@ fn sign(
         .proceed()
         .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
 
-    // Sign message
-    let message_int = BigInt::from_bytes(&message.as_bytes());
+    // Sign message digest
+    jobs::round(7);
+    let message_int = BigInt::from_bytes(digest);
     let offline_output = offline_stage.pick_output().unwrap().unwrap();
     sign_message(
         message_int,
@@ -414,142 +448,827 @@ fn sign(
     )
 }
 
-fn register_user(username: &str, password: &str) -> std::io::Result<()> {
-    // Open file in read mode first to check existing users
-    let mut file = OpenOptions::new().read(true).write(true).create(true).open(USER_DATA)?;
+/// Reads a required string parameter from the request parameters
+fn param_str<'a>(params: &'a Value, name: &str) -> io::Result<&'a str> {
+    params[name].as_str().ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput, format!("missing parameter {}", name))
+    })
+}
 
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+/// Reads a required party count, index or threshold from the request parameters
+fn param_u16(params: &Value, name: &str) -> io::Result<u16> {
+    let value = params[name].as_u64().ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput, format!("missing parameter {}", name))
+    })?;
+    u16::try_from(value).map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("{} is out of range", name)))
+}
 
-    let mut data: HashMap<String, String> = match serde_json::from_str(&contents) {
-        Ok(json) => json,
-        Err(_) => HashMap::new(), // If parsing fails, use an empty HashMap
-    };
+/// Exchanges the outcome of the checks this party ran before a ceremony with the other `parties`,
+/// so that either all of them start the ceremony or none does. Checks that depend on the node's
+/// own state, such as its users, key files and clock, may pass on some nodes only, and a party
+/// that stayed out would leave the others waiting for its messages. Parties outside `parties`
+/// return their own outcome.
+fn agree<T>(checks: Result<T, Box<dyn Error>>, parties: &[u16], party_index: u16) -> Result<T, Box<dyn Error>> {
+    if !parties.contains(&party_index) {
+        return checks;
+    }
+    let outcome: Result<(), String> = checks.as_ref().map(|_| ()).map_err(|e| e.to_string());
+    let serialized = serde_json::to_string(&outcome)?;
+    let others: Vec<u16> = parties.iter().copied().filter(|party| *party != party_index).collect();
+    for party in &others {
+        libdots::msg::send(serialized.as_bytes(), *party as usize - 1, 0)?;
+        metrics::sent(serialized.len());
+    }
 
-    // Check if the user is already registered
-    if data.contains_key(username) {
-        println!("User {} already exists", username);
-        return Ok(());
+    let mut refusals = vec![];
+    for party in &others {
+        let mut result_buf = [0; PROTOCOL_MSG_SIZE];
+        libdots::msg::recv(&mut result_buf, *party as usize - 1, 0)?;
+        metrics::received(&result_buf);
+        let outcome: Result<(), String> =
+            serde_json::from_str(String::from_utf8_lossy(&result_buf).trim_matches(char::from(0)))?;
+        if let Err(reason) = outcome {
+            refusals.push(format!("party {} refused: {}", party, reason));
+        }
     }
 
-    // Register a new user
-    let hashed_password = hash(password, DEFAULT_COST).unwrap();
-    data.insert(username.to_string(), hashed_password);
+    let value = checks?;
+    if !refusals.is_empty() {
+        return Err(io::Error::new(ErrorKind::Other, refusals.join("; ")).into());
+    }
+    Ok(value)
+}
+
+/// Reads the hex-encoded 32-byte digest to sign from the request parameters. Messages are hashed
+/// by the client, so that nodes never see the signed data itself.
+fn param_digest(params: &Value) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, "digest must be 32 hex-encoded bytes");
+    let digest = hex::decode(param_str(params, "digest")?).map_err(|_| invalid())?;
+    if digest.len() != 32 {
+        return Err(invalid());
+    }
+    Ok(digest)
+}
 
-    // Open file in write mode now to overwrite it with updated data
-    let mut file = OpenOptions::new().write(true).open(USER_DATA)?;
+/// Returns the digest to sign, after checking that it is the hash of the preimage if the request
+/// carries one, so that the decoded preimage describes what is actually signed
+fn signing_digest(params: &Value) -> Result<Vec<u8>, Box<dyn Error>> {
+    let digest = param_digest(params)?;
+    if params["preimage"].is_null() {
+        return Ok(digest);
+    }
 
-    // Write the updated contents
-    file.write_all(serde_json::to_string(&data)?.as_bytes())?;
+    let preimage: Preimage = serde_json::from_value(params["preimage"].clone())?;
+    if preimage.digest()?[..] != digest[..] {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "digest is not the hash of the preimage").into());
+    }
+    logging::info(format_args!("Signing {}", preimage.decode()?));
+    Ok(digest)
+}
 
-    println!("User {} registered", username);
+/// Fails unless the `username` of the request exists and its `password` is correct, returning the role of the user
+fn require_user(config: &Config, params: &Value) -> io::Result<Role> {
+    let username = param_str(params, "username")?;
+    let password = param_str(params, "password")?;
 
-    Ok(())
+    let role = authenticate_user(username, password, &config.lockout).map_err(|e| {
+        if e.kind() == ErrorKind::PermissionDenied {
+            metrics::auth_failure("throttled");
+        }
+        e
+    })?;
+    match role {
+        Some(role) => {
+            logging::debug(format_args!("User {} authenticated", username));
+            Ok(role)
+        }
+        None => {
+            logging::warn(format_args!("Failed to authenticate user {}", username));
+            metrics::auth_failure("password");
+            Err(login_refused(
+                FailureKind::Unauthenticated,
+                format!("failed to authenticate user {}", username),
+            ))
+        }
+    }
 }
 
+/// Classifies the error a request failed with for the reply, so that gateways can tell rejected
+/// logins and missing permissions apart without parsing the message
+fn failure_kind(e: &(dyn Error + 'static)) -> FailureKind {
+    let e = match e.downcast_ref::<io::Error>() {
+        Some(e) => e,
+        None => return FailureKind::Other,
+    };
+    if let Some(refused) = e.get_ref().and_then(|inner| inner.downcast_ref::<LoginRefused>()) {
+        return refused.kind;
+    }
+    match e.kind() {
+        ErrorKind::PermissionDenied => FailureKind::Forbidden,
+        ErrorKind::AlreadyExists => FailureKind::Conflict,
+        _ => FailureKind::Other,
+    }
+}
 
-fn authenticate_user(username: &str, password: &str) -> io::Result<bool> {
-    // Open file in read-only mode
-    let mut file = File::open(USER_DATA)?;
+fn permission_denied(username: &str, func_name: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        format!("user {} may not call {}", username, func_name),
+    )
+}
 
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+/// Authenticates the user of the request and fails unless its role grants `permission`
+fn require_permission(
+    config: &Config,
+    params: &Value,
+    func_name: &str,
+    permission: Permission,
+) -> io::Result<Role> {
+    let role = require_user(config, params)?;
+    if !role.allows(permission) {
+        return Err(permission_denied(param_str(params, "username")?, func_name));
+    }
+    Ok(role)
+}
 
-    let data: HashMap<String, String> = match serde_json::from_str(&contents) {
-        Ok(json) => json,
-        Err(_) => HashMap::new(), // If parsing fails, use an empty HashMap
+/// Verifies the one-time code of the request if the user enrolled a TOTP second factor
+fn require_second_factor(config: &Config, params: &Value) -> io::Result<()> {
+    users::verify_second_factor(param_str(params, "username")?, params["totp"].as_str(), &config.lockout).map_err(|e| {
+        if e.kind() == ErrorKind::PermissionDenied {
+            metrics::auth_failure("second_factor");
+        }
+        e
+    })
+}
+
+/// Checks that the user may sign with the key named in the request, returning its key file
+fn authorize_signing<'a>(config: &Config, params: &'a Value, func_name: &str) -> Result<&'a str, Box<dyn Error>> {
+    let role = require_permission(config, params, func_name, Permission::Sign)?;
+    require_second_factor(config, params)?;
+
+    // Keys without a recorded owner can only be used by users who may manage every key
+    let username = param_str(params, "username")?;
+    let key_file = param_str(params, "key_file")?;
+    let allowed = match keys::owner_of(key_file)?.filter(|owner| !owner.is_empty()) {
+        Some(owner) => owner == username,
+        None => role.allows(Permission::ManageKeys),
     };
+    if !allowed {
+        return Err(permission_denied(username, func_name).into());
+    }
+    Ok(key_file)
+}
 
-    // Check if the user exists and the password matches
-    Ok(data.get(username).map_or(false, |p| verify(password, p).unwrap()))
+/// Checks that `username` may delete or replace the key share stored in `key_file`, whose registry
+/// entry is `record`. Owners may remove their keys and users who manage keys any key, but only
+/// files that the registry records as key shares, so that no request reaches other files of the node.
+fn authorize_key_removal(
+    username: &str,
+    role: Role,
+    func_name: &str,
+    key_file: &str,
+    record: Option<&keys::KeyRecord>,
+) -> io::Result<()> {
+    let record = record.ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, format!("{} is not a key share recorded by this node", key_file))
+    })?;
+    if record.owner != username && !role.allows(Permission::ManageKeys) {
+        return Err(permission_denied(username, func_name));
+    }
+    Ok(())
 }
 
-fn handle_request(env: &Env, req: &Request) -> Result<(), Box<dyn Error>> {
+/// Checks that a retry answered from its first run comes from `caller`, the user who sent the
+/// first run. The first run went through the checks of its function, so the retry only has to
+/// authenticate as the same user, with the password that user had back then.
+fn authorize_retry(config: &Config, params: &Value, caller: Option<&Caller>) -> Result<(), Box<dyn Error>> {
+    let username = param_str(params, "username")?;
+    let password = param_str(params, "password")?;
+
+    let refused = || login_refused(FailureKind::Unauthenticated, format!("failed to authenticate user {}", username));
+    let caller = caller.filter(|caller| caller.username == username).ok_or_else(refused)?;
+    let verified = users::verify_retry(username, password, &caller.password_hash, &config.lockout).map_err(|e| {
+        if e.kind() == ErrorKind::PermissionDenied {
+            metrics::auth_failure("throttled");
+        }
+        e
+    })?;
+    if !verified {
+        logging::warn(format_args!("Failed to authenticate user {} for a retry", username));
+        metrics::auth_failure("password");
+        return Err(refused().into());
+    }
+    Ok(())
+}
+
+/// Turns `key` into the share of its BIP32 child at `path`. Every node adds the same tweak to its
+/// share, which shifts the shared secret by the tweak, and moves every public share and the joint
+/// public key by the tweak times the generator.
+fn derive_child_key(key: &mut LocalKey<Secp256k1>, path: &DerivationPath) -> Result<(), Box<dyn Error>> {
+    let (_, tweak) = ExtendedPublicKey::master(&key.public_key()).derive(path)?;
+    let tweak_point = Point::generator() * &tweak;
+
+    key.keys_linear.x_i = &key.keys_linear.x_i + &tweak;
+    key.keys_linear.y = &key.keys_linear.y + &tweak_point;
+    key.y_sum_s = &key.y_sum_s + &tweak_point;
+    for public_share in key.pk_vec.iter_mut() {
+        *public_share = &*public_share + &tweak_point;
+    }
+    key.vss_scheme.commitments[0] = &key.vss_scheme.commitments[0] + &tweak_point;
+    Ok(())
+}
+
+fn handle_request(env: &Env, config: &Config, func_name: &str, args: &[Vec<u8>]) -> Result<Value, Box<dyn Error>> {
     let rank = env.get_world_rank();
-    let num_parties = env.get_world_size();
-    let func_name = &req.func_name;
-    let args = &req.args;
 
     let party_index = (rank + 1) as u16;
     let params: Value = serde_json::from_slice(&args[0])?;
 
-    println!("rank {} starting", rank);
+    let username = param_str(&params, "username")?;
 
-    let username = params["username"].as_str().unwrap();
-    let password = params["password"].as_str().unwrap();
-
-    match &func_name[..] {
+    match func_name {
         "register" => {
-            println!("Register user");
-            if let Err(e) = register_user(username, password) {
-                eprintln!("Failed to register user: {}", e);
+            require_permission(config, &params, func_name, Permission::ManageUsers)?;
+
+            let new_username = param_str(&params, "new_username")?;
+            let role = match params["role"].as_str() {
+                Some(role) => role.parse()?,
+                None => Role::default(),
+            };
+            register_user(
+                new_username,
+                param_str(&params, "new_password")?,
+                role,
+                params["totp_secret"].as_str(),
+            )?;
+            logging::info(format_args!("User {} registered as {}", new_username, role));
+
+            Ok(json!({ "username": new_username, "role": role }))
+        },
+        "unlock_user" => {
+            require_permission(config, &params, func_name, Permission::ManageUsers)?;
+
+            let target = param_str(&params, "target")?;
+            users::unlock(target)?;
+            logging::info(format_args!("User {} unlocked", target));
+
+            Ok(json!({ "username": target }))
+        },
+        "set_role" => {
+            require_permission(config, &params, func_name, Permission::ManageUsers)?;
+
+            let target = param_str(&params, "target")?;
+            let role: Role = param_str(&params, "role")?.parse()?;
+            users::set_role(target, role)?;
+            logging::info(format_args!("User {} is now {}", target, role));
+
+            Ok(json!({ "username": target, "role": role }))
+        },
+        "change_password" => {
+            // A stolen password alone must not be enough to take over an account with a second factor
+            require_user(config, &params)?;
+            require_second_factor(config, &params)?;
+
+            users::change_password(username, param_str(&params, "new_password")?)?;
+            logging::info(format_args!("Password of user {} changed", username));
+
+            Ok(json!({ "username": username }))
+        },
+        "delete_user" => {
+            let role = require_user(config, &params)?;
+
+            // Users may delete themselves, administrators may delete anyone
+            let target = params["target"].as_str().unwrap_or(username);
+            if target != username && !role.allows(Permission::ManageUsers) {
+                return Err(permission_denied(username, func_name).into());
+            }
+            if !users::user_exists(target)? {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("user {} does not exist", target),
+                ).into());
             }
+            users::ensure_admin_remains(target)?;
+
+            // Keys must not be left without an owner, so they are handed over to another user
+            let owned_keys = keys::keys_owned_by(target)?;
+            let mut transferred_keys = vec![];
+            if !owned_keys.is_empty() {
+                let new_owner = params["transfer_to"].as_str().ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "user {} still owns keys {:?}, pass transfer_to to hand them over",
+                            target, owned_keys
+                        ),
+                    )
+                })?;
+                if new_owner == target || !users::user_exists(new_owner)? {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("cannot transfer keys to {}", new_owner),
+                    ).into());
+                }
+                transferred_keys = keys::transfer_keys(target, new_owner)?;
+            }
+
+            users::delete_user(target)?;
+            logging::info(format_args!("User {} deleted", target));
+
+            Ok(json!({ "username": target, "transferred_keys": transferred_keys }))
+        },
+        "job_status" => {
+            let role = require_user(config, &params)?;
+
+            let job = jobs::status(param_str(&params, "job")?)?;
+            if job.username != username && !role.allows(Permission::ViewKeys) {
+                return Err(permission_denied(username, func_name).into());
+            }
+            Ok(json!({ "job": job }))
+        },
+        "login" => {
+            let role = require_user(config, &params)?;
+            require_second_factor(config, &params)?;
 
-            Ok(())
+            Ok(json!({ "user": { "username": username, "role": role.to_string() } }))
+        },
+        "list_users" => {
+            require_permission(config, &params, func_name, Permission::ViewUsers)?;
+
+            let users: Vec<Value> = users::list_users()?
+                .into_iter()
+                .map(|(username, role)| json!({ "username": username, "role": role }))
+                .collect();
+            Ok(json!({ "users": users }))
         },
         "keygen" => {
-            let authenticated = authenticate_user(username, password)?;
-
-            if authenticated {
-                println!("User {} authenticated", username);
-
-                println!("Generating local key share for party {:?}...", party_index);
-                let key = keygen(
-                    params["num_parties"].as_u64().unwrap() as u16,
-                    params["num_threshold"].as_u64().unwrap() as u16,
-                    party_index,
-                )?;
-                fs::write(params["key_file"].as_str().unwrap(), &key)?;
-                println!("Key generation complete!");
-            } else {
-                println!("Failed to authenticate user {}", username);
+            let num_parties = param_u16(&params, "num_parties")?;
+            let num_threshold = param_u16(&params, "num_threshold")?;
+            let key_file = param_str(&params, "key_file")?;
+
+            // Returns whether an existing key is replaced. Key files and tombstones may exist on
+            // some nodes only, so the nodes agree on the outcome before generating the key.
+            let checks = || -> Result<bool, Box<dyn Error>> {
+                let role = require_permission(config, &params, func_name, Permission::Keygen)?;
+                require_second_factor(config, &params)?;
+
+                let record = keys::key_info(key_file)?;
+                if let Some(record) = &record {
+                    if record.deleted_at.is_some() {
+                        return Err(io::Error::new(
+                            ErrorKind::AlreadyExists,
+                            format!("{} belonged to deleted key {}, choose another key file", key_file, record.id),
+                        ).into());
+                    }
+                }
+
+                // An existing key is only replaced when the request asks for it, by its owner or
+                // an administrator, and its share is archived rather than overwritten
+                let replace = record.is_some() || Path::new(key_file).exists();
+                if replace {
+                    if params["replace"] != true {
+                        return Err(io::Error::new(
+                            ErrorKind::AlreadyExists,
+                            format!(
+                                "{} already holds a key share, pass replace to archive it and generate a new key",
+                                key_file,
+                            ),
+                        ).into());
+                    }
+                    authorize_key_removal(username, role, func_name, key_file, record.as_ref())?;
+                }
+                Ok(replace)
+            };
+            let parties: Vec<u16> = (1..=num_parties).collect();
+            let replace = agree(checks(), &parties, party_index)?;
+
+            logging::info(format_args!("Generating local key share for party {}", party_index));
+            let key = keygen(num_parties, num_threshold, party_index)?;
+            if replace {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                if let Some(path) = keys::archive(key_file, now)? {
+                    logging::warn(format_args!("Archived previous share of {} to {}", key_file, path));
+                }
             }
+            keys::write_share(key_file, &key)?;
+
+            let local_key = serde_json::from_slice::<LocalKey<Secp256k1>>(&key)?;
+            let public_key = hex::encode(local_key.public_key().to_bytes(true));
+            keys::record_key(key_file, keys::KeyRecord {
+                id: params["request_id"].as_str().unwrap_or(key_file).to_owned(),
+                owner: username.to_owned(),
+                num_parties: local_key.n,
+                threshold: local_key.t,
+                public_key: public_key.clone(),
+                created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                refresh_epoch: 0,
+                party_index: local_key.i,
+                party_indices: (1..=local_key.n).collect(),
+                deleted_at: None,
+            })?;
+            logging::info(format_args!("Key generation complete, public key {}", public_key));
+
+            Ok(json!({ "key_file": key_file, "public_key": public_key }))
+        },
+        "list_keys" => {
+            let role = require_user(config, &params)?;
+
+            // Users without ViewKeys only see the keys they own
+            let keys: Vec<Value> = keys::list_keys()?
+                .into_iter()
+                .filter(|(_, record)| role.allows(Permission::ViewKeys) || record.owner == username)
+                .map(|(key_file, record)| record.to_json(&key_file))
+                .collect();
+            Ok(json!({ "keys": keys }))
+        },
+        "key_info" => {
+            let role = require_user(config, &params)?;
+
+            let key_file = param_str(&params, "key_file")?;
+            let record = keys::key_info(key_file)?.ok_or_else(|| {
+                io::Error::new(ErrorKind::NotFound, format!("no metadata recorded for {}", key_file))
+            })?;
+            if record.owner != username && !role.allows(Permission::ViewKeys) {
+                return Err(permission_denied(username, func_name).into());
+            }
+            Ok(record.to_json(key_file))
+        },
+        "delete_key" => {
+            let role = require_user(config, &params)?;
+            require_second_factor(config, &params)?;
+
+            let key_file = param_str(&params, "key_file")?;
+            authorize_key_removal(username, role, func_name, key_file, keys::key_info(key_file)?.as_ref())?;
+
+            // The share is destroyed before the tombstone is written, so that a failure leaves
+            // the key usable and the deletion can be retried
+            let share_removed = keys::shred(key_file)?;
+            let record = keys::record_tombstone(
+                key_file,
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            )?;
+            logging::info(format_args!("Key {} deleted", key_file));
+
+            Ok(json!({
+                "key_file": key_file,
+                "id": record.id,
+                "share_removed": share_removed,
+                "deleted_at": record.deleted_at,
+            }))
+        },
+        "export_backup" => {
+            require_permission(config, &params, func_name, Permission::BackupKeys)?;
+            require_second_factor(config, &params)?;
 
-            Ok(())
+            // Only the requested party exports its share
+            if params["party"].as_u64() != Some(party_index as u64) {
+                return Ok(Value::Null);
+            }
+
+            let key_file = param_str(&params, "key_file")?;
+            let recipient = match (params["passphrase"].as_str(), params["recipient"].as_str()) {
+                (Some(passphrase), None) => backup::Recipient::Passphrase(passphrase),
+                (None, Some(public_key)) => backup::Recipient::PublicKey(public_key),
+                _ => return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "exactly one of passphrase and recipient is required",
+                ).into()),
+            };
+            let record = keys::key_info(key_file)?.unwrap_or_default();
+            let backup = backup::export(key_file, &record, &fs::read(key_file)?, recipient)?;
+            logging::info(format_args!("Exported backup of {} for party {}", key_file, party_index));
+
+            Ok(json!({ "backup": backup }))
         },
-        "signing" => {
-            let authenticated = authenticate_user(username, password)?;
-
-            if authenticated {
-                println!("Initiating signature generation for party {:?}...", party_index);
-                let key_data = fs::read(params["key_file"].as_str().unwrap())?;
-                let key = serde_json::from_slice::<LocalKey<Secp256k1>>(&key_data).unwrap();
-    
-                let active_party_iter = params["active_parties"].as_array().unwrap().iter();
-                let active_parties : Vec<u16> = active_party_iter.map( |x| x.as_u64().unwrap() as u16).collect();
-    
-                let signature = sign(
-                    params["num_threshold"].as_u64().unwrap() as u16,
-                    &active_parties,
-                    key,
-                    party_index,
-                    params["message"].to_string(),
-                )?;
-    
-                println!("Signature generation complete.");
+        "import_backup" => {
+            require_permission(config, &params, func_name, Permission::BackupKeys)?;
+            require_second_factor(config, &params)?;
+
+            if params["party"].as_u64() != Some(party_index as u64) {
+                return Ok(Value::Null);
+            }
+
+            let key_file = param_str(&params, "key_file")?;
+            let backup: backup::Backup = serde_json::from_value(params["backup"].clone())?;
+            if backup.header.party_index != party_index {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("backup holds the share of party {}", backup.header.party_index),
+                ).into());
+            }
+            if fs::metadata(key_file).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", key_file),
+                ).into());
+            }
+            if keys::key_info(key_file)?.map_or(false, |record| record.deleted_at.is_some()) {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} belonged to a deleted key", key_file),
+                ).into());
+            }
+
+            let secret = match (params["passphrase"].as_str(), params["secret_key"].as_str()) {
+                (Some(passphrase), None) => backup::Secret::Passphrase(passphrase),
+                (None, Some(secret_key)) => backup::Secret::SecretKey(secret_key),
+                _ => return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "exactly one of passphrase and secret_key is required",
+                ).into()),
+            };
+            let key_data = backup::import(&backup, secret)?;
+            keys::write_share(key_file, &key_data)?;
+
+            let header = backup.header;
+            keys::record_key(key_file, keys::KeyRecord {
+                id: header.key_id,
+                owner: header.owner,
+                num_parties: header.num_parties,
+                threshold: header.threshold,
+                public_key: header.public_key.clone(),
+                created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                refresh_epoch: header.refresh_epoch,
+                party_index,
+                party_indices: (1..=header.num_parties).collect(),
+                deleted_at: None,
+            })?;
+            logging::info(format_args!("Restored {} for party {} from backup", key_file, party_index));
+
+            Ok(json!({ "key_file": key_file, "public_key": header.public_key }))
+        },
+        "recover_share" => {
+            let helpers: Vec<u16> = serde_json::from_value(params["helpers"].clone())?;
+            let ceremony = recovery::Ceremony {
+                key_file: param_str(&params, "key_file")?,
+                lost_party: param_u16(&params, "lost_party")?,
+                helpers: &helpers,
+                num_parties: param_u16(&params, "num_parties")?,
+                party_index,
+            };
+
+            // The freshly provisioned node may not know the user yet, so the nodes agree on the
+            // checks before the ceremony
+            let checks = || -> Result<(), Box<dyn Error>> {
+                require_permission(config, &params, func_name, Permission::BackupKeys)?;
+                require_second_factor(config, &params)?;
+                ceremony.check()
+            };
+            let parties: Vec<u16> = (1..=ceremony.num_parties).collect();
+            agree(checks(), &parties, party_index)?;
+            ceremony.run()
+        },
+        "pubkey" => {
+            require_user(config, &params)?;
+
+            let key_data = fs::read(param_str(&params, "key_file")?)?;
+            let key = serde_json::from_slice::<LocalKey<Secp256k1>>(&key_data)?;
 
+            Ok(json!({ "public_key": hex::encode(key.public_key().to_bytes(true)) }))
+        },
+        "signing" => {
+            let active_parties: Vec<u16> = serde_json::from_value(params["active_parties"].clone())?;
+            let num_threshold = param_u16(&params, "num_threshold")?;
+
+            // Users, one-time codes and key files are checked by each node on its own, so the
+            // active parties agree on the outcome before signing
+            let checks = || -> Result<(LocalKey<Secp256k1>, Vec<u8>), Box<dyn Error>> {
+                let key_file = authorize_signing(config, &params, func_name)?;
+                let key_data = fs::read(key_file)?;
+                let mut key = serde_json::from_slice::<LocalKey<Secp256k1>>(&key_data)?;
+                if let Some(path) = params["derivation_path"].as_str() {
+                    derive_child_key(&mut key, &path.parse()?)?;
+                }
+                Ok((key, signing_digest(&params)?))
+            };
+            let (key, digest) = agree(checks(), &active_parties, party_index)?;
+
+            logging::info(format_args!("Initiating signature generation for party {}", party_index));
+            let signature = sign(num_threshold, &active_parties, key, party_index, &digest)?;
+
+            logging::info("Signature generation complete");
+
+            if signature.is_empty() {
+                Ok(Value::Null)
             } else {
-                println!("Failed to authenticate user {}", username);
+                Ok(serde_json::from_slice(&signature)?)
+            }
+        }
+        "sign_psbt" => {
+            let active_parties: Vec<u16> = serde_json::from_value(params["active_parties"].clone())?;
+            let num_threshold = param_u16(&params, "num_threshold")?;
+
+            // As for signing, the active parties agree on the checks each of them ran
+            let checks = || -> Result<_, Box<dyn Error>> {
+                let key_file = authorize_signing(config, &params, func_name)?;
+                let key_data = fs::read(key_file)?;
+                let key = serde_json::from_slice::<LocalKey<Secp256k1>>(&key_data)?;
+                let psbt = signing::psbt::parse(param_str(&params, "psbt")?)?;
+                let any_sighash = params["any_sighash"] == true;
+                let owned = signing::psbt::owned_inputs(&psbt, &key.public_key(), any_sighash)?;
+                if owned.is_empty() {
+                    let message = "no input of the PSBT is owned by the key";
+                    return Err(io::Error::new(ErrorKind::InvalidInput, message).into());
+                }
+                Ok((key, psbt, owned))
+            };
+            let (key, psbt, owned) = agree(checks(), &active_parties, party_index)?;
+            logging::info(format_args!("Signing {}", signing::psbt::describe(&psbt)));
+
+            // Every party finds the same inputs in the same order, so the ceremonies line up
+            let mut signatures = vec![];
+            for input in &owned {
+                logging::info(format_args!("Signing input {} for party {}", input.index, party_index));
+                let mut child = key.clone();
+                if !input.path.0.is_empty() {
+                    derive_child_key(&mut child, &input.path)?;
+                }
+                let signature = sign(num_threshold, &active_parties, child, party_index, &input.digest)?;
+                if !signature.is_empty() {
+                    signatures.push((input.index, serde_json::from_slice::<Value>(&signature)?));
+                }
             }
 
-            Ok(())
+            if active_parties.contains(&party_index) {
+                Ok(json!({ "signatures": signatures }))
+            } else {
+                Ok(Value::Null)
+            }
         }
-        _ => panic!(),
+        _ => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("unknown function {}", func_name),
+        ).into()),
     }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let env = libdots::env::init()?;
 
+    let config = config::load()?;
+    logging::init(config.log.level, config.log.format, Some(env.get_world_rank()));
+    if let Some(listen) = &config.metrics_listen {
+        metrics::serve(listen)?;
+    }
+    if let Some(admin) = &config.bootstrap_admin {
+        users::ensure_bootstrap_admin(admin)?;
+    }
+    let audit_key = config.audit_key_file.as_ref().map(fs::read).transpose()?;
+    jobs::restore()?;
+
     thread::scope(|s| -> Result<(), Box<dyn Error>> {
         loop {
             let env = &env;
+            let config = &config;
+            let audit_key = &audit_key;
             let req = libdots::request::accept()?;
             s.spawn(move || {
-                handle_request(env, &req).unwrap();
+                let _active = metrics::active_thread();
+                let func_name = req.func_name.clone();
+                let args = req.args.clone();
+                let params = args.first()
+                    .and_then(|args| serde_json::from_slice(args).ok())
+                    .unwrap_or(Value::Null);
+                let detach = params["detach"] == true;
+                logging::enter(&func_name, &params);
+                logging::info("Request received");
+                logging::debug(format_args!("Parameters {}", logging::redact(&params)));
+
+                let audit = |error: Option<String>| {
+                    let record = audit::Record::new(&func_name, &params, error);
+                    if let Err(e) = audit::append(&config.audit_log, audit_key.as_deref(), record) {
+                        logging::error(format_args!("Failed to write audit record: {}", e));
+                    }
+                };
+                let respond = |outcome: Result<Value, Box<dyn Error>>| {
+                    let reply = match outcome {
+                        Ok(result) => json!({ "status": "ok", "result": result }),
+                        Err(e) => {
+                            json!({ "status": "error", "error": e.to_string(), "kind": failure_kind(e.as_ref()) })
+                        }
+                    };
+                    req.output(&serde_json::to_vec(&reply).unwrap()).unwrap();
+                };
+
+                // A retried request is answered from the run it repeats, once the user who sent
+                // that run is authenticated, instead of running again
+                let claim = users::password_hash(params["username"].as_str().unwrap_or_default())
+                    .and_then(|password_hash| requests::claim(&func_name, &params, password_hash));
+                let claimed = match claim {
+                    Ok(Claim::New(id)) => Some(id),
+                    Ok(Claim::Untracked) => None,
+                    Ok(Claim::Completed(result, caller)) => {
+                        logging::info("Request was already completed, answering with its stored result");
+                        let outcome = authorize_retry(config, &params, caller.as_ref()).map(|_| result);
+                        audit(outcome.as_ref().err().map(|e| e.to_string()));
+                        return respond(outcome);
+                    }
+                    Ok(Claim::Running(caller)) => {
+                        let request_id = &params["request_id"];
+                        let outcome = authorize_retry(config, &params, caller.as_ref()).and_then(|_| {
+                            if detach && jobs::CEREMONIES.contains(&func_name.as_str()) {
+                                Ok(json!({ "job": request_id, "state": "running" }))
+                            } else {
+                                let message = "is still running, follow it with job_status";
+                                Err(format!("request {} {}", request_id, message).into())
+                            }
+                        });
+                        audit(outcome.as_ref().err().map(|e| e.to_string()));
+                        return respond(outcome);
+                    }
+                    Err(e) => {
+                        audit(Some(e.to_string()));
+                        return respond(Err(e.into()));
+                    }
+                };
+                let job = jobs::start(&func_name, &params);
+
+                // Records the outcome in the metrics and the job of the request, if it runs a
+                // ceremony, in the stored results, if the request is tracked, and in the audit log. Every request
+                // is audited, including those that fail to parse or authenticate.
+                let record = |outcome: Result<&Value, String>| {
+                    metrics::ceremony_finished(outcome.is_ok());
+                    if let Some(job) = &job {
+                        jobs::finish(job, outcome.clone());
+                    }
+                    if let Some(id) = &claimed {
+                        if let Err(e) = requests::complete(id, &func_name, outcome.as_ref().ok().copied()) {
+                            logging::error(format_args!("Failed to store the result of request {}: {}", id, e));
+                        }
+                    }
+                    audit(outcome.err());
+                };
+
+                // A panic is turned into a failure of the request, so that its claim, job and audit
+                // record are settled as for any other failure and a retry can run again
+                let run = || {
+                    let handle = AssertUnwindSafe(|| handle_request(env, config, &func_name, &args));
+                    let outcome = panic::catch_unwind(handle)
+                        .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::Other, "request handler panicked").into()));
+                    match outcome {
+                        Ok(result) => {
+                            logging::info("Request succeeded");
+                            record(Ok(&result));
+                            Ok(result)
+                        }
+                        Err(e) => {
+                            logging::warn(format_args!("Request failed: {}", e));
+                            record(Err(e.to_string()));
+                            Err(e)
+                        }
+                    }
+                };
+
+                // A detached ceremony is acknowledged as soon as the user is authenticated, and
+                // its outcome is left for job_status to report
+                if job.is_some() && detach {
+                    match require_user(config, &params) {
+                        Ok(_) => {
+                            respond(Ok(json!({ "job": job, "state": "running" })));
+                            let _ = run();
+                        }
+                        Err(e) => {
+                            record(Err(e.to_string()));
+                            respond(Err(e.into()));
+                        }
+                    }
+                } else {
+                    respond(run());
+                }
             });
         }
     })?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(owner: &str) -> keys::KeyRecord {
+        keys::KeyRecord { owner: owner.to_owned(), ..Default::default() }
+    }
+
+    #[test]
+    fn operator_cannot_delete_node_files() {
+        // users.json has no entry in the key registry, so no role may shred it or archive it by
+        // replacing it with a new key
+        let attempts = [(Role::Operator, "delete_key"), (Role::Admin, "delete_key"), (Role::Operator, "keygen")];
+        for (role, func_name) in attempts {
+            let refused = authorize_key_removal("operator", role, func_name, "users.json", None).unwrap_err();
+            assert_eq!(refused.kind(), ErrorKind::NotFound);
+            assert_eq!(refused.to_string(), "users.json is not a key share recorded by this node");
+        }
+    }
+
+    #[test]
+    fn owners_and_key_managers_may_remove_keys() {
+        let key = record("alice");
+        assert!(authorize_key_removal("alice", Role::Signer, "delete_key", "key", Some(&key)).is_ok());
+        assert!(authorize_key_removal("operator", Role::Operator, "delete_key", "key", Some(&key)).is_ok());
+
+        let refused = authorize_key_removal("bob", Role::Signer, "delete_key", "key", Some(&key)).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,308 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use signing::{psbt, Preimage};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hash preceding the first record of a log
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Serializes appends to the audit log between request threads
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// One entry of the audit log. Every record commits to its predecessor through `prev_hash`,
+/// so editing or removing a record breaks the chain from that point on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub request_id: String,
+    pub username: String,
+    pub function: String,
+    pub key_id: Option<String>,
+    /// BIP32 path of the child key that signed, left out of records of the master key so that
+    /// records written before derivation existed keep their hashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derivation_path: Option<String>,
+    /// Digest that was signed, hex encoded
+    pub message_digest: Option<String>,
+    /// Decoded fields of the data the digest was computed from, such as an Ethereum transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<Value>,
+    pub active_parties: Option<Vec<u16>>,
+    /// Either "ok" or "error"
+    pub outcome: String,
+    pub error: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+/// Position of the last record, kept next to the log so that truncation can be detected
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Head {
+    pub seq: u64,
+    pub hash: String,
+    /// HMAC-SHA256 of `seq` and `hash` under the audit key of the node, hex encoded. Without it,
+    /// whoever can write the log can also rewrite the chain and recompute the head.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+impl Head {
+    fn keyed_mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&self.seq.to_be_bytes());
+        mac.update(self.hash.as_bytes());
+        mac
+    }
+
+    /// Computes the MAC of the head under `key`
+    pub fn compute_mac(&self, key: &[u8]) -> String {
+        hex::encode(self.keyed_mac(key).finalize().into_bytes())
+    }
+
+    /// Checks in constant time that the head carries its MAC under `key`
+    pub fn verify_mac(&self, key: &[u8]) -> bool {
+        match self.mac.as_deref().map(hex::decode) {
+            Some(Ok(mac)) => self.keyed_mac(key).verify_slice(&mac).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+impl Record {
+    /// Builds the record of a request from its parameters and outcome, leaving the chain fields empty
+    pub fn new(function: &str, params: &Value, error: Option<String>) -> Self {
+        let message_digest = params["digest"].as_str().map(str::to_lowercase);
+        let preimage = match params["psbt"].as_str() {
+            Some(psbt) => Some(
+                psbt::parse(psbt).map_or_else(|e| Value::String(e.to_string()), |psbt| psbt::describe(&psbt)),
+            ),
+            None => serde_json::from_value::<Preimage>(params["preimage"].clone())
+                .ok()
+                .map(|preimage| preimage.decode().unwrap_or_else(|e| Value::String(e.to_string()))),
+        };
+        let active_parties = params["active_parties"].as_array().map(|parties| {
+            parties.iter().filter_map(|party| party.as_u64()).map(|party| party as u16).collect()
+        });
+
+        Record {
+            seq: 0,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            request_id: params["request_id"].as_str().unwrap_or_default().to_owned(),
+            username: params["username"].as_str().unwrap_or_default().to_owned(),
+            function: function.to_owned(),
+            key_id: params["key_file"].as_str().map(str::to_owned),
+            derivation_path: params["derivation_path"].as_str().map(str::to_owned),
+            message_digest,
+            preimage,
+            active_parties,
+            outcome: if error.is_none() { "ok" } else { "error" }.to_owned(),
+            error,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    /// Computes the hash of the record over all of its fields except `hash` itself
+    pub fn compute_hash(&self) -> String {
+        let mut unhashed = self.clone();
+        unhashed.hash = String::new();
+        let serialized = serde_json::to_vec(&unhashed).expect("records always serialize");
+        hex::encode(Sha256::digest(&serialized))
+    }
+}
+
+fn head_path(log_path: &str) -> String {
+    format!("{}.head", log_path)
+}
+
+/// Reads the head of the log at `log_path`, if one was written
+pub fn read_head(log_path: &str) -> io::Result<Option<Head>> {
+    match fs::read(head_path(log_path)) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads every record of the log at `log_path`
+pub fn read_log(log_path: &str) -> io::Result<Vec<Record>> {
+    let file = match fs::File::open(log_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok(records)
+}
+
+/// Chains `record` onto the log at `log_path` and advances the head, authenticating it with `key`
+/// if the node has an audit key
+pub fn append(log_path: &str, key: Option<&[u8]>, mut record: Record) -> io::Result<()> {
+    let _guard = AUDIT_LOCK.lock().unwrap();
+
+    let (seq, prev_hash) = match read_head(log_path)? {
+        Some(head) => (head.seq + 1, head.hash),
+        None => (0, GENESIS_HASH.to_owned()),
+    };
+    record.seq = seq;
+    record.prev_hash = prev_hash;
+    record.hash = record.compute_hash();
+
+    let mut log = OpenOptions::new().create(true).append(true).open(log_path)?;
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    log.write_all(&line)?;
+    log.sync_all()?;
+
+    let mut head = Head { seq: record.seq, hash: record.hash, mac: None };
+    head.mac = key.map(|key| head.compute_mac(key));
+    let tmp_path = format!("{}.tmp", head_path(log_path));
+    fs::write(&tmp_path, serde_json::to_vec(&head)?)?;
+    fs::rename(&tmp_path, head_path(log_path))
+}
+
+/// Checks that `records` form an unbroken chain ending at `head`, and that the head was written
+/// with `key` if one is given, returning every problem found
+pub fn verify_chain(records: &[Record], head: Option<&Head>, key: Option<&[u8]>) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    for (expected_seq, record) in records.iter().enumerate() {
+        if record.seq != expected_seq as u64 {
+            problems.push(format!("record {} has sequence number {}", expected_seq, record.seq));
+        }
+        if record.prev_hash != prev_hash {
+            problems.push(format!("record {} does not follow its predecessor", record.seq));
+        }
+        if record.hash != record.compute_hash() {
+            problems.push(format!("record {} was modified", record.seq));
+        }
+        prev_hash = record.hash.clone();
+    }
+
+    match (head, records.last()) {
+        (Some(head), Some(last)) if head.seq != last.seq || head.hash != last.hash => problems.push(
+            format!("log ends at record {} but its head points at record {}", last.seq, head.seq),
+        ),
+        (Some(head), None) => {
+            problems.push(format!("log is empty but its head points at record {}", head.seq))
+        }
+        (None, Some(_)) => problems.push("log has no head".to_owned()),
+        _ => {}
+    }
+    if let (Some(head), Some(key)) = (head, key) {
+        if head.mac.is_none() {
+            problems.push("head is not authenticated".to_owned());
+        } else if !head.verify_mac(key) {
+            problems.push("head was not written with the audit key".to_owned());
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const KEY: &[u8] = b"audit key of the node";
+
+    /// Returns a fresh log path under the temporary directory, unique to the test and process
+    fn log_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("audit-{}-{}.log", std::process::id(), name));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(head_path(&path));
+        path
+    }
+
+    fn remove_log(path: &str) {
+        fs::remove_file(path).unwrap();
+        fs::remove_file(head_path(path)).unwrap();
+    }
+
+    /// Appends three records to a fresh log and returns them with the head
+    fn write_log(name: &str, key: Option<&[u8]>) -> (Vec<Record>, Head) {
+        let path = log_path(name);
+        for (request_id, error) in [("a", None), ("b", Some("refused".to_owned())), ("c", None)] {
+            let params = json!({ "request_id": request_id, "username": "alice", "digest": "AB" });
+            append(&path, key, Record::new("sign", &params, error)).unwrap();
+        }
+        let log = (read_log(&path).unwrap(), read_head(&path).unwrap().unwrap());
+        remove_log(&path);
+        log
+    }
+
+    #[test]
+    fn appended_records_form_a_chain() {
+        let (records, head) = write_log("chain", Some(KEY));
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert_eq!(records[1].outcome, "error");
+        assert_eq!(records[2].message_digest.as_deref(), Some("ab"));
+        assert_eq!((head.seq, &head.hash), (2, &records[2].hash));
+        assert!(head.verify_mac(KEY));
+        assert!(verify_chain(&records, Some(&head), Some(KEY)).is_empty());
+    }
+
+    #[test]
+    fn modified_records_are_detected() {
+        let (mut records, head) = write_log("modified", None);
+        records[1].outcome = "ok".to_owned();
+        assert_eq!(verify_chain(&records, Some(&head), None), ["record 1 was modified"]);
+
+        records[1].hash = records[1].compute_hash();
+        assert_eq!(verify_chain(&records, Some(&head), None), ["record 2 does not follow its predecessor"]);
+    }
+
+    #[test]
+    fn removed_records_are_detected() {
+        let (mut records, head) = write_log("removed", None);
+        records.pop();
+        let problems = verify_chain(&records, Some(&head), None);
+        assert_eq!(problems, ["log ends at record 1 but its head points at record 2"]);
+
+        records.remove(0);
+        let problems = verify_chain(&records, Some(&head), None);
+        assert!(problems.contains(&"record 0 has sequence number 1".to_owned()));
+        assert!(problems.contains(&"record 1 does not follow its predecessor".to_owned()));
+
+        assert_eq!(verify_chain(&[], Some(&head), None), ["log is empty but its head points at record 2"]);
+        assert_eq!(verify_chain(&records, None, None).last().unwrap(), "log has no head");
+    }
+
+    #[test]
+    fn head_must_be_written_with_the_audit_key() {
+        let (records, head) = write_log("unkeyed", None);
+        assert!(verify_chain(&records, Some(&head), None).is_empty());
+        assert_eq!(verify_chain(&records, Some(&head), Some(KEY)), ["head is not authenticated"]);
+
+        let (records, head) = write_log("keyed", Some(KEY));
+        assert_eq!(
+            verify_chain(&records, Some(&head), Some(b"another key")),
+            ["head was not written with the audit key"]
+        );
+
+        // A rewritten chain with a recomputed head cannot carry a valid MAC
+        let mut forged = Head { seq: head.seq, hash: GENESIS_HASH.to_owned(), mac: head.mac.clone() };
+        assert!(!forged.verify_mac(KEY));
+        forged.mac = Some("zz".to_owned());
+        assert!(!forged.verify_mac(KEY));
+    }
+}
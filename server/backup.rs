@@ -0,0 +1,369 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use hkdf::Hkdf;
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::{self, ErrorKind};
+
+use crate::keys::KeyRecord;
+
+/// Version of the backup format written by `export`
+pub const BACKUP_VERSION: u32 = 1;
+
+const HKDF_INFO: &[u8] = b"dots-signing backup v1";
+
+/// Largest Argon2 costs accepted on import, twice those `export` writes. The costs come from the
+/// backup, so without a limit a crafted one could make the node spend arbitrary memory and time.
+const MAX_M_COST: u32 = 2 * Params::DEFAULT_M_COST;
+const MAX_T_COST: u32 = 2 * Params::DEFAULT_T_COST;
+const MAX_P_COST: u32 = 2 * Params::DEFAULT_P_COST;
+
+/// How the share in a backup is encrypted
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Encryption {
+    /// Key derived from a passphrase with Argon2id
+    Passphrase {
+        salt: String,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// ECIES to a secp256k1 public key of the operator: the key is derived with HKDF-SHA256 from
+    /// the Diffie-Hellman secret of an ephemeral key and the recipient key
+    PublicKey {
+        recipient: String,
+        ephemeral_public_key: String,
+    },
+}
+
+/// Everything in a backup but the ciphertext. The header is authenticated as associated data,
+/// so that it cannot be altered without failing decryption.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub version: u32,
+    pub key_file: String,
+    pub key_id: String,
+    pub owner: String,
+    pub party_index: u16,
+    pub num_parties: u16,
+    pub threshold: u16,
+    /// Joint public key, compressed and hex encoded
+    pub public_key: String,
+    /// Refresh epoch of the share, which only works with shares of the same epoch
+    pub refresh_epoch: u64,
+    pub encryption: Encryption,
+    pub nonce: String,
+}
+
+/// Key share of one party encrypted to an operator
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Backup {
+    #[serde(flatten)]
+    pub header: BackupHeader,
+    /// The serialized `LocalKey`, encrypted with ChaCha20-Poly1305 and hex encoded
+    pub ciphertext: String,
+}
+
+/// Who can decrypt a backup
+pub enum Recipient<'a> {
+    Passphrase(&'a str),
+    /// Compressed secp256k1 public key in hex
+    PublicKey(&'a str),
+}
+
+/// Secret that decrypts a backup
+pub enum Secret<'a> {
+    Passphrase(&'a str),
+    /// secp256k1 secret key in hex
+    SecretKey(&'a str),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn decode_hex(value: &str, name: &str) -> io::Result<Vec<u8>> {
+    hex::decode(value).map_err(|_| invalid(&format!("{} is not valid hex", name)))
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> io::Result<[u8; 32]> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|e| invalid(&e.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(key)
+}
+
+fn ecies_key(shared: &Point<Secp256k1>, ephemeral: &Point<Secp256k1>) -> io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&ephemeral.to_bytes(true)), &shared.to_bytes(true))
+        .expand(HKDF_INFO, &mut key)
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(key)
+}
+
+fn parse_point(value: &str, name: &str) -> io::Result<Point<Secp256k1>> {
+    Point::from_bytes(&decode_hex(value, name)?).map_err(|_| invalid(&format!("{} is not a valid public key", name)))
+}
+
+/// Encrypts the share in `key_data` to `recipient`, taking the key id and owner from `record`
+pub fn export(key_file: &str, record: &KeyRecord, key_data: &[u8], recipient: Recipient) -> io::Result<Backup> {
+    let local_key: LocalKey<Secp256k1> = serde_json::from_slice(key_data)?;
+
+    let (key, encryption) = match recipient {
+        Recipient::Passphrase(passphrase) => {
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let defaults = Params::default();
+            let (m_cost, t_cost, p_cost) = (defaults.m_cost(), defaults.t_cost(), defaults.p_cost());
+            let key = passphrase_key(passphrase, &salt, m_cost, t_cost, p_cost)?;
+            (key, Encryption::Passphrase { salt: hex::encode(salt), m_cost, t_cost, p_cost })
+        }
+        Recipient::PublicKey(recipient) => {
+            let recipient_point = parse_point(recipient, "recipient")?;
+            let ephemeral_secret = Scalar::<Secp256k1>::random();
+            let ephemeral = Point::generator() * &ephemeral_secret;
+            let key = ecies_key(&(&recipient_point * &ephemeral_secret), &ephemeral)?;
+            let encryption = Encryption::PublicKey {
+                recipient: recipient.to_lowercase(),
+                ephemeral_public_key: hex::encode(ephemeral.to_bytes(true)),
+            };
+            (key, encryption)
+        }
+    };
+
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        key_file: key_file.to_owned(),
+        key_id: record.id.clone(),
+        owner: record.owner.clone(),
+        party_index: local_key.i,
+        num_parties: local_key.n,
+        threshold: local_key.t,
+        public_key: hex::encode(local_key.public_key().to_bytes(true)),
+        refresh_epoch: record.refresh_epoch,
+        encryption,
+        nonce: hex::encode(nonce),
+    };
+
+    let aad = serde_json::to_vec(&header)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: key_data, aad: &aad })
+        .map_err(|_| io::Error::new(ErrorKind::Other, "failed to encrypt share"))?;
+
+    Ok(Backup { header, ciphertext: hex::encode(ciphertext) })
+}
+
+/// Decrypts a backup with `secret` and checks that the share inside is consistent with the
+/// header, returning the serialized share
+pub fn import(backup: &Backup, secret: Secret) -> io::Result<Vec<u8>> {
+    let header = &backup.header;
+    if header.version != BACKUP_VERSION {
+        return Err(invalid(&format!("unsupported backup version {}", header.version)));
+    }
+
+    let key = match (&header.encryption, secret) {
+        (Encryption::Passphrase { salt, m_cost, t_cost, p_cost }, Secret::Passphrase(passphrase)) => {
+            if *m_cost > MAX_M_COST || *t_cost > MAX_T_COST || *p_cost > MAX_P_COST {
+                return Err(invalid("backup asks for larger Argon2 costs than this node accepts"));
+            }
+            passphrase_key(passphrase, &decode_hex(salt, "salt")?, *m_cost, *t_cost, *p_cost)?
+        }
+        (Encryption::PublicKey { ephemeral_public_key, .. }, Secret::SecretKey(secret_key)) => {
+            let secret_key = Scalar::<Secp256k1>::from_bytes(&decode_hex(secret_key, "secret key")?)
+                .map_err(|_| invalid("secret key is not a valid secp256k1 scalar"))?;
+            let ephemeral = parse_point(ephemeral_public_key, "ephemeral public key")?;
+            ecies_key(&(&ephemeral * &secret_key), &ephemeral)?
+        }
+        (Encryption::Passphrase { .. }, _) => return Err(invalid("backup is encrypted with a passphrase")),
+        (Encryption::PublicKey { .. }, _) => return Err(invalid("backup is encrypted to a public key")),
+    };
+
+    let aad = serde_json::to_vec(header)?;
+    let nonce = decode_hex(&header.nonce, "nonce")?;
+    if nonce.len() != 12 {
+        return Err(invalid("nonce must be 12 bytes"));
+    }
+    let key_data = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload { msg: &decode_hex(&backup.ciphertext, "ciphertext")?, aad: &aad },
+        )
+        .map_err(|_| invalid("backup cannot be decrypted: wrong secret or corrupted backup"))?;
+
+    // The share must belong to the party and key named in the header, and its secret must
+    // match the public share that the other parties hold for it
+    let local_key: LocalKey<Secp256k1> = serde_json::from_slice(&key_data)?;
+    if local_key.i != header.party_index || local_key.t != header.threshold || local_key.n != header.num_parties {
+        return Err(invalid("share does not match the party described by the backup"));
+    }
+    if hex::encode(local_key.public_key().to_bytes(true)) != header.public_key {
+        return Err(invalid("share does not belong to the public key of the backup"));
+    }
+    let public_share = (local_key.i as usize).checked_sub(1).and_then(|i| local_key.pk_vec.get(i));
+    if public_share != Some(&(Point::generator() * &local_key.keys_linear.x_i)) {
+        return Err(invalid("secret share does not match its public share"));
+    }
+
+    Ok(key_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::Keygen;
+    use round_based::{Msg, StateMachine};
+    use std::sync::OnceLock;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// Shares of a 2-of-2 key, generated once by running the keygen of both parties in memory
+    fn local_keys() -> &'static [LocalKey<Secp256k1>] {
+        static KEYS: OnceLock<Vec<LocalKey<Secp256k1>>> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            let mut parties: Vec<Keygen> = (1..=2).map(|i| Keygen::new(i, 1, 2).unwrap()).collect();
+            while !parties.iter().all(|party| party.is_finished()) {
+                for i in 0..parties.len() {
+                    if parties[i].wants_to_proceed() {
+                        parties[i].proceed().unwrap();
+                    }
+                    let messages: Vec<Msg<_>> = parties[i].message_queue().drain(..).collect();
+                    for message in messages {
+                        for party in parties.iter_mut().filter(|party| party.party_ind() != message.sender) {
+                            if message.receiver.map_or(true, |receiver| receiver == party.party_ind()) {
+                                party.handle_incoming(message.clone()).unwrap();
+                            }
+                        }
+                    }
+                }
+            }
+            parties.iter_mut().map(|party| party.pick_output().unwrap().unwrap()).collect()
+        })
+    }
+
+    fn record() -> KeyRecord {
+        KeyRecord { id: "key-id".to_owned(), owner: "alice".to_owned(), refresh_epoch: 3, ..Default::default() }
+    }
+
+    fn share(party: usize) -> Vec<u8> {
+        serde_json::to_vec(&local_keys()[party]).unwrap()
+    }
+
+    fn passphrase_backup() -> Backup {
+        export("key", &record(), &share(0), Recipient::Passphrase(PASSPHRASE)).unwrap()
+    }
+
+    /// Encrypts `key_data` again under the header of `backup`, as a backup with a consistent
+    /// ciphertext but an altered header or share would be
+    fn reseal(backup: &mut Backup, key_data: &[u8]) {
+        let Encryption::Passphrase { salt, m_cost, t_cost, p_cost } = &backup.header.encryption else {
+            panic!("backup is not encrypted with a passphrase");
+        };
+        let key = passphrase_key(PASSPHRASE, &hex::decode(salt).unwrap(), *m_cost, *t_cost, *p_cost).unwrap();
+        let aad = serde_json::to_vec(&backup.header).unwrap();
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(
+                Nonce::from_slice(&hex::decode(&backup.header.nonce).unwrap()),
+                Payload { msg: key_data, aad: &aad },
+            )
+            .unwrap();
+        backup.ciphertext = hex::encode(ciphertext);
+    }
+
+    fn import_error(backup: &Backup, secret: Secret) -> String {
+        import(backup, secret).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn passphrase_backup_round_trips() {
+        let backup = passphrase_backup();
+        assert_eq!(backup.header.key_id, "key-id");
+        assert_eq!(backup.header.owner, "alice");
+        assert_eq!(backup.header.refresh_epoch, 3);
+        assert_eq!((backup.header.party_index, backup.header.threshold, backup.header.num_parties), (1, 1, 2));
+        assert_eq!(backup.header.public_key, hex::encode(local_keys()[0].public_key().to_bytes(true)));
+        assert_eq!(import(&backup, Secret::Passphrase(PASSPHRASE)).unwrap(), share(0));
+    }
+
+    #[test]
+    fn public_key_backup_round_trips() {
+        let secret_key = Scalar::<Secp256k1>::random();
+        let recipient = hex::encode((Point::generator() * &secret_key).to_bytes(true));
+        let backup = export("key", &record(), &share(1), Recipient::PublicKey(&recipient)).unwrap();
+        let secret_key = hex::encode(&*secret_key.to_bytes());
+        assert_eq!(import(&backup, Secret::SecretKey(&secret_key)).unwrap(), share(1));
+
+        let other_key = hex::encode(&*Scalar::<Secp256k1>::random().to_bytes());
+        assert!(import_error(&backup, Secret::SecretKey(&other_key)).contains("cannot be decrypted"));
+        assert_eq!(import_error(&backup, Secret::Passphrase(PASSPHRASE)), "backup is encrypted to a public key");
+    }
+
+    #[test]
+    fn wrong_passphrase_and_tampering_are_detected() {
+        let backup = passphrase_backup();
+        assert!(import_error(&backup, Secret::Passphrase("wrong")).contains("cannot be decrypted"));
+        assert_eq!(import_error(&backup, Secret::SecretKey("01")), "backup is encrypted with a passphrase");
+
+        let mut tampered = backup.clone();
+        tampered.header.owner = "mallory".to_owned();
+        assert!(import_error(&tampered, Secret::Passphrase(PASSPHRASE)).contains("cannot be decrypted"));
+
+        let mut tampered = backup.clone();
+        let mut ciphertext = hex::decode(&tampered.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        tampered.ciphertext = hex::encode(ciphertext);
+        assert!(import_error(&tampered, Secret::Passphrase(PASSPHRASE)).contains("cannot be decrypted"));
+
+        let mut tampered = backup;
+        tampered.header.version = BACKUP_VERSION + 1;
+        assert_eq!(import_error(&tampered, Secret::Passphrase(PASSPHRASE)), "unsupported backup version 2");
+    }
+
+    #[test]
+    fn share_must_match_the_header() {
+        let mut backup = passphrase_backup();
+        backup.header.party_index = 2;
+        reseal(&mut backup, &share(0));
+        assert_eq!(
+            import_error(&backup, Secret::Passphrase(PASSPHRASE)),
+            "share does not match the party described by the backup"
+        );
+
+        let mut backup = passphrase_backup();
+        backup.header.public_key = hex::encode(Point::<Secp256k1>::generator().to_point().to_bytes(true));
+        reseal(&mut backup, &share(0));
+        assert_eq!(
+            import_error(&backup, Secret::Passphrase(PASSPHRASE)),
+            "share does not belong to the public key of the backup"
+        );
+
+        let mut local_key = local_keys()[0].clone();
+        local_key.keys_linear.x_i = Scalar::random();
+        let mut backup = passphrase_backup();
+        reseal(&mut backup, &serde_json::to_vec(&local_key).unwrap());
+        assert_eq!(
+            import_error(&backup, Secret::Passphrase(PASSPHRASE)),
+            "secret share does not match its public share"
+        );
+    }
+
+    #[test]
+    fn large_argon2_costs_are_refused() {
+        for (m_cost, t_cost, p_cost) in [(MAX_M_COST + 1, 1, 1), (8, MAX_T_COST + 1, 1), (8, 1, MAX_P_COST + 1)] {
+            let mut backup = passphrase_backup();
+            backup.header.encryption = Encryption::Passphrase { salt: "00".repeat(16), m_cost, t_cost, p_cost };
+            assert_eq!(
+                import_error(&backup, Secret::Passphrase(PASSPHRASE)),
+                "backup asks for larger Argon2 costs than this node accepts"
+            );
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::logging::{Format, Level};
+
+const CONFIG_VAR: &str = "SIGNING_CONFIG";
+const DEFAULT_CONFIG: &str = "signing_conf.json";
+
+/// Node configuration, read from `signing_conf.json` in the working directory or from the file named by `SIGNING_CONFIG`
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Administrator account created on startup if it does not exist yet
+    pub bootstrap_admin: Option<BootstrapAdmin>,
+    /// Throttling of failed logins
+    pub lockout: LockoutConfig,
+    /// Path of the hash-chained audit log
+    pub audit_log: String,
+    /// File holding the secret key the head of the audit log is authenticated with. Heads are
+    /// written without a MAC if unset.
+    pub audit_key_file: Option<String>,
+    /// Level and format of the node's logs
+    pub log: LogConfig,
+    /// Local address to serve Prometheus metrics on, such as 127.0.0.1:9100. Metrics are not served if unset.
+    pub metrics_listen: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bootstrap_admin: None,
+            lockout: LockoutConfig::default(),
+            audit_log: "audit.log".to_owned(),
+            audit_key_file: None,
+            log: LogConfig::default(),
+            metrics_listen: None,
+        }
+    }
+}
+
+/// Credentials of the bootstrap administrator. Either the plain password or its bcrypt hash must be given.
+#[derive(Deserialize)]
+pub struct BootstrapAdmin {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_hash: Option<String>,
+}
+
+/// Failed login handling. Every failure doubles the delay before the next attempt is accepted,
+/// starting at `base_delay_secs` and capped at `max_delay_secs`. After `max_attempts` consecutive
+/// failures the user is locked out for `lockout_secs`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub max_attempts: u32,
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_attempts: 5,
+            base_delay_secs: 1,
+            max_delay_secs: 60,
+            lockout_secs: 900,
+        }
+    }
+}
+
+/// Logging of the node. `level` is one of error, warn, info and debug, `format` is text or json.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: Level,
+    pub format: Format,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Info,
+            format: Format::Text,
+        }
+    }
+}
+
+/// Loads the node configuration, falling back to the defaults if no configuration file exists
+pub fn load() -> io::Result<Config> {
+    let path = env::var(CONFIG_VAR).unwrap_or_else(|_| DEFAULT_CONFIG.to_owned());
+    match fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid config {}: {}", path, e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,166 @@
+//! Registry of the ceremonies a node runs, so that clients can follow them by request id.
+//!
+//! Every keygen, signing and recovery request is recorded as a job when it starts, with the round
+//! it has reached and its outcome once it ends. Each request runs on its own thread, which tracks
+//! the job it is running so that the ceremonies can report their rounds. Jobs are kept for a day
+//! after they finish, and stored in `jobs.json` when they start and end so that their outcome
+//! survives a restart of the node. Jobs that were running when the node stopped are failed.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use signing::{JobState, JobStatus};
+
+use crate::logging;
+use crate::metrics;
+
+/// Functions that run a ceremony between the nodes
+pub const CEREMONIES: [&str; 4] = ["keygen", "signing", "sign_psbt", "recover_share"];
+
+const JOB_DATA: &str = "jobs.json";
+
+/// How long finished jobs are kept, in seconds
+const RETENTION: u64 = 24 * 60 * 60;
+
+static JOBS: Mutex<BTreeMap<String, JobStatus>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// Job run by the current thread
+    static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn load_jobs() -> io::Result<BTreeMap<String, JobStatus>> {
+    match fs::read(JOB_DATA) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid job store {}: {}", JOB_DATA, e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn store_jobs(jobs: &BTreeMap<String, JobStatus>) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", JOB_DATA);
+    fs::write(&tmp_path, serde_json::to_vec(jobs)?)?;
+    fs::rename(&tmp_path, JOB_DATA)
+}
+
+/// Stores the jobs, logging instead of failing the ceremony if they cannot be written
+fn save(jobs: &BTreeMap<String, JobStatus>) {
+    if let Err(e) = store_jobs(jobs) {
+        logging::error(format_args!("Failed to store jobs: {}", e));
+    }
+}
+
+/// Loads the jobs stored before the node last stopped. Jobs that were still running then never
+/// finished, so they are recorded as failed.
+pub fn restore() -> io::Result<()> {
+    let mut stored = load_jobs()?;
+    let now = unix_time();
+    for job in stored.values_mut().filter(|job| job.state == JobState::Running) {
+        job.state = JobState::Failed;
+        job.error = Some("node restarted before the job finished".to_owned());
+        job.finished_at = Some(now);
+    }
+
+    let mut jobs = JOBS.lock().unwrap();
+    *jobs = stored;
+    store_jobs(&jobs)
+}
+
+/// Parties that take part in the ceremony of a request
+fn parties(params: &Value) -> Vec<u16> {
+    let list = |name: &str| serde_json::from_value::<Vec<u16>>(params[name].clone()).ok();
+    if let Some(active_parties) = list("active_parties") {
+        return active_parties;
+    }
+    if let Some(mut helpers) = list("helpers") {
+        helpers.extend(params["lost_party"].as_u64().map(|party| party as u16));
+        helpers.sort_unstable();
+        return helpers;
+    }
+    (1..=params["num_parties"].as_u64().unwrap_or_default() as u16).collect()
+}
+
+/// Records the start of a request if it runs a ceremony, and makes it the job of the current
+/// thread. Returns the id of the job, which is the request id. Ceremonies without a request id are
+/// counted but not tracked as jobs.
+pub fn start(func_name: &str, params: &Value) -> Option<String> {
+    if !CEREMONIES.contains(&func_name) {
+        return None;
+    }
+    metrics::ceremony_started(func_name);
+    let id = params["request_id"].as_str()?.to_owned();
+
+    let now = unix_time();
+    let status = JobStatus {
+        job: id.clone(),
+        function: func_name.to_owned(),
+        username: params["username"].as_str().unwrap_or_default().to_owned(),
+        state: JobState::Running,
+        round: None,
+        parties: parties(params),
+        result: None,
+        error: None,
+        started_at: now,
+        finished_at: None,
+    };
+
+    let mut jobs = JOBS.lock().unwrap();
+    jobs.retain(|_, job| job.finished_at.map_or(true, |finished_at| finished_at + RETENTION > now));
+    jobs.insert(id.clone(), status);
+    save(&jobs);
+    CURRENT.with(|current| *current.borrow_mut() = Some(id.clone()));
+    Some(id)
+}
+
+/// Records that the job of the current thread reached `round` of its ceremony
+pub fn round(round: u32) {
+    logging::round(round);
+    metrics::round(round);
+    CURRENT.with(|current| {
+        if let Some(id) = &*current.borrow() {
+            if let Some(job) = JOBS.lock().unwrap().get_mut(id) {
+                job.round = Some(round);
+            }
+        }
+    });
+}
+
+/// Records the outcome of a job
+pub fn finish(id: &str, outcome: Result<&Value, String>) {
+    CURRENT.with(|current| *current.borrow_mut() = None);
+
+    let mut jobs = JOBS.lock().unwrap();
+    if let Some(job) = jobs.get_mut(id) {
+        match outcome {
+            Ok(result) => {
+                job.state = JobState::Succeeded;
+                job.result = Some(result.clone());
+            }
+            Err(error) => {
+                job.state = JobState::Failed;
+                job.error = Some(error);
+            }
+        }
+        job.finished_at = Some(unix_time());
+        save(&jobs);
+    }
+}
+
+pub fn status(id: &str) -> io::Result<JobStatus> {
+    JOBS.lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no job {}", id)))
+}
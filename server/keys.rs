@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const KEY_DATA: &str = "keys.json";
+
+/// Directory that replaced key shares are moved to, along with their metadata
+const ARCHIVE_DIR: &str = "archive";
+
+/// Serializes read-modify-write cycles on the key registry between request threads
+static KEY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Registry entry for a key share held by this node, indexed by its key file. Entries written
+/// before metadata was recorded only hold the owner.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyRecord {
+    /// Identifies the key on every node, taken from the request that generated it
+    pub id: String,
+    pub owner: String,
+    pub num_parties: u16,
+    pub threshold: u16,
+    /// Joint public key, compressed and hex encoded
+    pub public_key: String,
+    /// Seconds since the Unix epoch at which this node stored its share
+    pub created_at: u64,
+    /// Number of times the shares were refreshed since key generation
+    pub refresh_epoch: u64,
+    /// Party index of this node's share
+    pub party_index: u16,
+    /// Party indices of all shares of the key
+    pub party_indices: Vec<u16>,
+    /// Seconds since the Unix epoch at which the share was deleted. The record is kept as a
+    /// tombstone so that the key file cannot be reused for another key.
+    pub deleted_at: Option<u64>,
+}
+
+impl KeyRecord {
+    /// Returns the record as JSON, together with the key file it describes
+    pub fn to_json(&self, key_file: &str) -> Value {
+        let mut value = serde_json::to_value(self).expect("key records always serialize");
+        value["key_file"] = Value::String(key_file.to_owned());
+        value
+    }
+}
+
+fn load_keys() -> io::Result<HashMap<String, KeyRecord>> {
+    match fs::read(KEY_DATA) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid key registry {}: {}", KEY_DATA, e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn store_keys(keys: &HashMap<String, KeyRecord>) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", KEY_DATA);
+    fs::write(&tmp_path, serde_json::to_vec_pretty(keys)?)?;
+    fs::rename(&tmp_path, KEY_DATA)
+}
+
+/// Records the metadata of the key share stored in `key_file`, replacing any earlier entry
+pub fn record_key(key_file: &str, record: KeyRecord) -> io::Result<()> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    keys.insert(key_file.to_owned(), record);
+    store_keys(&keys)
+}
+
+/// Returns the metadata of the key share stored in `key_file`, if it was recorded
+pub fn key_info(key_file: &str) -> io::Result<Option<KeyRecord>> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    Ok(load_keys()?.remove(key_file))
+}
+
+/// Returns every recorded key share with its key file, sorted by key file
+pub fn list_keys() -> io::Result<Vec<(String, KeyRecord)>> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    let mut keys: Vec<(String, KeyRecord)> = load_keys()?.into_iter().collect();
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(keys)
+}
+
+/// Marks the key share stored in `key_file` as deleted, creating a record if none exists
+pub fn record_tombstone(key_file: &str, deleted_at: u64) -> io::Result<KeyRecord> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+    let record = keys.entry(key_file.to_owned()).or_default();
+    record.deleted_at.get_or_insert(deleted_at);
+    let record = record.clone();
+    store_keys(&keys)?;
+    Ok(record)
+}
+
+/// Writes a key share to `key_file` through a temporary file that is flushed to disk before it
+/// replaces the share, so that a crash never leaves a partial share behind
+pub fn write_share(key_file: &str, key_data: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", key_file);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(key_data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, key_file)
+}
+
+/// Overwrites the file at `path` with zeros and flushes it to disk before unlinking it, so that
+/// the share cannot be read back from the blocks it occupied. Returns false if there was no file.
+pub fn shred(path: &str) -> io::Result<bool> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len() as usize;
+    file.write_all(&vec![0; len])?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::remove_file(path)?;
+    Ok(true)
+}
+
+/// Moves the key share stored in `key_file` and its metadata into the archive directory, under the
+/// key file name suffixed with `archived_at`, and returns the path of the archived share. Returns
+/// None if there was no share.
+pub fn archive(key_file: &str, archived_at: u64) -> io::Result<Option<String>> {
+    if !Path::new(key_file).exists() {
+        return Ok(None);
+    }
+    fs::create_dir_all(ARCHIVE_DIR)?;
+    let name = key_file.replace(['/', '\\'], "_");
+    let path = format!("{}/{}.{}", ARCHIVE_DIR, name, archived_at);
+
+    if let Some(record) = key_info(key_file)? {
+        fs::write(format!("{}.meta.json", path), serde_json::to_vec_pretty(&record.to_json(key_file))?)?;
+    }
+    fs::rename(key_file, &path)?;
+    Ok(Some(path))
+}
+
+/// Returns the owner of the key share stored in `key_file`, if one was recorded
+pub fn owner_of(key_file: &str) -> io::Result<Option<String>> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    Ok(load_keys()?.remove(key_file).map(|record| record.owner))
+}
+
+/// Returns the key files of the keys owned by `owner` that were not deleted, in sorted order
+pub fn keys_owned_by(owner: &str) -> io::Result<Vec<String>> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    let mut key_files: Vec<String> = load_keys()?
+        .into_iter()
+        .filter(|(_, record)| record.owner == owner && record.deleted_at.is_none())
+        .map(|(key_file, _)| key_file)
+        .collect();
+    key_files.sort();
+    Ok(key_files)
+}
+
+/// Reassigns every key owned by `from` that was not deleted to `to`, returning the key files that changed hands
+pub fn transfer_keys(from: &str, to: &str) -> io::Result<Vec<String>> {
+    let _guard = KEY_LOCK.lock().unwrap();
+    let mut keys = load_keys()?;
+
+    let mut transferred = vec![];
+    for (key_file, record) in keys.iter_mut() {
+        if record.owner == from && record.deleted_at.is_none() {
+            record.owner = to.to_owned();
+            transferred.push(key_file.clone());
+        }
+    }
+    transferred.sort();
+
+    store_keys(&keys)?;
+    Ok(transferred)
+}
@@ -0,0 +1,232 @@
+//! Leveled logs of the node, written to stderr one event per line, as text or as JSON.
+//!
+//! Every event is tagged with the rank of the node and, on request threads, with the request id,
+//! function, username and ceremony round of the request the thread handles. Nodes receive the
+//! same request id for a request, so the events of one ceremony can be collected from all nodes.
+//!
+//! The gateways include this module too. Their requests do not stay on one thread, so they pass
+//! the fields of each event to [`event`] instead, and have no rank.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parameters that are never logged
+const SECRETS: [&str; 7] = [
+    "password",
+    "new_password",
+    "totp",
+    "totp_secret",
+    "passphrase",
+    "secret_key",
+    "backup",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json,
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    rank: Option<usize>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Request handled by the current thread
+#[derive(Default)]
+struct Context {
+    request_id: Option<String>,
+    function: Option<String>,
+    username: Option<String>,
+    round: Option<u32>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Sets the level and format of the logs of the node with the given rank, or of a gateway if there
+/// is none. Events logged before are written as info-level text.
+pub fn init(level: Level, format: Format, rank: Option<usize>) {
+    let _ = LOGGER.set(Logger { level, format, rank });
+}
+
+/// Tags the events logged by the current thread with the request it handles
+pub fn enter(func_name: &str, params: &Value) {
+    let field = |name: &str| params[name].as_str().map(str::to_owned);
+    CONTEXT.with(|context| {
+        *context.borrow_mut() = Context {
+            request_id: field("request_id"),
+            function: Some(func_name.to_owned()),
+            username: field("username"),
+            round: None,
+        }
+    });
+}
+
+/// Tags the events logged by the current thread with the ceremony round it reached
+pub fn round(round: u32) {
+    CONTEXT.with(|context| context.borrow_mut().round = Some(round));
+    debug(format_args!("Reached round {}", round));
+}
+
+/// Returns the parameters of a request with its secrets replaced, so that they can be logged.
+/// Secrets are replaced at any depth, such as in the objects of a list.
+pub fn redact(params: &Value) -> Value {
+    let mut params = params.clone();
+    redact_in_place(&mut params);
+    params
+}
+
+fn redact_in_place(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if SECRETS.contains(&name.as_str()) {
+                    if !field.is_null() {
+                        *field = Value::String("[redacted]".to_owned());
+                    }
+                } else {
+                    redact_in_place(field);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_in_place),
+        _ => {}
+    }
+}
+
+/// Formats the current time as an RFC 3339 timestamp in UTC, with milliseconds
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let time = secs % 86400;
+
+    // Civil date of the day, counted from 0000-03-01 so that leap days end the year
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis(),
+    )
+}
+
+/// Logs an event tagged with the `extra` fields besides the request of the current thread
+pub fn event(level: Level, extra: &[(&str, Value)], message: impl Display) {
+    let (max_level, format, rank) = match LOGGER.get() {
+        Some(logger) => (logger.level, logger.format, logger.rank),
+        None => (Level::Info, Format::Text, None),
+    };
+    if level > max_level {
+        return;
+    }
+
+    let line = CONTEXT.with(|context| {
+        let context = context.borrow();
+        let mut fields: Vec<(&str, Value)> = vec![];
+        if let Some(rank) = rank {
+            fields.push(("rank", json!(rank)));
+        }
+        if let Some(request_id) = &context.request_id {
+            fields.push(("request_id", json!(request_id)));
+        }
+        if let Some(function) = &context.function {
+            fields.push(("function", json!(function)));
+        }
+        if let Some(username) = &context.username {
+            fields.push(("username", json!(username)));
+        }
+        if let Some(round) = context.round {
+            fields.push(("round", json!(round)));
+        }
+        fields.extend(extra.iter().cloned());
+
+        match format {
+            Format::Text => {
+                let mut line = format!("{} {:<5}", timestamp(), level.name().to_uppercase());
+                for (name, value) in fields {
+                    match value {
+                        Value::String(value) => line.push_str(&format!(" {}={}", name, value)),
+                        value => line.push_str(&format!(" {}={}", name, value)),
+                    }
+                }
+                format!("{} {}", line, message)
+            }
+            Format::Json => {
+                let mut event = json!({
+                    "timestamp": timestamp(),
+                    "level": level.name(),
+                    "message": message.to_string(),
+                });
+                for (name, value) in fields {
+                    event[name] = value;
+                }
+                event.to_string()
+            }
+        }
+    });
+    eprintln!("{}", line);
+}
+
+fn log(level: Level, message: impl Display) {
+    event(level, &[], message)
+}
+
+pub fn error(message: impl Display) {
+    log(Level::Error, message)
+}
+
+pub fn warn(message: impl Display) {
+    log(Level::Warn, message)
+}
+
+pub fn info(message: impl Display) {
+    log(Level::Info, message)
+}
+
+pub fn debug(message: impl Display) {
+    log(Level::Debug, message)
+}
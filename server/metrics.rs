@@ -0,0 +1,233 @@
+//! Counters of the node, served in the Prometheus text format on a local HTTP port.
+//!
+//! Ceremonies are counted by function when they start and end, and the time each of their rounds
+//! takes is observed in a histogram. Request threads track the round their ceremony is in, so
+//! rounds are timed from the moment the ceremony reports one until it reports the next or ends.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+
+/// Upper bounds of the round latency buckets, in seconds
+const ROUND_BUCKETS: [f64; 12] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_THREADS: AtomicI64 = AtomicI64::new(0);
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    started: BTreeMap::new(),
+    completed: BTreeMap::new(),
+    failed: BTreeMap::new(),
+    auth_failures: BTreeMap::new(),
+    rounds: BTreeMap::new(),
+});
+
+struct Metrics {
+    /// Ceremonies by function
+    started: BTreeMap<String, u64>,
+    completed: BTreeMap<String, u64>,
+    failed: BTreeMap<String, u64>,
+    /// Rejected authentication attempts by reason
+    auth_failures: BTreeMap<&'static str, u64>,
+    /// Round latencies by function and round
+    rounds: BTreeMap<(String, u32), Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations that fell in each bucket, not cumulated
+    buckets: [u64; ROUND_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = ROUND_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Ceremony run by the current thread, with the round it is in and when that round started
+struct Ceremony {
+    function: String,
+    round: Option<(u32, Instant)>,
+}
+
+thread_local! {
+    static CEREMONY: RefCell<Option<Ceremony>> = RefCell::new(None);
+}
+
+/// Counts a ceremony started by the current thread
+pub fn ceremony_started(func_name: &str) {
+    *METRICS.lock().unwrap().started.entry(func_name.to_owned()).or_default() += 1;
+    let function = func_name.to_owned();
+    CEREMONY.with(|ceremony| *ceremony.borrow_mut() = Some(Ceremony { function, round: None }));
+}
+
+/// Ends the round the ceremony of the current thread is in, observing how long it took
+fn end_round(ceremony: &mut Ceremony) {
+    if let Some((round, started_at)) = ceremony.round.take() {
+        let mut metrics = METRICS.lock().unwrap();
+        let histogram = metrics.rounds.entry((ceremony.function.clone(), round)).or_default();
+        histogram.observe(started_at.elapsed().as_secs_f64());
+    }
+}
+
+/// Records that the ceremony of the current thread moved on to `round`
+pub fn round(round: u32) {
+    CEREMONY.with(|ceremony| {
+        if let Some(ceremony) = &mut *ceremony.borrow_mut() {
+            end_round(ceremony);
+            ceremony.round = Some((round, Instant::now()));
+        }
+    });
+}
+
+/// Counts the outcome of the ceremony of the current thread
+pub fn ceremony_finished(succeeded: bool) {
+    CEREMONY.with(|ceremony| {
+        if let Some(mut ceremony) = ceremony.borrow_mut().take() {
+            end_round(&mut ceremony);
+            let mut metrics = METRICS.lock().unwrap();
+            let counts = if succeeded { &mut metrics.completed } else { &mut metrics.failed };
+            *counts.entry(ceremony.function).or_default() += 1;
+        }
+    });
+}
+
+/// Counts a rejected authentication attempt
+pub fn auth_failure(reason: &'static str) {
+    *METRICS.lock().unwrap().auth_failures.entry(reason).or_default() += 1;
+}
+
+/// Counts a message sent to another node
+pub fn sent(len: usize) {
+    BYTES_SENT.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+/// Counts a message received from another node into `buf`, which is zero-padded after it
+pub fn received(buf: &[u8]) {
+    let len = buf.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    BYTES_RECEIVED.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+/// Counts the current thread as handling a request until the returned guard is dropped
+pub fn active_thread() -> ActiveThread {
+    ACTIVE_THREADS.fetch_add(1, Ordering::Relaxed);
+    ActiveThread(())
+}
+
+pub struct ActiveThread(());
+
+impl Drop for ActiveThread {
+    fn drop(&mut self) {
+        ACTIVE_THREADS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    let counters = [
+        ("signing_ceremonies_started_total", "Ceremonies started, by function", &metrics.started),
+        ("signing_ceremonies_completed_total", "Ceremonies that succeeded, by function", &metrics.completed),
+        ("signing_ceremonies_failed_total", "Ceremonies that failed, by function", &metrics.failed),
+    ];
+    for (name, help, counts) in counters {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (function, count) in counts {
+            let _ = writeln!(out, "{}{{function=\"{}\"}} {}", name, function, count);
+        }
+    }
+
+    let name = "signing_round_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time each round of a ceremony took\n# TYPE {} histogram", name, name);
+    for ((function, round), histogram) in &metrics.rounds {
+        let labels = format!("function=\"{}\",round=\"{}\"", function, round);
+        let mut cumulative = 0;
+        for (bound, count) in ROUND_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+
+    let name = "signing_auth_failures_total";
+    let _ = writeln!(out, "# HELP {} Rejected authentication attempts, by reason\n# TYPE {} counter", name, name);
+    for (reason, count) in &metrics.auth_failures {
+        let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, reason, count);
+    }
+
+    let totals = [
+        ("signing_libdots_sent_bytes_total", "Bytes sent to other nodes in ceremonies", &BYTES_SENT),
+        ("signing_libdots_received_bytes_total", "Bytes received from other nodes in ceremonies", &BYTES_RECEIVED),
+    ];
+    for (name, help, total) in totals {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        let _ = writeln!(out, "{} {}", name, total.load(Ordering::Relaxed));
+    }
+
+    let name = "signing_active_threads";
+    let _ = writeln!(out, "# HELP {} Threads handling a request\n# TYPE {} gauge", name, name);
+    let _ = writeln!(out, "{} {}", name, ACTIVE_THREADS.load(Ordering::Relaxed));
+    out
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..len]);
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", render()),
+        _ => ("404 Not Found", "only /metrics is served\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    )
+}
+
+/// Serves the metrics at `/metrics` on `listen` from a background thread. Each connection gets a
+/// thread of its own, so a scraper that connects and sends nothing does not hold up the others.
+pub fn serve(listen: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    logging::info(format_args!("Serving metrics on {}", listener.local_addr()?));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    logging::debug(format_args!("Failed to accept metrics connection: {}", e));
+                    continue;
+                }
+            };
+            thread::spawn(move || {
+                if let Err(e) = respond(stream) {
+                    logging::debug(format_args!("Failed to serve metrics: {}", e));
+                }
+            });
+        }
+    });
+    Ok(())
+}
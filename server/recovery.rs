@@ -0,0 +1,371 @@
+//! Rebuilds the share of a party that lost its key file from the shares of t + 1 other parties.
+//!
+//! Every helper k sends the lost party j its share weighted by its Lagrange coefficient at j, so
+//! that the contributions sum to j's share. Before sending, each pair of helpers agrees on a random
+//! mask that one of them adds and the other subtracts. The lost party therefore only learns the
+//! sum, and no helper learns anything about another helper's share.
+//!
+//! The Paillier key of the lost party cannot be recovered, so the new node generates a fresh one
+//! together with its range proof parameters. Every other party checks the proofs, the new node
+//! only stores its share once all of them accepted, and they replace the old key in their own
+//! shares once the new node confirms that its share was stored.
+
+use curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::{KeyGenBroadcastMessage1, Keys};
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zk_paillier::zkproofs::{DLogStatement, SALT_STRING};
+
+use crate::jobs;
+use crate::keys::{self, KeyRecord};
+use crate::logging;
+use crate::metrics;
+
+/// Size of the receive buffer, which must hold the public key material of every party
+const RECOVERY_MSG_SIZE: usize = 1 << 20;
+
+/// Every message either carries the next step of the ceremony or the reason the sender gave up,
+/// so that a failing party does not leave the others waiting
+type Message<T> = Result<T, String>;
+
+/// Share of an existing party and its metadata, or why it could not be read
+type LoadedKey = Result<(LocalKey<Secp256k1>, KeyRecord), Box<dyn Error>>;
+
+/// Contribution of a helper to the lost share
+#[derive(Serialize, Deserialize)]
+struct HelperShare {
+    /// Weighted and masked share of the helper
+    share: Scalar<Secp256k1>,
+    /// Public key material and metadata of the key, which all helpers must agree on
+    public: Value,
+}
+
+/// One run of the ceremony, as seen by party `party_index`
+pub struct Ceremony<'a> {
+    pub key_file: &'a str,
+    /// Party whose share is rebuilt, running on the freshly provisioned node
+    pub lost_party: u16,
+    /// The t + 1 parties whose shares are used
+    pub helpers: &'a [u16],
+    pub num_parties: u16,
+    pub party_index: u16,
+}
+
+fn send<T: Serialize>(message: &Message<T>, party: u16) -> Result<(), Box<dyn Error>> {
+    let serialized = serde_json::to_string(message)?;
+    libdots::msg::send(serialized.as_bytes(), party as usize - 1, 0)?;
+    metrics::sent(serialized.len());
+    Ok(())
+}
+
+fn recv<T: DeserializeOwned>(party: u16) -> Result<T, Box<dyn Error>> {
+    let mut buf = vec![0u8; RECOVERY_MSG_SIZE];
+    libdots::msg::recv(&mut buf, party as usize - 1, 0)?;
+    metrics::received(&buf);
+    let message: Message<T> = serde_json::from_str(String::from_utf8_lossy(&buf).trim_matches(char::from(0)))?;
+    message.map_err(|reason| io::Error::new(ErrorKind::Other, format!("party {} gave up: {}", party, reason)).into())
+}
+
+/// Lagrange coefficient of `party` for interpolating the polynomial through `parties` at `at`
+fn lagrange_coefficient(party: u16, at: u16, parties: &[u16]) -> Scalar<Secp256k1> {
+    let mut coefficient = Scalar::from(1u16);
+    for other in parties.iter().filter(|other| **other != party) {
+        let numerator = Scalar::from(at) - Scalar::from(*other);
+        let denominator = Scalar::from(party) - Scalar::from(*other);
+        coefficient = coefficient * numerator * denominator.invert().expect("parties are distinct");
+    }
+    coefficient
+}
+
+/// Returns the share without its secrets and the key metadata, which the lost party rebuilds its
+/// share from
+fn public_data(key: &LocalKey<Secp256k1>, record: &KeyRecord) -> Result<Value, Box<dyn Error>> {
+    let mut public = serde_json::to_value(key)?;
+    let fields = public.as_object_mut().ok_or("key share is not a JSON object")?;
+    fields.remove("paillier_dk");
+    fields.remove("keys_linear");
+    fields.remove("i");
+
+    Ok(json!({
+        "key": public,
+        "id": record.id,
+        "owner": record.owner,
+        "refresh_epoch": record.refresh_epoch,
+    }))
+}
+
+/// Checks the proofs that the new Paillier key and range proof parameters are well formed
+fn verify_new_keys(bc1: &KeyGenBroadcastMessage1) -> io::Result<()> {
+    let statement_base_h2 = DLogStatement {
+        N: bc1.dlog_statement.N.clone(),
+        g: bc1.dlog_statement.ni.clone(),
+        ni: bc1.dlog_statement.g.clone(),
+    };
+    let valid = bc1.correct_key_proof.verify(&bc1.e, SALT_STRING).is_ok()
+        && bc1.composite_dlog_proof_base_h1.verify(&bc1.dlog_statement).is_ok()
+        && bc1.composite_dlog_proof_base_h2.verify(&statement_base_h2).is_ok();
+    if !valid {
+        return Err(io::Error::new(ErrorKind::InvalidData, "new party sent invalid key proofs"));
+    }
+    Ok(())
+}
+
+fn write_key(key_file: &str, key: &LocalKey<Secp256k1>) -> io::Result<()> {
+    keys::write_share(key_file, &serde_json::to_vec_pretty(key)?)
+}
+
+/// Puts the new Paillier key and range proof parameters of the lost party into `key`
+fn replace_new_keys(
+    key: &mut LocalKey<Secp256k1>,
+    lost_party: u16,
+    bc1: &KeyGenBroadcastMessage1,
+) -> Result<(), Box<dyn Error>> {
+    let lost = lost_party as usize - 1;
+    match (key.paillier_key_vec.get_mut(lost), key.h1_h2_n_tilde_vec.get_mut(lost)) {
+        (Some(paillier_key), Some(dlog_statement)) => {
+            *paillier_key = bc1.e.clone();
+            *dlog_statement = bc1.dlog_statement.clone();
+            Ok(())
+        }
+        _ => Err(format!("key has no party {}", lost_party).into()),
+    }
+}
+
+impl Ceremony<'_> {
+    /// Checks the parties of the ceremony. All nodes receive the same parameters, so they all
+    /// reject an invalid ceremony before exchanging any message.
+    fn validate(&self) -> io::Result<()> {
+        let in_range = |party: u16| (1..=self.num_parties).contains(&party);
+        let invalid = |message: String| Err(io::Error::new(ErrorKind::InvalidInput, message));
+
+        if !in_range(self.lost_party) || !in_range(self.party_index) {
+            return invalid(format!("lost party must be between 1 and {}", self.num_parties));
+        }
+        if self.helpers.is_empty() {
+            return invalid("no helpers given".to_owned());
+        }
+        for (i, helper) in self.helpers.iter().enumerate() {
+            if !in_range(*helper) || *helper == self.lost_party || self.helpers[..i].contains(helper) {
+                return invalid(format!("invalid helper {}", helper));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the checks of this party before the ceremony. The lost party also refuses to replace
+    /// a share or a deleted key.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        self.validate()?;
+        if self.party_index == self.lost_party {
+            let in_use = fs::metadata(self.key_file).is_ok()
+                || keys::key_info(self.key_file)?.map_or(false, |record| record.deleted_at.is_some());
+            if in_use {
+                let reason = format!("party {} still holds {} or the key was deleted", self.lost_party, self.key_file);
+                return Err(io::Error::new(ErrorKind::AlreadyExists, reason).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs this party's side of the ceremony, once every party passed [`Ceremony::check`]
+    pub fn run(&self) -> Result<Value, Box<dyn Error>> {
+        self.validate()?;
+        if self.party_index == self.lost_party {
+            self.rebuild()
+        } else {
+            self.assist()
+        }
+    }
+
+    fn others(&self) -> impl Iterator<Item = u16> + '_ {
+        (1..=self.num_parties).filter(move |party| *party != self.party_index)
+    }
+
+    /// Side of the lost party: collects the contributions and assembles the new share
+    fn rebuild(&self) -> Result<Value, Box<dyn Error>> {
+        jobs::round(1);
+        let new_keys = Keys::create(self.lost_party as usize);
+        let (bc1, _) = new_keys.phase1_broadcast_phase3_proof_of_correct_key_proof_of_correct_h1h2();
+        for party in self.others() {
+            send(&Ok(&bc1), party)?;
+        }
+
+        jobs::round(2);
+        let assembled = self.assemble(&new_keys, &bc1);
+        jobs::round(3);
+        let message: Message<()> = assembled.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        for party in self.others() {
+            send(&message, party)?;
+        }
+        let (key, record) = assembled?;
+
+        // The share is only stored once every other party checked the proofs of the new keys, and
+        // the other parties wait for it to be stored before replacing the old Paillier key
+        jobs::round(4);
+        let verdicts: Vec<Result<(), Box<dyn Error>>> = self.others().map(recv::<()>).collect();
+        let stored = verdicts.into_iter().collect::<Result<(), _>>().and_then(|_| {
+            write_key(self.key_file, &key)?;
+            keys::record_key(self.key_file, record)?;
+            Ok(())
+        });
+        jobs::round(5);
+        let message: Message<()> = stored.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        for party in self.others() {
+            send(&message, party)?;
+        }
+
+        stored?;
+        logging::info(format_args!("Rebuilt share of party {} for {}", self.lost_party, self.key_file));
+        Ok(json!({
+            "key_file": self.key_file,
+            "public_key": hex::encode(key.public_key().to_bytes(true)),
+        }))
+    }
+
+    fn assemble(
+        &self,
+        new_keys: &Keys,
+        bc1: &KeyGenBroadcastMessage1,
+    ) -> Result<(LocalKey<Secp256k1>, KeyRecord), Box<dyn Error>> {
+        // Every contribution is received before any is checked, so that no helper is left waiting
+        let contributions: Vec<Result<HelperShare, Box<dyn Error>>> =
+            self.helpers.iter().map(|helper| recv(*helper)).collect();
+
+        let mut x_i = Scalar::<Secp256k1>::zero();
+        let mut public: Option<Value> = None;
+        for (helper, contribution) in self.helpers.iter().zip(contributions) {
+            let contribution = contribution?;
+            x_i = x_i + &contribution.share;
+            match &public {
+                None => public = Some(contribution.public),
+                Some(public) if *public != contribution.public => {
+                    return Err(format!("helper {} holds different public key material", helper).into());
+                }
+                Some(_) => {}
+            }
+        }
+        let public = public.ok_or("no helpers given")?;
+
+        let mut key_json = public["key"].clone();
+        key_json["paillier_dk"] = serde_json::to_value(&new_keys.dk)?;
+        key_json["keys_linear"] = json!({ "y": key_json["y_sum_s"].clone(), "x_i": x_i });
+        key_json["i"] = json!(self.lost_party);
+        let mut key: LocalKey<Secp256k1> = serde_json::from_value(key_json)?;
+        if key.n != self.num_parties {
+            return Err(format!("key has {} parties but the ceremony names {}", key.n, self.num_parties).into());
+        }
+        replace_new_keys(&mut key, self.lost_party, bc1)?;
+
+        if self.helpers.len() != key.t as usize + 1 {
+            return Err(format!("{} helpers given but the key needs {}", self.helpers.len(), key.t + 1).into());
+        }
+        if key.pk_vec.get(self.lost_party as usize - 1) != Some(&(Point::generator() * &key.keys_linear.x_i)) {
+            return Err("rebuilt share does not match the public share of the lost party".into());
+        }
+
+        let record = KeyRecord {
+            id: public["id"].as_str().unwrap_or_default().to_owned(),
+            owner: public["owner"].as_str().unwrap_or_default().to_owned(),
+            num_parties: key.n,
+            threshold: key.t,
+            public_key: hex::encode(key.public_key().to_bytes(true)),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            refresh_epoch: public["refresh_epoch"].as_u64().unwrap_or_default(),
+            party_index: self.lost_party,
+            party_indices: (1..=key.n).collect(),
+            deleted_at: None,
+        };
+        Ok((key, record))
+    }
+
+    fn load_key(&self) -> LoadedKey {
+        let key: LocalKey<Secp256k1> = serde_json::from_slice(&fs::read(self.key_file)?)?;
+        if key.i != self.party_index || key.n != self.num_parties {
+            return Err(format!("{} does not hold the share of party {}", self.key_file, self.party_index).into());
+        }
+        Ok((key, keys::key_info(self.key_file)?.unwrap_or_default()))
+    }
+
+    /// Side of every other party: contributes a share if it is a helper, then accepts the new
+    /// Paillier key of the lost party
+    fn assist(&self) -> Result<Value, Box<dyn Error>> {
+        jobs::round(1);
+        let bc1: KeyGenBroadcastMessage1 = recv(self.lost_party)?;
+        let loaded = self.load_key();
+
+        jobs::round(2);
+        let helper = self.helpers.contains(&self.party_index);
+        let contributed = if helper { self.contribute(&loaded) } else { Ok(()) };
+        let verified = verify_new_keys(&bc1);
+
+        jobs::round(3);
+        recv::<()>(self.lost_party)?;
+
+        // Tell the lost party whether this party accepts the new keys, then wait for it to store
+        // its share before replacing the old key
+        jobs::round(4);
+        let accepted = contributed.and(verified.map_err(Into::into)).and(loaded).and_then(|(mut key, _)| {
+            replace_new_keys(&mut key, self.lost_party, &bc1)?;
+            Ok(key)
+        });
+        let verdict: Message<()> = accepted.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        send(&verdict, self.lost_party)?;
+        let key = accepted?;
+
+        jobs::round(5);
+        recv::<()>(self.lost_party)?;
+        write_key(self.key_file, &key)?;
+        logging::info(format_args!("Accepted the new key of party {} for {}", self.lost_party, self.key_file));
+
+        Ok(json!({
+            "key_file": self.key_file,
+            "public_key": hex::encode(key.public_key().to_bytes(true)),
+        }))
+    }
+
+    /// Sends the weighted and masked share of this helper to the lost party
+    fn contribute(&self, loaded: &LoadedKey) -> Result<(), Box<dyn Error>> {
+        let contribution = match loaded {
+            Ok((key, record)) if self.helpers.len() == key.t as usize + 1 => {
+                let weight = lagrange_coefficient(self.party_index, self.lost_party, self.helpers);
+                public_data(key, record)
+                    .map(|public| (weight * &key.keys_linear.x_i, public))
+                    .map_err(|e| e.to_string())
+            }
+            Ok((key, _)) => Err(format!("{} helpers given but the key needs {}", self.helpers.len(), key.t + 1)),
+            Err(e) => Err(e.to_string()),
+        };
+
+        // Add a mask for every later helper and subtract the mask of every earlier one
+        let mut masks = Scalar::<Secp256k1>::zero();
+        let mut failure = contribution.as_ref().err().cloned();
+        for other in self.helpers.iter().filter(|other| **other > self.party_index) {
+            let mask = Scalar::random();
+            let message: Message<&Scalar<Secp256k1>> = match &failure {
+                Some(reason) => Err(reason.clone()),
+                None => Ok(&mask),
+            };
+            send(&message, *other)?;
+            masks = masks + &mask;
+        }
+        for other in self.helpers.iter().filter(|other| **other < self.party_index) {
+            match recv::<Scalar<Secp256k1>>(*other) {
+                Ok(mask) => masks = masks - &mask,
+                Err(e) => failure = failure.or(Some(e.to_string())),
+            }
+        }
+
+        let message: Message<HelperShare> = match (failure, contribution) {
+            (None, Ok((share, public))) => Ok(HelperShare { share: share + &masks, public }),
+            (Some(reason), _) | (None, Err(reason)) => Err(reason),
+        };
+        send(&message, self.lost_party)?;
+        message.map(|_| ()).map_err(|reason| io::Error::new(ErrorKind::Other, reason).into())
+    }
+}
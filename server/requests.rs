@@ -0,0 +1,227 @@
+//! Results of completed requests, so that a retried request is answered with the result of the
+//! first run instead of running again.
+//!
+//! A request is identified by its `request_id`. Its fingerprint is the hash of its function and
+//! parameters, apart from the fields that identify the connection or the login attempt, so that a
+//! retry matches the original while another request that reuses the id does not. Requests that
+//! only read are not tracked, since running them twice is harmless.
+//!
+//! A retry is answered as its user, whose password hash at the time of the first run is kept
+//! with the request, so that a retried password change still authenticates with the old password.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REQUEST_DATA: &str = "requests.json";
+
+/// How long the results of completed requests are kept, in seconds
+const RETENTION: u64 = 24 * 60 * 60;
+
+/// Functions that change nothing on the node
+const READ_ONLY: [&str; 7] = [
+    "login",
+    "list_users",
+    "list_keys",
+    "key_info",
+    "pubkey",
+    "export_backup",
+    "job_status",
+];
+
+/// Parameters that may differ between a request and its retry
+const VOLATILE: [&str; 5] = ["request_id", "client_id", "password", "totp", "detach"];
+
+/// Secret parameters, left out of fingerprints so that the stored results hold nothing they could
+/// be guessed from
+const SECRETS: [&str; 4] = ["new_password", "totp_secret", "passphrase", "secret_key"];
+
+/// Serializes read-modify-write cycles on the stored results between request threads
+static REQUEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Fingerprints and callers of the tracked requests that are running, by request id
+static RUNNING: Mutex<BTreeMap<String, (String, Option<Caller>)>> = Mutex::new(BTreeMap::new());
+
+/// User who sent the first run of a request, as its retries must authenticate
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Caller {
+    pub username: String,
+    /// bcrypt hash of the user's password when the request was claimed
+    pub password_hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Completed {
+    function: String,
+    fingerprint: String,
+    /// Caller of the first run, None if the user did not exist when the request was claimed
+    #[serde(default)]
+    caller: Option<Caller>,
+    /// Seconds since the Unix epoch at which the request completed
+    completed_at: u64,
+    result: Value,
+}
+
+/// What to do with an incoming request
+pub enum Claim {
+    /// The request is not tracked
+    Untracked,
+    /// The request is new and was claimed under its id, which must be passed to [`complete`]
+    New(String),
+    /// A request with the same id and parameters is still running, sent by this caller
+    Running(Option<Caller>),
+    /// A request with the same id and parameters sent by this caller completed with this result
+    Completed(Value, Option<Caller>),
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn fingerprint(func_name: &str, params: &Value) -> String {
+    let mut params = params.clone();
+    if let Some(params) = params.as_object_mut() {
+        for field in VOLATILE.iter().chain(&SECRETS) {
+            params.remove(*field);
+        }
+    }
+    // Objects serialize with sorted keys, so equal parameters hash equally
+    let mut hasher = Sha256::new();
+    hasher.update(func_name.as_bytes());
+    hasher.update([0]);
+    hasher.update(serde_json::to_vec(&params).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+fn load_requests() -> io::Result<HashMap<String, Completed>> {
+    match fs::read(REQUEST_DATA) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid request store {}: {}", REQUEST_DATA, e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn store_requests(requests: &HashMap<String, Completed>) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", REQUEST_DATA);
+    fs::write(&tmp_path, serde_json::to_vec(requests)?)?;
+    fs::rename(&tmp_path, REQUEST_DATA)
+}
+
+fn conflict(id: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("request id {} was already used for a different request", id),
+    )
+}
+
+/// Looks up the id of an incoming request among the running and completed requests, and claims
+/// it if the request is new, for the user whose password currently has `password_hash`. Fails if
+/// the id belongs to a request with other parameters.
+pub fn claim(func_name: &str, params: &Value, password_hash: Option<String>) -> io::Result<Claim> {
+    let id = match params["request_id"].as_str() {
+        Some(id) if !READ_ONLY.contains(&func_name) => id,
+        _ => return Ok(Claim::Untracked),
+    };
+    let fingerprint = fingerprint(func_name, params);
+
+    let _guard = REQUEST_LOCK.lock().unwrap();
+    if let Some(completed) = load_requests()?.remove(id) {
+        if completed.fingerprint != fingerprint {
+            return Err(conflict(id));
+        }
+        return Ok(Claim::Completed(completed.result, completed.caller));
+    }
+
+    let mut running = RUNNING.lock().unwrap();
+    match running.get(id) {
+        Some((running, _)) if *running != fingerprint => Err(conflict(id)),
+        Some((_, caller)) => Ok(Claim::Running(caller.clone())),
+        None => {
+            let username = params["username"].as_str().unwrap_or_default().to_owned();
+            let caller = password_hash.map(|password_hash| Caller { username, password_hash });
+            running.insert(id.to_owned(), (fingerprint, caller));
+            Ok(Claim::New(id.to_owned()))
+        }
+    }
+}
+
+/// Releases a claimed request and stores its result if it succeeded. A request that failed may be
+/// retried under the same id.
+pub fn complete(id: &str, func_name: &str, result: Option<&Value>) -> io::Result<()> {
+    let _guard = REQUEST_LOCK.lock().unwrap();
+    let (fingerprint, caller) = match RUNNING.lock().unwrap().remove(id) {
+        Some(running) => running,
+        None => return Ok(()),
+    };
+    let result = match result {
+        Some(result) => result.clone(),
+        None => return Ok(()),
+    };
+
+    let now = unix_time();
+    let mut requests = load_requests()?;
+    requests.retain(|_, completed| completed.completed_at + RETENTION > now);
+    let completed = Completed { function: func_name.to_owned(), fingerprint, caller, completed_at: now, result };
+    requests.insert(id.to_owned(), completed);
+    store_requests(&requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn retries_have_the_same_fingerprint() {
+        let request = json!({
+            "request_id": "1",
+            "client_id": "a",
+            "username": "alice",
+            "password": "first",
+            "totp": "123456",
+            "key_file": "key",
+            "digest": "ab",
+        });
+        let retry = json!({
+            "digest": "ab",
+            "key_file": "key",
+            "username": "alice",
+            "password": "second",
+            "client_id": "b",
+            "request_id": "1",
+            "detach": true,
+        });
+        assert_eq!(fingerprint("sign", &request), fingerprint("sign", &retry));
+    }
+
+    #[test]
+    fn secrets_are_not_fingerprinted() {
+        let request = json!({ "username": "alice", "new_password": "secret" });
+        let guess = json!({ "username": "alice", "new_password": "guess" });
+        let without = json!({ "username": "alice" });
+        assert_eq!(fingerprint("change_password", &request), fingerprint("change_password", &guess));
+        assert_eq!(fingerprint("change_password", &request), fingerprint("change_password", &without));
+
+        let backup = json!({ "key_file": "key", "passphrase": "secret", "secret_key": "01" });
+        assert_eq!(fingerprint("import_backup", &backup), fingerprint("import_backup", &json!({ "key_file": "key" })));
+    }
+
+    #[test]
+    fn other_requests_have_other_fingerprints() {
+        let request = json!({ "request_id": "1", "username": "alice", "key_file": "key", "digest": "ab" });
+        let other_digest = json!({ "request_id": "1", "username": "alice", "key_file": "key", "digest": "cd" });
+        let other_user = json!({ "request_id": "1", "username": "bob", "key_file": "key", "digest": "ab" });
+        let nested = json!({ "request_id": "1", "username": "alice", "key_file": "key", "digest": ["ab"] });
+        assert_ne!(fingerprint("sign", &request), fingerprint("sign", &other_digest));
+        assert_ne!(fingerprint("sign", &request), fingerprint("sign", &other_user));
+        assert_ne!(fingerprint("sign", &request), fingerprint("sign", &nested));
+        assert_ne!(fingerprint("sign", &request), fingerprint("sign_psbt", &request));
+    }
+}
@@ -0,0 +1,126 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::io::{self, ErrorKind};
+use subtle::ConstantTimeEq;
+
+/// Length of a time step in seconds
+const STEP_SECS: u64 = 30;
+/// Number of digits of a one-time code
+const DIGITS: usize = 6;
+/// Number of time steps a code may lag behind or run ahead of the node's clock
+const SKEW_STEPS: u64 = 1;
+/// Minimum secret length in bytes recommended by RFC 4226
+const MIN_SECRET_LEN: usize = 16;
+
+/// Decodes a base32 TOTP secret as shown to authenticator apps
+pub fn decode_secret(secret: &str) -> io::Result<Vec<u8>> {
+    let normalized = secret.trim_end_matches('=').to_ascii_uppercase();
+    let key = BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("invalid TOTP secret: {}", e)))?;
+    if key.len() < MIN_SECRET_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("TOTP secret must be at least {} bytes", MIN_SECRET_LEN),
+        ));
+    }
+    Ok(key)
+}
+
+/// Computes the HOTP value of RFC 4226 for the given counter
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS as u32)
+}
+
+/// Checks `code` against the codes valid around Unix time `now` and returns the time step it belongs to.
+/// Every valid code is compared in constant time, so that timing does not reveal how close a guess was.
+pub fn verify(key: &[u8], code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP_SECS;
+    let mut matched = None;
+    for step in current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS {
+        let expected = format!("{:0width$}", hotp(key, step), width = DIGITS);
+        if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) && matched.is_none() {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the test vectors of RFC 4226 and RFC 6238
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        // RFC 4226, appendix D
+        let expected = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64), code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn verify_matches_rfc_6238() {
+        // RFC 6238, appendix B, SHA-1, truncated to the last six of the eight digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (now, code) in vectors {
+            assert_eq!(verify(RFC_SECRET, code, now), Some(now / STEP_SECS), "time {}", now);
+        }
+    }
+
+    #[test]
+    fn verify_allows_one_step_of_skew() {
+        assert_eq!(verify(RFC_SECRET, "287082", 89), Some(1));
+        assert_eq!(verify(RFC_SECRET, "287082", 0), Some(1));
+        assert_eq!(verify(RFC_SECRET, "287082", 90), None);
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        assert_eq!(verify(RFC_SECRET, "28708", 59), None);
+        assert_eq!(verify(RFC_SECRET, "94287082", 59), None);
+        assert_eq!(verify(RFC_SECRET, "28708a", 59), None);
+        assert_eq!(verify(RFC_SECRET, "287083", 59), None);
+    }
+
+    #[test]
+    fn decode_secret_accepts_base32_in_any_case() {
+        let encoded = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(decode_secret(encoded).unwrap(), RFC_SECRET);
+        assert_eq!(decode_secret(&encoded.to_lowercase()).unwrap(), RFC_SECRET);
+        assert_eq!(decode_secret(&format!("{}====", encoded)).unwrap(), RFC_SECRET);
+    }
+
+    #[test]
+    fn decode_secret_rejects_short_secrets() {
+        assert!(decode_secret("GEZDGNBVGY3TQOJQ").is_err());
+        assert!(decode_secret("not base32!").is_err());
+    }
+}
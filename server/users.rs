@@ -0,0 +1,564 @@
+use bcrypt::{hash, verify, DEFAULT_COST};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use signing::FailureKind;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{BootstrapAdmin, LockoutConfig};
+use crate::logging;
+use crate::totp;
+
+const USER_DATA: &str = "users.json";
+const USER_DATA_VERSION: u32 = 2;
+
+/// Serializes read-modify-write cycles on the user store between request threads
+static USER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Role of a user, deciding which operations it may perform
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Manages users and may perform every operation
+    Admin,
+    /// Manages the lifecycle of keys without signing with them: generates, views, backs up,
+    /// restores and deletes keys of every user
+    Operator,
+    /// Generates keys and signs with them
+    #[default]
+    Signer,
+    /// Has read-only access to the node state
+    Auditor,
+}
+
+/// Operations that are restricted to some roles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    ManageUsers,
+    ViewUsers,
+    /// View the metadata of keys owned by other users
+    ViewKeys,
+    /// Delete keys owned by other users
+    ManageKeys,
+    /// Back up, restore and rebuild key shares
+    BackupKeys,
+    Keygen,
+    Sign,
+}
+
+impl Role {
+    pub fn allows(self, permission: Permission) -> bool {
+        match (self, permission) {
+            (Role::Admin, _) => true,
+            (
+                Role::Operator,
+                Permission::Keygen | Permission::ViewKeys | Permission::ManageKeys | Permission::BackupKeys,
+            ) => true,
+            (Role::Signer, Permission::Keygen | Permission::Sign) => true,
+            (Role::Auditor, Permission::ViewUsers | Permission::ViewKeys) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Signer => "signer",
+            Role::Auditor => "auditor",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Role {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "operator" => Ok(Role::Operator),
+            "signer" => Ok(Role::Signer),
+            "auditor" => Ok(Role::Auditor),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, format!("unknown role {}", s))),
+        }
+    }
+}
+
+/// Login that was refused, carried inside the `PermissionDenied` error so that the node can report
+/// a wrong credential apart from a throttled attempt
+#[derive(Debug)]
+pub struct LoginRefused {
+    pub kind: FailureKind,
+    message: String,
+}
+
+impl fmt::Display for LoginRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for LoginRefused {}
+
+/// Builds the error of a refused login
+pub fn login_refused(kind: FailureKind, message: String) -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, LoginRefused { kind, message })
+}
+
+/// Failed login attempts of a user
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Throttle {
+    /// Consecutive failures since the last success or lockout
+    pub failed_attempts: u32,
+    /// Unix time of the last failure
+    pub last_failure: u64,
+    /// Unix time until which every attempt is rejected
+    pub locked_until: u64,
+}
+
+impl Throttle {
+    /// Returns the Unix time before which no further attempt is accepted
+    fn retry_at(&self, lockout: &LockoutConfig) -> u64 {
+        let backoff_until = if self.failed_attempts == 0 {
+            0
+        } else {
+            let exponent = (self.failed_attempts - 1).min(32);
+            let delay = lockout
+                .base_delay_secs
+                .saturating_mul(1 << exponent)
+                .min(lockout.max_delay_secs);
+            self.last_failure.saturating_add(delay)
+        };
+        backoff_until.max(self.locked_until)
+    }
+
+    /// Fails if an attempt at `now` comes too early
+    fn check(&self, name: &str, now: u64, lockout: &LockoutConfig) -> io::Result<()> {
+        let retry_at = self.retry_at(lockout);
+        if now < retry_at {
+            return Err(login_refused(
+                FailureKind::Throttled,
+                format!("too many failed logins for {}, retry in {} seconds", name, retry_at - now),
+            ));
+        }
+        Ok(())
+    }
+
+    fn record_failure(&mut self, now: u64, lockout: &LockoutConfig) {
+        self.failed_attempts += 1;
+        self.last_failure = now;
+        if self.failed_attempts >= lockout.max_attempts {
+            self.failed_attempts = 0;
+            self.locked_until = now.saturating_add(lockout.lockout_secs);
+        }
+    }
+}
+
+/// A single entry of the user store
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub throttle: Throttle,
+    /// Base32 TOTP secret, if the user enrolled a second factor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// Last TOTP time step accepted, so that a code cannot be replayed
+    #[serde(default)]
+    pub totp_last_step: u64,
+}
+
+impl UserRecord {
+    fn new(password_hash: String, role: Role) -> Self {
+        UserRecord {
+            password_hash,
+            role,
+            throttle: Throttle::default(),
+            totp_secret: None,
+            totp_last_step: 0,
+        }
+    }
+}
+
+/// Contents of the user store
+#[derive(Default, Serialize, Deserialize)]
+struct UserStore {
+    version: u32,
+    users: HashMap<String, UserRecord>,
+}
+
+/// Reads the user store, upgrading stores written by older versions that mapped usernames to bcrypt hashes
+fn load_store() -> io::Result<UserStore> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(USER_DATA)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    // The file is created empty on first use, but a store that does not parse is never replaced,
+    // since saving over it would drop every user
+    let data: HashMap<String, Value> = if contents.trim().is_empty() {
+        HashMap::new()
+    } else {
+        serde_json::from_str(&contents).map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid user store {}: {}", USER_DATA, e))
+        })?
+    };
+
+    if data.contains_key("version") {
+        return Ok(serde_json::from_str(&contents)?);
+    }
+
+    let mut store = UserStore::default();
+    for (username, value) in data {
+        let record = match value {
+            Value::String(password_hash) => UserRecord::new(password_hash, Role::default()),
+            value => serde_json::from_value(value)?,
+        };
+        store.users.insert(username, record);
+    }
+    Ok(store)
+}
+
+/// Atomically replaces the user store with `store`
+fn save_store(store: &mut UserStore) -> io::Result<()> {
+    store.version = USER_DATA_VERSION;
+    let tmp_path = format!("{}.tmp", USER_DATA);
+    fs::write(&tmp_path, serde_json::to_vec(store)?)?;
+    fs::rename(&tmp_path, USER_DATA)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn hash_password(password: &str) -> io::Result<String> {
+    hash(password, DEFAULT_COST).map_err(|e| io::Error::new(ErrorKind::Other, e))
+}
+
+/// Hash that passwords of unknown users are checked against, so that a login takes as long whether
+/// or not the user exists
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("dummy password").expect("bcrypt hashes any password"))
+}
+
+/// Reserves a login attempt of `username`: fails if the user is backing off or locked out, and
+/// otherwise counts the attempt as failed until the password is found to match. Concurrent
+/// attempts therefore each wait for the backoff of those before them. Returns the user, if it exists.
+fn reserve_attempt(username: &str, lockout: &LockoutConfig) -> io::Result<Option<UserRecord>> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+    let now = unix_time();
+
+    let user = match store.users.get_mut(username) {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    user.throttle.check(&format!("user {}", username), now, lockout)?;
+    user.throttle.record_failure(now, lockout);
+    let user = user.clone();
+    save_store(&mut store)?;
+    Ok(Some(user))
+}
+
+/// Clears the failed attempts of `username` after a password matched
+fn release_attempt(username: &str) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+    if let Some(user) = store.users.get_mut(username) {
+        user.throttle = Throttle::default();
+    }
+    save_store(&mut store)
+}
+
+/// Creates the bootstrap administrator from the node configuration unless a user of that name exists
+pub fn ensure_bootstrap_admin(admin: &BootstrapAdmin) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    if store.users.contains_key(&admin.username) {
+        return Ok(());
+    }
+
+    let password_hash = match (&admin.password_hash, &admin.password) {
+        (Some(password_hash), _) => password_hash.clone(),
+        (None, Some(password)) => hash_password(password)?,
+        (None, None) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "bootstrap_admin needs a password or password_hash",
+            ))
+        }
+    };
+    store.users.insert(admin.username.clone(), UserRecord::new(password_hash, Role::Admin));
+    save_store(&mut store)?;
+
+    logging::info(format_args!("Bootstrap admin {} created", admin.username));
+    Ok(())
+}
+
+/// Registers a new user, enrolling the base32 `totp_secret` as its second factor if given
+pub fn register_user(
+    username: &str,
+    password: &str,
+    role: Role,
+    totp_secret: Option<&str>,
+) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    // Check if the user is already registered
+    if store.users.contains_key(username) {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("user {} already exists", username),
+        ));
+    }
+
+    // Register a new user
+    let mut user = UserRecord::new(hash_password(password)?, role);
+    if let Some(secret) = totp_secret {
+        totp::decode_secret(secret)?;
+        user.totp_secret = Some(secret.to_owned());
+    }
+    store.users.insert(username.to_string(), user);
+    save_store(&mut store)
+}
+
+/// Returns the role of `username` if the user exists and the password matches.
+///
+/// Failed attempts are counted for the user. Every attempt is counted before the password is
+/// checked, and while the user is backing off or locked out it is rejected without checking the
+/// password. The password of an unknown user is checked against a dummy hash, so that the time a
+/// login takes does not tell whether the user exists. Nodes do not see where a request comes
+/// from, so they cannot throttle by client, and `client_id` is chosen by the client itself. The
+/// gateways throttle the addresses of their clients instead.
+pub fn authenticate_user(username: &str, password: &str, lockout: &LockoutConfig) -> io::Result<Option<Role>> {
+    // The store is not locked while bcrypt runs
+    let user = reserve_attempt(username, lockout)?;
+    let password_hash = user.as_ref().map_or_else(dummy_hash, |user| user.password_hash.as_str());
+    let matches = verify(password, password_hash).unwrap_or(false);
+
+    match user {
+        Some(user) if matches => {
+            release_attempt(username)?;
+            Ok(Some(user.role))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the bcrypt hash of the password of `username`, if the user exists
+pub fn password_hash(username: &str) -> io::Result<Option<String>> {
+    let _guard = USER_LOCK.lock().unwrap();
+    Ok(load_store()?.users.remove(username).map(|user| user.password_hash))
+}
+
+/// Checks the password of a retried request against `password_hash`, the hash the password of
+/// `username` had when the first run was received, and returns whether it matches.
+///
+/// The attempt is throttled and counted as for [`authenticate_user`]. The one-time code is not
+/// checked again, since the first run consumed it.
+pub fn verify_retry(username: &str, password: &str, password_hash: &str, lockout: &LockoutConfig) -> io::Result<bool> {
+    reserve_attempt(username, lockout)?;
+    if !verify(password, password_hash).unwrap_or(false) {
+        return Ok(false);
+    }
+    release_attempt(username)?;
+    Ok(true)
+}
+
+/// Verifies the one-time `code` of a user who enrolled a TOTP second factor. Users without a
+/// second factor pass without a code.
+///
+/// Wrong or replayed codes count as failed logins of the user.
+pub fn verify_second_factor(username: &str, code: Option<&str>, lockout: &LockoutConfig) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+    let now = unix_time();
+
+    let user = store.users.get_mut(username).ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, format!("user {} does not exist", username))
+    })?;
+    let secret = match &user.totp_secret {
+        Some(secret) => totp::decode_secret(secret)?,
+        None => return Ok(()),
+    };
+
+    let step = code.and_then(|code| totp::verify(&secret, code, now));
+    match step {
+        Some(step) if step > user.totp_last_step => {
+            user.totp_last_step = step;
+            save_store(&mut store)
+        }
+        _ => {
+            user.throttle.record_failure(now, lockout);
+            save_store(&mut store)?;
+            Err(login_refused(
+                FailureKind::Unauthenticated,
+                format!("invalid one-time code for user {}", username),
+            ))
+        }
+    }
+}
+
+/// Clears the failed login attempts and any lockout of a user
+pub fn unlock(username: &str) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    let user = store.users.get_mut(username).ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, format!("user {} does not exist", username))
+    })?;
+    user.throttle = Throttle::default();
+    save_store(&mut store)
+}
+
+/// Replaces the password of an existing user. The caller must have authenticated the user first.
+pub fn change_password(username: &str, new_password: &str) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    let user = store.users.get_mut(username).ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, format!("user {} does not exist", username))
+    })?;
+    user.password_hash = hash_password(new_password)?;
+    save_store(&mut store)
+}
+
+/// Changes the role of an existing user
+pub fn set_role(username: &str, role: Role) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    if role != Role::Admin {
+        check_admin_remains(&store, username)?;
+    }
+    let user = store.users.get_mut(username).ok_or_else(|| {
+        io::Error::new(ErrorKind::NotFound, format!("user {} does not exist", username))
+    })?;
+    user.role = role;
+    save_store(&mut store)
+}
+
+/// Removes a user from the store
+pub fn delete_user(username: &str) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut store = load_store()?;
+
+    check_admin_remains(&store, username)?;
+    if store.users.remove(username).is_none() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("user {} does not exist", username),
+        ));
+    }
+    save_store(&mut store)
+}
+
+/// Fails if `username` is the only administrator, who must not be removed or demoted since nobody
+/// could manage users afterwards
+fn check_admin_remains(store: &UserStore, username: &str) -> io::Result<()> {
+    let is_admin = store.users.get(username).map_or(false, |user| user.role == Role::Admin);
+    let admins = store.users.values().filter(|user| user.role == Role::Admin).count();
+    if is_admin && admins == 1 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("user {} is the last admin and must stay one", username),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if `username` is the only administrator
+pub fn ensure_admin_remains(username: &str) -> io::Result<()> {
+    let _guard = USER_LOCK.lock().unwrap();
+    check_admin_remains(&load_store()?, username)
+}
+
+/// Returns whether `username` is present in the store
+pub fn user_exists(username: &str) -> io::Result<bool> {
+    let _guard = USER_LOCK.lock().unwrap();
+    Ok(load_store()?.users.contains_key(username))
+}
+
+/// Returns the names and roles of all registered users sorted by name
+pub fn list_users() -> io::Result<Vec<(String, Role)>> {
+    let _guard = USER_LOCK.lock().unwrap();
+    let mut users: Vec<(String, Role)> = load_store()?
+        .users
+        .into_iter()
+        .map(|(username, user)| (username, user.role))
+        .collect();
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttled_kind(result: io::Result<()>) -> Option<FailureKind> {
+        let error = result.err()?;
+        error.get_ref().and_then(|e| e.downcast_ref::<LoginRefused>()).map(|refused| refused.kind)
+    }
+
+    #[test]
+    fn delay_doubles_after_every_failure() {
+        let lockout = LockoutConfig::default();
+        let mut throttle = Throttle::default();
+        assert_eq!(throttle.retry_at(&lockout), 0);
+        assert!(throttle.check("user alice", 1000, &lockout).is_ok());
+
+        for (failures, delay) in [(1, 1), (2, 2), (3, 4), (4, 8)] {
+            throttle.record_failure(1000, &lockout);
+            assert_eq!(throttle.failed_attempts, failures);
+            assert_eq!(throttle.retry_at(&lockout), 1000 + delay);
+        }
+        assert_eq!(throttled_kind(throttle.check("user alice", 1007, &lockout)), Some(FailureKind::Throttled));
+        assert!(throttle.check("user alice", 1008, &lockout).is_ok());
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let lockout = LockoutConfig { max_attempts: 100, ..Default::default() };
+        let mut throttle = Throttle::default();
+        for _ in 0..7 {
+            throttle.record_failure(1000, &lockout);
+        }
+        assert_eq!(throttle.retry_at(&lockout), 1000 + lockout.max_delay_secs);
+
+        // Shifting by the number of failures must not overflow
+        for _ in 0..90 {
+            throttle.record_failure(1000, &lockout);
+        }
+        assert_eq!(throttle.retry_at(&lockout), 1000 + lockout.max_delay_secs);
+    }
+
+    #[test]
+    fn too_many_failures_lock_the_user_out() {
+        let lockout = LockoutConfig::default();
+        let mut throttle = Throttle::default();
+        for _ in 0..lockout.max_attempts {
+            throttle.record_failure(1000, &lockout);
+        }
+        assert_eq!(throttle.failed_attempts, 0);
+        assert_eq!(throttle.locked_until, 1000 + lockout.lockout_secs);
+        assert_eq!(throttle.retry_at(&lockout), 1000 + lockout.lockout_secs);
+
+        let error = throttle.check("user alice", 1899, &lockout).unwrap_err();
+        assert_eq!(error.to_string(), "too many failed logins for user alice, retry in 1 seconds");
+        assert!(throttle.check("user alice", 1900, &lockout).is_ok());
+    }
+}
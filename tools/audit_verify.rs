@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::process;
+
+#[allow(dead_code)]
+#[path = "../server/audit.rs"]
+mod audit;
+
+use audit::Record;
+
+/// Fields of a record that every node must agree on for the same request
+#[derive(PartialEq)]
+struct RequestSummary<'a> {
+    username: &'a str,
+    function: &'a str,
+    key_id: Option<&'a str>,
+    derivation_path: Option<&'a str>,
+    message_digest: Option<&'a str>,
+    preimage: Option<&'a serde_json::Value>,
+    active_parties: Option<&'a [u16]>,
+}
+
+impl<'a> From<&'a Record> for RequestSummary<'a> {
+    fn from(record: &'a Record) -> Self {
+        RequestSummary {
+            username: &record.username,
+            function: &record.function,
+            key_id: record.key_id.as_deref(),
+            derivation_path: record.derivation_path.as_deref(),
+            message_digest: record.message_digest.as_deref(),
+            preimage: record.preimage.as_ref(),
+            active_parties: record.active_parties.as_deref(),
+        }
+    }
+}
+
+/// Verifies the audit logs of one or more nodes.
+///
+/// Every log is checked for edits, reordering and truncation against its head file. With
+/// `--key <file>`, heads must also carry a MAC under that audit key, so that a log rewritten
+/// together with its head is detected. When several logs are given, requests that are missing on
+/// some nodes or recorded differently are reported.
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut log_paths: Vec<String> = env::args().skip(1).collect();
+    let mut key = None;
+    if log_paths.len() > 2 && log_paths[0] == "--key" {
+        key = Some(fs::read(&log_paths[1])?);
+        log_paths.drain(..2);
+    }
+    if log_paths.is_empty() || log_paths[0] == "--key" {
+        eprintln!("usage: audit_verify [--key <audit.key>] <audit.log> [<audit.log> ...]");
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    let mut logs = vec![];
+    for path in &log_paths {
+        let records = audit::read_log(path)?;
+        let head = audit::read_head(path)?;
+
+        let problems = audit::verify_chain(&records, head.as_ref(), key.as_deref());
+        if problems.is_empty() {
+            println!("{}: {} records, chain intact", path, records.len());
+        } else {
+            failed = true;
+            for problem in problems {
+                println!("{}: {}", path, problem);
+            }
+        }
+        logs.push(records);
+    }
+
+    if logs.len() > 1 {
+        // Collect the records of every request by node
+        let mut requests: BTreeMap<String, Vec<Option<&Record>>> = BTreeMap::new();
+        for (node, records) in logs.iter().enumerate() {
+            for record in records.iter().filter(|record| !record.request_id.is_empty()) {
+                requests
+                    .entry(record.request_id.clone())
+                    .or_insert_with(|| vec![None; logs.len()])[node] = Some(record);
+            }
+        }
+
+        for (request_id, records) in &requests {
+            let missing: Vec<&String> = log_paths
+                .iter()
+                .zip(records)
+                .filter(|(_, record)| record.is_none())
+                .map(|(path, _)| path)
+                .collect();
+            if !missing.is_empty() {
+                failed = true;
+                println!("request {}: missing from {:?}", request_id, missing);
+            }
+
+            let mut summaries = records.iter().flatten().map(|record| RequestSummary::from(*record));
+            if let Some(first) = summaries.next() {
+                if summaries.any(|summary| summary != first) {
+                    failed = true;
+                    println!("request {}: nodes recorded different requests", request_id);
+                }
+            }
+
+            let outcomes: Vec<&str> = records.iter().flatten().map(|record| record.outcome.as_str()).collect();
+            if outcomes.iter().any(|outcome| *outcome != outcomes[0]) {
+                println!("request {}: outcomes differ between nodes: {:?}", request_id, outcomes);
+            }
+        }
+        println!("{} requests compared across {} nodes", requests.len(), logs.len());
+    }
+
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}